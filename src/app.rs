@@ -0,0 +1,324 @@
+use crate::builder::{sample_name, Plotter};
+use crate::cli::{deduplicate, Alignoth, DataFormat, NamedRegion};
+use anyhow::Result;
+use csv::WriterBuilder;
+use serde::Serialize;
+use serde_json::json;
+use std::fs::File;
+use std::io::{stdout, Write};
+use std::path::{Path, PathBuf};
+use tera::{Context, Tera};
+
+fn joined_sample_names(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|p| sample_name(p))
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+fn encode<T: Serialize>(value: &T, format: &DataFormat) -> Result<Vec<u8>> {
+    Ok(match format {
+        DataFormat::Json => json!(value).to_string().into_bytes(),
+        DataFormat::Tsv => {
+            let mut writer = WriterBuilder::new().delimiter(b'\t').from_writer(vec![]);
+            writer.serialize(value)?;
+            writer.into_inner()?
+        }
+    })
+}
+
+fn encode_each<T: Serialize>(values: &[T], format: &DataFormat) -> Result<Vec<u8>> {
+    Ok(match format {
+        DataFormat::Json => json!(values).to_string().into_bytes(),
+        DataFormat::Tsv => {
+            let mut writer = WriterBuilder::new().delimiter(b'\t').from_writer(vec![]);
+            for value in values {
+                writer.serialize(value)?;
+            }
+            writer.into_inner()?
+        }
+    })
+}
+
+/// Plots every region given via --regions-file or --each-vcf-record into its own file within the output directory
+/// and writes an index.html linking all of them. Regions whose names map to the same file name get a numbered suffix.
+fn write_batch(opt: &Alignoth, plotter: &mut Plotter, out_path: &Path) -> Result<()> {
+    if !out_path.exists() {
+        std::fs::create_dir_all(out_path)?;
+    }
+    let extension = match (opt.html, opt.svg, opt.png) {
+        (true, _, _) => "html",
+        (_, true, _) => "svg",
+        (_, _, true) => "png",
+        _ => "vl.json",
+    };
+    let regions = opt.batch_regions()?;
+    let stems = deduplicate(regions.iter().map(NamedRegion::file_stem));
+    let mut entries = Vec::new();
+    for (named, stem) in regions.into_iter().zip(stems) {
+        let plot = plotter.plot(&named.region)?;
+        let content = if opt.png {
+            plot.to_png()?
+        } else if opt.svg {
+            plot.to_svg()?.into_bytes()
+        } else if opt.html {
+            plot.to_html(opt.no_embed_js)?.into_bytes()
+        } else {
+            plot.inline_spec().to_string().into_bytes()
+        };
+        let file_name = format!("{stem}.{extension}");
+        std::fs::write(Path::join(out_path, &file_name), content)?;
+        entries.push(json!({
+            "name": named.name,
+            "region": named.region.to_string(),
+            "file": file_name,
+        }));
+    }
+    let mut templates = Tera::default();
+    // The .html suffix enables autoescaping of the region names, which are taken from user input.
+    templates.add_raw_template("index.html", include_str!("../resources/index.html.tera"))?;
+    let mut context = Context::new();
+    context.insert("samples", &joined_sample_names(&opt.bam_path));
+    context.insert("entries", &entries);
+    std::fs::write(
+        Path::join(out_path, "index.html"),
+        templates.render("index.html", &context)?.as_bytes(),
+    )?;
+    Ok(())
+}
+
+/// Runs the command line interface with the given, already preprocessed options. If `wizard` is set,
+/// the plot is written to a file in the current directory instead of stdout.
+pub fn run(opt: &Alignoth, wizard: bool) -> Result<()> {
+    let reproduce_command = wizard.then(|| opt.to_command());
+    let mut plotter = opt.plot_builder().build()?;
+
+    if opt.is_batch() {
+        return write_batch(opt, &mut plotter, opt.output.as_ref().unwrap());
+    }
+
+    let region = opt.region.as_ref().unwrap();
+    let plot = plotter.plot(region)?;
+    let reference = encode(plot.reference(), &opt.data_format)?;
+    let coverage = encode_each(plot.coverage(), &opt.data_format)?;
+    let reads = encode_each(plot.reads(), &opt.data_format)?;
+    let highlights = encode(&plot.highlight(), &opt.data_format)?;
+
+    if let Some(out_path) = &opt.output {
+        if !out_path.exists() {
+            std::fs::create_dir_all(out_path)?;
+        }
+        let bam_file_name = joined_sample_names(&opt.bam_path);
+        let highlight_path = if opt.highlight.is_some() || opt.vcf.is_some() || opt.bed.is_some() {
+            Some(Path::join(
+                out_path,
+                format!("{}.highlight.{}", bam_file_name, opt.data_format),
+            ))
+        } else {
+            None
+        };
+        write_files(
+            plot.spec().to_string().as_bytes(),
+            &reference,
+            &reads,
+            &highlights,
+            &coverage,
+            &Path::join(out_path, format!("{bam_file_name}.vl.json")),
+            &Path::join(
+                out_path,
+                format!("{}.reference.{}", bam_file_name, opt.data_format),
+            ),
+            &Path::join(
+                out_path,
+                format!("{}.reads.{}", bam_file_name, opt.data_format),
+            ),
+            highlight_path,
+            &Path::join(
+                out_path,
+                format!("{}.coverage.{}", bam_file_name, opt.data_format),
+            ),
+        )?;
+    } else if let (
+        Some(spec_output),
+        Some(ref_data_output),
+        Some(read_data_output),
+        Some(coverage_output),
+    ) = (
+        &opt.spec_output,
+        &opt.ref_data_output,
+        &opt.read_data_output,
+        &opt.coverage_output,
+    ) {
+        write_files(
+            plot.spec().to_string().as_bytes(),
+            &reference,
+            &reads,
+            &highlights,
+            &coverage,
+            spec_output,
+            ref_data_output,
+            read_data_output,
+            opt.highlight_data_output.clone(),
+            coverage_output,
+        )?;
+    } else if opt.svg || opt.png {
+        let image = if opt.png {
+            plot.to_png()?
+        } else {
+            plot.to_svg()?.into_bytes()
+        };
+        if wizard {
+            let file_name = format!(
+                "{}.{}",
+                joined_sample_names(&opt.bam_path),
+                if opt.png { "png" } else { "svg" }
+            );
+            std::fs::write(&file_name, image)?;
+            println!("Plot saved to {file_name} 🪄");
+        } else {
+            stdout().write_all(&image)?;
+        }
+    } else {
+        let plot_specs = plot.inline_spec();
+        let bam_name = joined_sample_names(&opt.bam_path);
+        if opt.html {
+            let html = plot.to_html(opt.no_embed_js)?;
+            if wizard {
+                std::fs::write(format!("{bam_name}.html"), html.as_bytes())?;
+                println!("Plot saved to {bam_name}.html 🪄");
+            } else {
+                stdout().write_all(html.as_bytes())?;
+            }
+        } else if wizard {
+            std::fs::write(
+                format!("{bam_name}.vl.json"),
+                plot_specs.to_string().as_bytes(),
+            )?;
+            println!("Plot saved to {bam_name}.vl.json 🪄");
+        } else {
+            stdout().write_all(plot_specs.to_string().as_bytes())?;
+        }
+    }
+    if let Some(command) = reproduce_command {
+        println!("\nReproduce this plot with:\n  {command}");
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_files(
+    spec_data: &[u8],
+    ref_data: &[u8],
+    read_data: &[u8],
+    highlight_data: &[u8],
+    coverage_data: &[u8],
+    spec_path: &Path,
+    ref_path: &Path,
+    read_path: &Path,
+    highlight_path: Option<PathBuf>,
+    coverage_path: &Path,
+) -> Result<()> {
+    let mut specs = File::create(spec_path).unwrap();
+    specs.write_all(spec_data)?;
+    let mut read_file = File::create(read_path).unwrap();
+    read_file.write_all(read_data)?;
+    let mut reference_file = File::create(ref_path).unwrap();
+    reference_file.write_all(ref_data)?;
+    let mut coverage_file = File::create(coverage_path).unwrap();
+    coverage_file.write_all(coverage_data)?;
+    if let Some(path) = highlight_path {
+        let mut highlight_file = File::create(path).unwrap();
+        highlight_file.write_all(highlight_data)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_batch, write_files};
+    use crate::cli::Alignoth;
+    use std::fs;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_write_batch() {
+        let mut regions = tempfile::NamedTempFile::new().unwrap();
+        writeln!(regions, "chr1\t0\t20\tstart").unwrap();
+        writeln!(regions, "chr1:50-100").unwrap();
+        writeln!(regions, "chr1\t0\t20\t<b>start</b>").unwrap();
+        writeln!(regions, "chr1:50-100").unwrap();
+        let opt = Alignoth {
+            bam_path: vec![PathBuf::from("tests/sample_1/reads.bam")],
+            reference: Some(PathBuf::from("tests/sample_1/reference.fa")),
+            region: None,
+            regions_file: Some(regions.path().to_path_buf()),
+            ..crate::cli::tests::base_alignoth()
+        };
+        let out = tempfile::tempdir().unwrap();
+        let mut plotter = opt.plot_builder().build().unwrap();
+        write_batch(&opt, &mut plotter, out.path()).unwrap();
+
+        for file in [
+            "start.vl.json",
+            "chr1_50-100.vl.json",
+            "_b_start__b_.vl.json",
+            "chr1_50-100_2.vl.json",
+        ] {
+            let specs: serde_json::Value =
+                serde_json::from_str(&fs::read_to_string(out.path().join(file)).unwrap()).unwrap();
+            assert_eq!(specs["datasets"]["reads"][0]["sample"], "reads");
+        }
+        let index = fs::read_to_string(out.path().join("index.html")).unwrap();
+        assert!(index.contains(r#"<a href="start.vl.json">start</a>"#));
+        assert!(index.contains(r#"<a href="chr1_50-100.vl.json">chr1:50-100</a>"#));
+        // Repeated regions are written to separate files.
+        assert!(index.contains(r#"<a href="chr1_50-100_2.vl.json">chr1:50-100</a>"#));
+        // Names are escaped rather than interpreted as markup.
+        assert!(index.contains("&lt;b&gt;start&lt;/b&gt;"));
+        assert!(!index.contains("<b>"));
+    }
+
+    #[test]
+    fn test_write_files() {
+        write_files(
+            "test spec".as_bytes(),
+            "test ref".as_bytes(),
+            "test read".as_bytes(),
+            "test highlight".as_bytes(),
+            "test coverage".as_bytes(),
+            Path::new("/tmp/test_spec.json"),
+            Path::new("/tmp/test_ref.json"),
+            Path::new("/tmp/test_read.json"),
+            Some(PathBuf::from("/tmp/test_highlight.json")),
+            Path::new("/tmp/test_coverage.json"),
+        )
+        .unwrap();
+        assert!(Path::new("/tmp/test_spec.json").exists());
+        assert!(Path::new("/tmp/test_ref.json").exists());
+        assert!(Path::new("/tmp/test_read.json").exists());
+        assert!(Path::new("/tmp/test_highlight.json").exists());
+        assert!(Path::new("/tmp/test_coverage.json").exists());
+        assert_eq!(
+            fs::read_to_string("/tmp/test_spec.json").unwrap(),
+            "test spec"
+        );
+        assert_eq!(
+            fs::read_to_string("/tmp/test_ref.json").unwrap(),
+            "test ref"
+        );
+        assert_eq!(
+            fs::read_to_string("/tmp/test_read.json").unwrap(),
+            "test read"
+        );
+        assert_eq!(
+            fs::read_to_string("/tmp/test_highlight.json").unwrap(),
+            "test highlight"
+        );
+        fs::remove_file("/tmp/test_spec.json").unwrap();
+        fs::remove_file("/tmp/test_ref.json").unwrap();
+        fs::remove_file("/tmp/test_read.json").unwrap();
+        fs::remove_file("/tmp/test_highlight.json").unwrap();
+    }
+}
@@ -0,0 +1,1330 @@
+use crate::cli::{Interval, Region};
+use crate::expression::Expression;
+use crate::filter::ReadFilter;
+use crate::highlight::{BedHighlight, Highlight, VcfHighlight};
+use crate::plot::{
+    create_plot_data, partner_region, Coverage, DisplayMode, DownsampleStrategy, Downsampling,
+    EncodedRead, Junction, ModificationFrequency, PlotData, PlotDataOptions, ReadAttribute,
+    Reference, SortBy, StrandBias, PAIR_COLORS,
+};
+use crate::render::{render_png, render_svg};
+use crate::utils::{ensure_vcf_index, open_alignment, open_fasta};
+use anyhow::{Context as _, Result};
+use itertools::Itertools;
+use lz_str::compress_to_utf16;
+use rust_htslib::bam::Read as _;
+use rust_htslib::{bam, faidx};
+use serde_json::{json, Value};
+use std::cmp::min;
+use std::path::{Path, PathBuf};
+use std::{iter, panic, thread};
+use tera::{Context, Tera};
+
+const CDN_VEGA: &str = r#"<script src="https://cdn.jsdelivr.net/npm/vega@5"></script>"#;
+const CDN_VEGA_LITE: &str = r#"<script src="https://cdn.jsdelivr.net/npm/vega-lite@5"></script>"#;
+const CDN_VEGA_EMBED: &str = r#"<script src="https://cdn.jsdelivr.net/npm/vega-embed@6"></script>"#;
+const CDN_LZ_STRING: &str = r#"<script src="https://cdn.jsdelivr.net/npm/lz-string@1"></script>"#;
+
+/// Inlines a javascript resource into a `<script>` tag at compile time.
+macro_rules! embedded {
+    ($path:literal) => {
+        concat!("<script>", include_str!($path), "</script>")
+    };
+}
+
+/// Scales the row height and the stroke widths of the marks of the given reads view.
+fn scale_read_marks(reads: &mut Value, factor: f64) {
+    let scale = |value: &mut Value| {
+        if let Some(number) = value.as_f64() {
+            *value = json!(number * factor);
+        }
+    };
+    scale(&mut reads["height"]["step"]);
+    for layer in reads["layer"].as_array_mut().into_iter().flatten() {
+        if let Some(stroke_width) = layer.pointer_mut("/encoding/strokeWidth") {
+            if let Some(value) = stroke_width.get_mut("value") {
+                scale(value);
+            }
+            if let Some(range) = stroke_width
+                .pointer_mut("/scale/range")
+                .and_then(Value::as_array_mut)
+            {
+                range.iter_mut().for_each(scale);
+            }
+        }
+    }
+}
+
+/// Creates the plot data of the given region for each alignment file, named by the given names.
+/// With more than one thread, the files are processed concurrently. As readers of the reference
+/// cannot be shared between threads, each thread then uses its own one of the given readers.
+fn sample_plot_data(
+    options: &PlotBuilder,
+    bams: &mut [bam::IndexedReader],
+    fastas: &mut [faidx::Reader],
+    region: &Region,
+    downsampling: &Downsampling,
+    names: &[String],
+) -> Result<Vec<PlotData>> {
+    let (workers, threads_per_worker) = options.split_threads(bams.len());
+    let plot_options = PlotDataOptions {
+        max_read_depth: options.max_read_depth,
+        downsampling: downsampling.clone(),
+        display_mode: options.display_mode,
+        aux_tags: options.aux_tags.clone(),
+        mismatch_display_min_percent: options.mismatch_display_min_percent,
+        clamp_reads: options.clamp_reads,
+        show_soft_clips: options.show_soft_clips,
+        group_by: options.group_by.clone(),
+        color_by: options.color_by.clone(),
+        sort_by: options.sort_by.clone(),
+        min_base_quality: options.min_base_quality,
+        filter: options.filter.clone(),
+        view_as_pairs: options.view_as_pairs,
+        dedup_mate_overlap: options.dedup_mate_overlap,
+        threads: threads_per_worker,
+    };
+    let create = |bam: &mut bam::IndexedReader, fasta: Option<&faidx::Reader>, name: &String| {
+        create_plot_data(bam, fasta, region, &plot_options, name.clone())
+    };
+    if workers == 1 {
+        return bams
+            .iter_mut()
+            .zip(names)
+            .map(|(bam, name)| create(bam, fastas.first(), name))
+            .collect();
+    }
+    // Files are distributed round-robin over the threads and put back into their order afterwards,
+    // so that the output does not depend on which thread finishes first.
+    let mut jobs = (0..workers).map(|_| Vec::new()).collect_vec();
+    for (i, (bam, name)) in bams.iter_mut().zip(names).enumerate() {
+        jobs[i % workers].push((i, bam, name));
+    }
+    let fastas = fastas
+        .iter_mut()
+        .map(Some)
+        .chain(iter::repeat_with(|| None));
+    let results = thread::scope(|scope| {
+        let handles = jobs
+            .into_iter()
+            .zip(fastas)
+            .map(|(jobs, fasta)| {
+                scope.spawn(move || -> Result<Vec<(usize, PlotData)>> {
+                    jobs.into_iter()
+                        .map(|(i, bam, name)| Ok((i, create(bam, fasta.as_deref(), name)?)))
+                        .collect()
+                })
+            })
+            .collect_vec();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|panic| panic::resume_unwind(panic))
+            })
+            .collect::<Result<Vec<_>>>()
+    })?;
+    Ok(results
+        .into_iter()
+        .flatten()
+        .sorted_by_key(|(i, _)| *i)
+        .map(|(_, plot_data)| plot_data)
+        .collect())
+}
+
+/// Strips the alignment file extension to get the sample name, e.g. `reads.bam` -> `reads`.
+pub(crate) fn sample_name(path: &Path) -> &str {
+    path.file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .trim_end_matches(".bam")
+        .trim_end_matches(".sam")
+        .trim_end_matches(".cram")
+}
+
+/// Configures how alignment plots are created.
+///
+/// # Example
+///
+/// ```no_run
+/// use alignoth::{PlotBuilder, Region};
+/// use std::str::FromStr;
+///
+/// let plot = PlotBuilder::new(vec!["sample.bam".into()], "reference.fa")
+///     .region(Region::from_str("chr1:1000-2000")?)
+///     .max_read_depth(100)
+///     .plot()?;
+/// std::fs::write("plot.vl.json", plot.inline_spec().to_string())?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct PlotBuilder {
+    bam_paths: Vec<PathBuf>,
+    reference: Option<PathBuf>,
+    region: Option<Region>,
+    max_read_depth: usize,
+    max_width: Option<i64>,
+    autofit: bool,
+    aux_tags: Option<Vec<String>>,
+    mismatch_display_min_percent: f64,
+    min_base_quality: u8,
+    clamp_reads: bool,
+    show_soft_clips: bool,
+    sashimi: bool,
+    split_partners: bool,
+    view_as_pairs: bool,
+    coverage_by_strand: bool,
+    dedup_mate_overlap: bool,
+    downsample_strategy: DownsampleStrategy,
+    seed: u64,
+    display_mode: DisplayMode,
+    threads: usize,
+    group_by: Option<ReadAttribute>,
+    color_by: Option<ReadAttribute>,
+    sort_by: Option<SortBy>,
+    filter: ReadFilter,
+    highlights: Vec<Interval>,
+    vcf: Option<PathBuf>,
+    bed: Option<PathBuf>,
+}
+
+impl PlotBuilder {
+    /// Creates a builder plotting the given indexed BAM files against the given indexed reference.
+    pub fn new(bam_paths: Vec<PathBuf>, reference: impl Into<PathBuf>) -> Self {
+        PlotBuilder {
+            reference: Some(reference.into()),
+            ..PlotBuilder::without_reference(bam_paths)
+        }
+    }
+
+    /// Creates a builder plotting the given indexed BAM files without a reference. Mismatches and
+    /// deletions are reconstructed from the MD tags of the reads, which hence need to be present,
+    /// and the reference track is inferred from them.
+    pub fn without_reference(bam_paths: Vec<PathBuf>) -> Self {
+        PlotBuilder {
+            bam_paths,
+            reference: None,
+            region: None,
+            max_read_depth: 500,
+            max_width: None,
+            autofit: false,
+            aux_tags: None,
+            mismatch_display_min_percent: 1.0,
+            min_base_quality: 0,
+            clamp_reads: false,
+            show_soft_clips: false,
+            sashimi: false,
+            split_partners: false,
+            view_as_pairs: false,
+            coverage_by_strand: false,
+            dedup_mate_overlap: false,
+            downsample_strategy: DownsampleStrategy::default(),
+            seed: 42,
+            display_mode: DisplayMode::default(),
+            threads: 1,
+            group_by: None,
+            color_by: None,
+            sort_by: None,
+            filter: ReadFilter::default(),
+            highlights: Vec::new(),
+            vcf: None,
+            bed: None,
+        }
+    }
+
+    /// Sets the region plotted by [`PlotBuilder::plot`].
+    pub fn region(mut self, region: Region) -> Self {
+        self.region = Some(region);
+        self
+    }
+
+    /// Sets the maximum rows of reads shown per sample. Defaults to 500.
+    pub fn max_read_depth(mut self, max_read_depth: usize) -> Self {
+        self.max_read_depth = max_read_depth;
+        self
+    }
+
+    /// Sets how reads are subsampled if they exceed the maximum read depth. Defaults to
+    /// [`DownsampleStrategy::Rows`].
+    pub fn downsample_strategy(mut self, downsample_strategy: DownsampleStrategy) -> Self {
+        self.downsample_strategy = downsample_strategy;
+        self
+    }
+
+    /// Sets the seed of the random subsampling of reads. Defaults to 42.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Sets how densely reads are packed into rows and drawn. Defaults to
+    /// [`DisplayMode::Expanded`].
+    pub fn display_mode(mut self, display_mode: DisplayMode) -> Self {
+        self.display_mode = display_mode;
+        self
+    }
+
+    /// Sets the number of threads used to decompress and process the alignment files. Multiple
+    /// files are then processed concurrently, with the threads split evenly between the files
+    /// processed at once, and the records of long regions are converted into reads concurrently.
+    /// Defaults to 1.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Sets the maximum width of the plot. Defaults to 1024.
+    pub fn max_width(mut self, max_width: Option<i64>) -> Self {
+        self.max_width = max_width;
+        self
+    }
+
+    /// If set and no maximum width is given, the plot fills the available width when embedded
+    /// into HTML.
+    pub fn autofit(mut self, autofit: bool) -> Self {
+        self.autofit = autofit;
+        self
+    }
+
+    /// Sets the aux tags whose content is shown in the tooltip of each read.
+    pub fn aux_tags(mut self, aux_tags: Option<Vec<String>>) -> Self {
+        self.aux_tags = aux_tags;
+        self
+    }
+
+    /// Sets the minimum percentage of mismatches and indels at a position to be shown in the
+    /// coverage track.
+    /// Defaults to 1.0.
+    pub fn mismatch_display_min_percent(mut self, percent: f64) -> Self {
+        self.mismatch_display_min_percent = percent;
+        self
+    }
+
+    /// Sets the minimum base quality of mismatches to be counted in the coverage track. Defaults
+    /// to 0.
+    pub fn min_base_quality(mut self, min_base_quality: u8) -> Self {
+        self.min_base_quality = min_base_quality;
+        self
+    }
+
+    /// If set, reads are clamped to the boundaries of the plotted region.
+    pub fn clamp_reads(mut self, clamp_reads: bool) -> Self {
+        self.clamp_reads = clamp_reads;
+        self
+    }
+
+    /// If set, soft clipped bases are shown at the read ends instead of being hidden.
+    pub fn show_soft_clips(mut self, show_soft_clips: bool) -> Self {
+        self.show_soft_clips = show_soft_clips;
+        self
+    }
+
+    /// If set, a sashimi track with the splice junctions of the reads is shown above the reads.
+    pub fn sashimi(mut self, sashimi: bool) -> Self {
+        self.sashimi = sashimi;
+        self
+    }
+
+    /// If set, the locus the supplementary alignments of split reads map to is shown in a second
+    /// panel below the plotted region. Clicking a read highlights its other segments there.
+    pub fn split_partners(mut self, split_partners: bool) -> Self {
+        self.split_partners = split_partners;
+        self
+    }
+
+    /// If set, mates are connected by a colored line and reads are colored by the orientation
+    /// and insert size of their pair instead of by [`PlotBuilder::color_by`].
+    pub fn view_as_pairs(mut self, view_as_pairs: bool) -> Self {
+        self.view_as_pairs = view_as_pairs;
+        self
+    }
+
+    /// If set, the coverage track shows the reads on the forward strand above and the ones on the
+    /// reverse strand below the axis.
+    pub fn coverage_by_strand(mut self, coverage_by_strand: bool) -> Self {
+        self.coverage_by_strand = coverage_by_strand;
+        self
+    }
+
+    /// If set, positions covered by both mates of a pair are counted once in the coverage track,
+    /// keeping the base with the higher quality if the mates disagree.
+    pub fn dedup_mate_overlap(mut self, dedup_mate_overlap: bool) -> Self {
+        self.dedup_mate_overlap = dedup_mate_overlap;
+        self
+    }
+
+    /// Stacks reads into separate, labelled blocks per value of the given attribute.
+    pub fn group_by(mut self, group_by: Option<ReadAttribute>) -> Self {
+        self.group_by = group_by;
+        self
+    }
+
+    /// Colors reads by the given attribute instead of their mapping quality.
+    pub fn color_by(mut self, color_by: Option<ReadAttribute>) -> Self {
+        self.color_by = color_by;
+        self
+    }
+
+    /// Sorts reads by the given key before they are packed into rows, e.g. to stack reads carrying
+    /// an alternative base at a position at the top.
+    pub fn sort_by(mut self, sort_by: Option<SortBy>) -> Self {
+        self.sort_by = sort_by;
+        self
+    }
+
+    /// Skips reads with a mapping quality below the given one.
+    pub fn min_mapq(mut self, min_mapq: u8) -> Self {
+        self.filter.min_mapq = min_mapq;
+        self
+    }
+
+    /// Only keeps reads that have all of the given SAM flags set.
+    pub fn include_flags(mut self, flags: u16) -> Self {
+        self.filter.include_flags = flags;
+        self
+    }
+
+    /// Skips reads that have any of the given SAM flags set.
+    pub fn exclude_flags(mut self, flags: u16) -> Self {
+        self.filter.exclude_flags = flags;
+        self
+    }
+
+    /// Only keeps reads whose aux tags match the given expression, e.g. `NM<5 && HP==1`.
+    pub fn aux_filter(mut self, aux_filter: Option<Expression>) -> Self {
+        self.filter.aux_filter = aux_filter;
+        self
+    }
+
+    /// Adds intervals (1-based, fully inclusive) to be highlighted.
+    pub fn highlights(mut self, highlights: impl IntoIterator<Item = Interval>) -> Self {
+        self.highlights.extend(highlights);
+        self
+    }
+
+    /// Highlights all variants of the given VCF file that are located within the plotted region.
+    pub fn vcf(mut self, vcf: Option<PathBuf>) -> Self {
+        self.vcf = vcf;
+        self
+    }
+
+    /// Highlights all records of the given BED file that overlap the plotted region.
+    pub fn bed(mut self, bed: Option<PathBuf>) -> Self {
+        self.bed = bed;
+        self
+    }
+
+    /// Splits the threads between the given number of alignment files, returning the number of
+    /// files processed concurrently and the threads left to each of them, which are used both to
+    /// decompress the file and to convert the records of long regions into reads.
+    fn split_threads(&self, files: usize) -> (usize, usize) {
+        let workers = min(self.threads, files).max(1);
+        (workers, (self.threads / workers).max(1))
+    }
+
+    /// Opens all input files once, returning a [`Plotter`] that can plot many regions.
+    pub fn build(self) -> Result<Plotter> {
+        let (workers, threads_per_worker) = self.split_threads(self.bam_paths.len());
+        let bams = self
+            .bam_paths
+            .iter()
+            .map(|path| {
+                let mut bam = open_alignment(path, self.reference.as_deref())?;
+                if threads_per_worker > 1 {
+                    bam.set_threads(threads_per_worker)?;
+                }
+                Ok(bam)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let fastas = self
+            .reference
+            .iter()
+            .flat_map(|path| (0..workers).map(move |_| open_fasta(path)))
+            .collect::<Result<Vec<_>>>()?;
+        let vcf = self.vcf.as_deref().map(ensure_vcf_index).transpose()?;
+        Ok(Plotter {
+            options: PlotBuilder { vcf, ..self },
+            bams,
+            fastas,
+        })
+    }
+
+    /// Plots the region given via [`PlotBuilder::region`].
+    pub fn plot(self) -> Result<Plot> {
+        let region = self
+            .region
+            .clone()
+            .context("No region to plot was given to the PlotBuilder")?;
+        self.build()?.plot(&region)
+    }
+}
+
+/// Creates plots of arbitrary regions from already opened input files.
+pub struct Plotter {
+    options: PlotBuilder,
+    bams: Vec<bam::IndexedReader>,
+    /// One reader of the reference for each thread processing alignment files concurrently.
+    fastas: Vec<faidx::Reader>,
+}
+
+impl Plotter {
+    /// Plots the given region.
+    pub fn plot(&mut self, region: &Region) -> Result<Plot> {
+        let options = &self.options;
+        let mut plot_specs: Value =
+            serde_json::from_str(include_str!("../resources/plot.vl.json"))?;
+        let width = match options.max_width {
+            Some(max_width) => Some(min(max_width, 5 * region.length())),
+            None if options.autofit => None,
+            None => Some(min(1024, 5 * region.length())),
+        };
+        let domain = json!(vec![region.start as f32 - 0.5, region.end as f32 - 0.5]);
+        let mut highlight = options.highlights.clone();
+        if let Some(vcf_path) = options.vcf.as_ref() {
+            highlight.extend(VcfHighlight::new(vcf_path.clone()).intervals(region)?);
+        }
+        if let Some(bed_path) = options.bed.as_ref() {
+            highlight.extend(BedHighlight::new(bed_path.clone()).intervals(region)?);
+        }
+        let downsampling = Downsampling {
+            strategy: options.downsample_strategy,
+            seed: options.seed,
+            sites: highlight.iter().map(Interval::positions).collect(),
+        };
+
+        let template_coverage = if options.coverage_by_strand {
+            plot_specs["vconcat"][4].clone()
+        } else {
+            plot_specs["vconcat"][0].clone()
+        };
+        let mut template_reads = plot_specs["vconcat"][1].clone();
+        scale_read_marks(&mut template_reads, options.display_mode.scale());
+        let template_junctions = plot_specs["vconcat"][2].clone();
+        let template_modifications = plot_specs["vconcat"][3].clone();
+        let mut new_vconcat = Vec::new();
+
+        let mut all_read_data = Vec::new();
+        let mut all_coverage_data = Vec::new();
+        let mut all_junction_data = Vec::new();
+        let mut all_modification_data = Vec::new();
+        let mut labels = Vec::new();
+        let mut reference_data = None;
+        let mut sample_panels = Vec::new();
+
+        let names = options
+            .bam_paths
+            .iter()
+            .map(|bam_path| sample_name(bam_path).to_string())
+            .collect_vec();
+        let plot_data = sample_plot_data(
+            options,
+            &mut self.bams,
+            &mut self.fastas,
+            region,
+            &downsampling,
+            &names,
+        )?;
+        for (i, (bam_name, plot_data)) in names.into_iter().zip(plot_data).enumerate() {
+            let PlotData {
+                reads: mut read_data,
+                reference: ref_data,
+                total_reads,
+                coverage: coverage_data,
+                junctions: mut junction_data,
+                retained_reads,
+                filtered_reads,
+                modifications: mut modification_data,
+            } = plot_data;
+
+            if reference_data.is_none() {
+                reference_data = Some(ref_data);
+            }
+            all_read_data.append(&mut read_data);
+            all_coverage_data.push(coverage_data);
+            if options.sashimi {
+                all_junction_data.append(&mut junction_data);
+            }
+
+            let filtered = if filtered_reads > 0 {
+                format!(", {filtered_reads} filtered")
+            } else {
+                String::new()
+            };
+            let subsampling_warning = if total_reads > retained_reads {
+                format!(
+                    "{} ({} of {} reads, downsampling: {}{})",
+                    bam_name, retained_reads, total_reads, options.downsample_strategy, filtered
+                )
+            } else {
+                format!("{} ({} reads{})", bam_name, total_reads, filtered)
+            };
+
+            let mut cov = template_coverage.clone();
+            if let Some(width) = width {
+                cov["width"] = json!(width);
+            }
+            if i == 0 {
+                cov["title"] = json!({
+                    "text": &region.target,
+                });
+            } else if let Some(obj) = cov.as_object_mut() {
+                obj.remove("title");
+            }
+            if let Some(arr) = cov["transform"].as_array_mut() {
+                arr.insert(
+                    0,
+                    json!({ "filter": format!("datum.sample == '{}'", bam_name) }),
+                );
+            }
+
+            let mut rds = template_reads.clone();
+            if let Some(width) = width {
+                rds["width"] = json!(width);
+            }
+            rds["encoding"]["x"]["scale"]["domain"] = domain.clone();
+            rds["encoding"]["y"]["axis"]["title"] = json!(subsampling_warning);
+            labels.push(subsampling_warning);
+            let read_color = if options.view_as_pairs {
+                let (classes, colors): (Vec<_>, Vec<_>) = PAIR_COLORS.into_iter().unzip();
+                Some(json!({
+                    "field": "color",
+                    "type": "nominal",
+                    "title": "pair",
+                    "scale": { "domain": classes, "range": colors },
+                }))
+            } else {
+                options
+                    .color_by
+                    .as_ref()
+                    .filter(|attribute| **attribute != ReadAttribute::Mapq)
+                    .map(|attribute| {
+                        json!({
+                            "field": "color",
+                            "type": "nominal",
+                            "title": attribute.to_string(),
+                            "scale": { "scheme": "tableau10" },
+                        })
+                    })
+            };
+            if read_color.is_some() {
+                rds["resolve"] = json!({ "scale": { "color": "independent" } });
+            }
+
+            if let Some(layers) = rds["layer"].as_array_mut() {
+                for layer in layers {
+                    if let Some(read_color) = &read_color {
+                        if layer["encoding"]["color"]["field"] == "mapq" {
+                            layer["encoding"]["color"] = read_color.clone();
+                        }
+                        if options.view_as_pairs && layer["name"] == "mate_link" {
+                            layer["encoding"]["color"] = read_color.clone();
+                            layer["encoding"]["strokeWidth"] = json!({ "value": 2 });
+                        }
+                    }
+
+                    if layer["data"]["name"] == "reads" {
+                        if let Some(arr) = layer["transform"].as_array_mut() {
+                            arr.insert(
+                                0,
+                                json!({ "filter": format!("datum.sample == '{}'", bam_name) }),
+                            );
+                        }
+                    }
+
+                    // The strand bias of the sample is looked up by sample and highlight name.
+                    if layer["data"]["name"] == "highlight" {
+                        if let Some(arr) = layer["transform"].as_array_mut() {
+                            arr.insert(
+                                0,
+                                json!({
+                                    "calculate": format!("'{}:' + datum.name", bam_name),
+                                    "as": "site",
+                                }),
+                            );
+                        }
+                    }
+
+                    if i > 0 && layer["data"]["name"] == "reference" {
+                        if let Some(obj) = layer.as_object_mut() {
+                            obj.remove("params");
+                        }
+                    }
+
+                    if let Some(params) = layer.get_mut("params").and_then(|p| p.as_array_mut()) {
+                        for param in params {
+                            if param["name"] == "rplc" {
+                                param["name"] = json!(format!("rplc_{}", i));
+                            }
+                        }
+                    }
+
+                    if let Some(encoding) = layer.get_mut("encoding") {
+                        if let Some(opacity) = encoding.get_mut("opacity") {
+                            if let Some(condition) = opacity.get_mut("condition") {
+                                if condition["param"] == "rplc" {
+                                    condition["param"] = json!(format!("rplc_{}", i));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            if options.split_partners {
+                sample_panels.push((cov.clone(), rds.clone()));
+            }
+            new_vconcat.push(cov);
+            if !modification_data.is_empty() {
+                let mut modifications = template_modifications.clone();
+                if let Some(width) = width {
+                    modifications["width"] = json!(width);
+                }
+                if let Some(arr) = modifications["transform"].as_array_mut() {
+                    arr.insert(
+                        0,
+                        json!({ "filter": format!("datum.sample == '{}'", bam_name) }),
+                    );
+                }
+                new_vconcat.push(modifications);
+                all_modification_data.append(&mut modification_data);
+            }
+            if options.sashimi {
+                let mut junctions = template_junctions.clone();
+                if let Some(width) = width {
+                    junctions["width"] = json!(width);
+                }
+                if let Some(arr) = junctions["transform"].as_array_mut() {
+                    arr.insert(
+                        0,
+                        json!({ "filter": format!("datum.sample == '{}'", bam_name) }),
+                    );
+                }
+                new_vconcat.push(junctions);
+            }
+            new_vconcat.push(rds);
+        }
+
+        let mut partner = None;
+        let mut partner_read_data = Vec::new();
+        let mut partner_coverage_data = Vec::new();
+        if options.split_partners {
+            let reads = all_read_data
+                .iter()
+                .map(EncodedRead::decode)
+                .collect::<Result<Vec<_>>>()?
+                .concat();
+            partner = partner_region(&reads, region);
+        }
+        if let Some(partner) = &partner {
+            let partner_domain = json!(vec![partner.start as f32 - 0.5, partner.end as f32 - 0.5]);
+            let mut partner_vconcat = Vec::new();
+            let names = options
+                .bam_paths
+                .iter()
+                .map(|bam_path| format!("{} ({partner})", sample_name(bam_path)))
+                .collect_vec();
+            let plot_data = sample_plot_data(
+                options,
+                &mut self.bams,
+                &mut self.fastas,
+                partner,
+                &downsampling,
+                &names,
+            )?;
+            for (i, ((name, mut plot_data), (mut cov, mut rds))) in names
+                .into_iter()
+                .zip(plot_data)
+                .zip(sample_panels)
+                .enumerate()
+            {
+                partner_read_data.append(&mut plot_data.reads);
+                partner_coverage_data.push(plot_data.coverage);
+
+                let sample_filter = json!({ "filter": format!("datum.sample == '{}'", name) });
+                cov["transform"][0] = sample_filter.clone();
+                cov["encoding"]["x"]["scale"]["domain"] = partner_domain.clone();
+                if i == 0 {
+                    cov["title"] =
+                        json!({ "text": format!("{partner} (supplementary alignments)") });
+                }
+
+                rds["encoding"]["x"]["scale"]["domain"] = partner_domain.clone();
+                rds["encoding"]["y"]["axis"]["title"] = json!(name);
+                // Reads are linked by name to the selection in the panel of the plotted region, as
+                // the segments of a split read are aligned at different positions.
+                let selection = format!("data('rplc_{i}_store')");
+                let linked = json!({
+                    "test": format!("!length({selection}) || indexof(pluck({selection}, 'values[0]'), datum.name) >= 0"),
+                    "value": 1,
+                });
+                if let Some(layers) = rds["layer"].as_array_mut() {
+                    layers.retain(|layer| layer["data"]["name"] == "reads");
+                    for layer in layers {
+                        layer["transform"][0] = sample_filter.clone();
+                        if let Some(obj) = layer.as_object_mut() {
+                            obj.remove("params");
+                        }
+                        if layer["encoding"]["opacity"]["condition"].is_object() {
+                            layer["encoding"]["opacity"]["condition"] = linked.clone();
+                        }
+                    }
+                }
+                partner_vconcat.push(cov);
+                partner_vconcat.push(rds);
+            }
+            // The partner locus needs its own x scale, so both parts are nested into separate
+            // views that each share their x scale.
+            let resolve = plot_specs["resolve"].clone();
+            new_vconcat = vec![
+                json!({ "vconcat": new_vconcat, "resolve": resolve }),
+                json!({ "vconcat": partner_vconcat, "resolve": resolve }),
+            ];
+            plot_specs["resolve"]["scale"]["x"] = json!("independent");
+        }
+        plot_specs["vconcat"] = json!(new_vconcat);
+        highlight.iter_mut().for_each(|h| h.preprocess());
+        let mut strand_bias = Vec::new();
+        for coverage in &all_coverage_data {
+            for interval in &highlight {
+                strand_bias.extend(StrandBias::from_coverage(coverage, interval));
+            }
+        }
+
+        Ok(Plot {
+            region: region.clone(),
+            spec: plot_specs,
+            reference: reference_data.context("No BAM file given to plot")?,
+            reads: all_read_data,
+            coverage: all_coverage_data,
+            junctions: all_junction_data,
+            modifications: all_modification_data,
+            partner,
+            partner_reads: partner_read_data,
+            partner_coverage: partner_coverage_data,
+            highlight,
+            strand_bias,
+            labels,
+            coverage_by_strand: options.coverage_by_strand,
+            width,
+        })
+    }
+}
+
+/// A plotted region: the vega-lite spec together with the datasets it refers to.
+#[derive(Debug)]
+pub struct Plot {
+    region: Region,
+    spec: Value,
+    reference: Reference,
+    reads: Vec<EncodedRead>,
+    coverage: Vec<Coverage>,
+    junctions: Vec<Junction>,
+    modifications: Vec<ModificationFrequency>,
+    partner: Option<Region>,
+    partner_reads: Vec<EncodedRead>,
+    partner_coverage: Vec<Coverage>,
+    highlight: Vec<Interval>,
+    strand_bias: Vec<StrandBias>,
+    labels: Vec<String>,
+    coverage_by_strand: bool,
+    width: Option<i64>,
+}
+
+impl Plot {
+    /// The plotted region.
+    pub fn region(&self) -> &Region {
+        &self.region
+    }
+
+    /// The vega-lite spec without any data, referring to the named datasets `reference`,
+    /// `reads`, `coverage`, `junctions`, `modifications`, `highlight` and `strand_bias`.
+    pub fn spec(&self) -> &Value {
+        &self.spec
+    }
+
+    /// The reference sequence of the plotted region.
+    pub fn reference(&self) -> &Reference {
+        &self.reference
+    }
+
+    /// The encoded reads of each sample.
+    pub fn reads(&self) -> &[EncodedRead] {
+        &self.reads
+    }
+
+    /// The encoded coverage of each sample.
+    pub fn coverage(&self) -> &[Coverage] {
+        &self.coverage
+    }
+
+    /// The splice junctions of each sample, empty unless the sashimi track is enabled.
+    pub fn junctions(&self) -> &[Junction] {
+        &self.junctions
+    }
+
+    /// The base modification frequencies of each sample, empty unless the reads have `MM` tags.
+    pub fn modifications(&self) -> &[ModificationFrequency] {
+        &self.modifications
+    }
+
+    /// The locus the supplementary alignments of split reads map to, if shown.
+    pub fn partner(&self) -> Option<&Region> {
+        self.partner.as_ref()
+    }
+
+    /// The encoded reads of each sample at the partner locus.
+    pub(crate) fn partner_reads(&self) -> &[EncodedRead] {
+        &self.partner_reads
+    }
+
+    /// The encoded coverage of each sample at the partner locus.
+    pub(crate) fn partner_coverage(&self) -> &[Coverage] {
+        &self.partner_coverage
+    }
+
+    /// The read summary shown above each sample's reads.
+    pub(crate) fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    /// Whether the coverage of the forward and the reverse strand is shown separately.
+    pub(crate) fn coverage_by_strand(&self) -> bool {
+        self.coverage_by_strand
+    }
+
+    /// The highlighted intervals in the coordinates of the plot.
+    pub fn highlight(&self) -> &[Interval] {
+        &self.highlight
+    }
+
+    /// The strand bias of each sample at each position of each highlighted site.
+    pub fn strand_bias(&self) -> &[StrandBias] {
+        &self.strand_bias
+    }
+
+    /// The width of the plot, `None` if it fills the available width.
+    pub fn width(&self) -> Option<i64> {
+        self.width
+    }
+
+    /// Returns the vega-lite spec with all datasets inlined.
+    pub fn inline_spec(&self) -> Value {
+        let mut specs = self.spec.clone();
+        specs["datasets"]["reference"] = json!(self.reference);
+        specs["datasets"]["reads"] = json!(self
+            .reads
+            .iter()
+            .chain(&self.partner_reads)
+            .collect::<Vec<_>>());
+        specs["datasets"]["highlight"] = json!(self.highlight);
+        specs["datasets"]["coverage"] = json!(self
+            .coverage
+            .iter()
+            .chain(&self.partner_coverage)
+            .collect::<Vec<_>>());
+        specs["datasets"]["junctions"] = json!(self.junctions);
+        specs["datasets"]["modifications"] = json!(self.modifications);
+        specs["datasets"]["strand_bias"] = json!(StrandBias::strongest(&self.strand_bias));
+        specs
+    }
+
+    /// Renders the plot into a standalone html page. If `no_embed_js` is set, the javascript
+    /// dependencies are loaded from a CDN instead of being embedded.
+    pub fn to_html(&self, no_embed_js: bool) -> Result<String> {
+        let mut templates = Tera::default();
+        templates.add_raw_template("plot", include_str!("../resources/plot.html.tera"))?;
+        let mut context = Context::new();
+        context.insert("num_bams", &self.reads.len());
+        context.insert("autofit", &self.width.is_none());
+        context.insert(
+            "spec",
+            &json!(compress_to_utf16(&self.inline_spec().to_string())).to_string(),
+        );
+        let scripts = if no_embed_js {
+            [
+                ("vega", CDN_VEGA),
+                ("vegalite", CDN_VEGA_LITE),
+                ("vegaembed", CDN_VEGA_EMBED),
+                ("lzstring", CDN_LZ_STRING),
+            ]
+        } else {
+            [
+                ("vega", embedded!("../resources/vega.min.js")),
+                ("vegalite", embedded!("../resources/vega-lite.min.js")),
+                ("vegaembed", embedded!("../resources/vega-embed.min.js")),
+                ("lzstring", embedded!("../resources/lz-string.min.js")),
+            ]
+        };
+        for (name, script) in scripts {
+            context.insert(name, script);
+        }
+        Ok(templates.render("plot", &context)?)
+    }
+
+    /// Renders the plot into a static SVG image without the need for vega-lite.
+    pub fn to_svg(&self) -> Result<String> {
+        render_svg(self)
+    }
+
+    /// Renders the plot into a static PNG image.
+    pub fn to_png(&self) -> Result<Vec<u8>> {
+        render_png(&self.to_svg()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn sample_1() -> PlotBuilder {
+        PlotBuilder::new(
+            vec![PathBuf::from("tests/sample_1/reads.bam")],
+            "tests/sample_1/reference.fa",
+        )
+    }
+
+    #[test]
+    fn test_plot_builder() {
+        let plot = sample_1()
+            .region(Region::from_str("chr1:1-20").unwrap())
+            .highlights([Interval::new("variant".to_string(), 10.0, 10.0)])
+            .plot()
+            .unwrap();
+        assert_eq!(plot.width(), Some(100));
+        assert_eq!(plot.reads().len(), 1);
+        assert_eq!(plot.reads()[0].sample(), "reads");
+        assert_eq!(plot.coverage()[0].sample(), "reads");
+        assert_eq!(plot.highlight()[0].start, 9.5);
+        let spec = plot.inline_spec();
+        assert_eq!(spec["vconcat"].as_array().unwrap().len(), 2);
+        assert_eq!(spec["datasets"]["reads"][0]["sample"], "reads");
+        assert_ne!(plot.spec()["datasets"]["reads"], spec["datasets"]["reads"]);
+    }
+
+    #[test]
+    fn test_plot_builder_with_split_partners() {
+        let (_dir, bam) = crate::utils::tests::split_reads();
+        let plot = PlotBuilder::new(vec![bam], "tests/sample_3/ref.fa")
+            .region(Region::from_str("1:281-420").unwrap())
+            .split_partners(true)
+            .plot()
+            .unwrap();
+        let partner = plot.partner().unwrap();
+        assert_eq!(partner.target, "1");
+        assert_eq!((partner.start, partner.end), (1399, 1649));
+        let reads = plot.reads()[0].decode().unwrap();
+        assert_eq!(reads.len(), 1);
+        assert_eq!(
+            reads[0].supplementary[0].to_string(),
+            "1,1500,+,50S50M,60,0"
+        );
+
+        let spec = plot.inline_spec();
+        assert_eq!(spec["resolve"]["scale"]["x"], "independent");
+        let panels = spec["vconcat"].as_array().unwrap();
+        assert_eq!(panels.len(), 2);
+        assert_eq!(
+            panels[1]["vconcat"][1]["encoding"]["x"]["scale"]["domain"],
+            json!([1398.5, 1648.5])
+        );
+        let samples = spec["datasets"]["reads"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|reads| reads["sample"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(samples, ["split", "split (1:1400-1649)"]);
+    }
+
+    #[test]
+    fn test_plot_builder_view_as_pairs() {
+        let plot = sample_1()
+            .region(Region::from_str("chr1:1-20").unwrap())
+            .color_by(Some(ReadAttribute::Strand))
+            .view_as_pairs(true)
+            .plot()
+            .unwrap();
+        let reads = plot.reads()[0].decode().unwrap();
+        assert_eq!(reads[0].color.as_deref(), Some("concordant"));
+        assert_eq!(reads[0].tlen, 315);
+        let layers = plot.spec()["vconcat"][1]["layer"].as_array().unwrap();
+        let link = layers
+            .iter()
+            .find(|layer| layer["name"] == "mate_link")
+            .unwrap();
+        assert_eq!(link["encoding"]["color"]["title"], "pair");
+        assert_eq!(link["encoding"]["strokeWidth"]["value"], 2);
+        assert!(layers
+            .iter()
+            .all(|layer| layer["encoding"]["color"]["title"] != "strand"));
+    }
+
+    #[test]
+    fn test_plot_builder_threads() {
+        let plot = |threads: usize| {
+            PlotBuilder::new(
+                vec![
+                    PathBuf::from("tests/sample_3/NA12878.bam"),
+                    PathBuf::from("tests/sample_3/NA12878_with_clipping_read.bam"),
+                ],
+                "tests/sample_3/ref.fa",
+            )
+            .region(Region::from_str("1:1-2411").unwrap())
+            .threads(threads)
+            .plot()
+            .unwrap()
+            .inline_spec()
+        };
+        assert_eq!(plot(1), plot(4));
+    }
+
+    #[test]
+    fn test_plot_builder_split_threads() {
+        let split = |threads: usize, files: usize| {
+            PlotBuilder::new(Vec::new(), "tests/sample_3/ref.fa")
+                .threads(threads)
+                .split_threads(files)
+        };
+        assert_eq!(split(1, 3), (1, 1));
+        assert_eq!(split(8, 1), (1, 8));
+        // The threads are never oversubscribed, even if they do not divide evenly.
+        assert_eq!(split(8, 3), (3, 2));
+        assert_eq!(split(2, 4), (2, 1));
+    }
+
+    #[test]
+    fn test_plot_builder_dedup_mate_overlap() {
+        let depth = |dedup_mate_overlap: bool| {
+            let plot = PlotBuilder::new(
+                vec![PathBuf::from("tests/sample_2/sample.bam")],
+                "tests/sample_2/ref.fa",
+            )
+            .region(Region::from_str("chr6:200-300").unwrap())
+            .dedup_mate_overlap(dedup_mate_overlap)
+            .plot()
+            .unwrap();
+            plot.coverage()[0]
+                .decode()
+                .unwrap()
+                .iter()
+                .map(|coverage| coverage.depth())
+                .sum::<usize>()
+        };
+        assert!(depth(true) < depth(false));
+    }
+
+    #[test]
+    fn test_plot_builder_display_mode() {
+        let plot = sample_1()
+            .region(Region::from_str("chr1:1-20").unwrap())
+            .display_mode(DisplayMode::Squished)
+            .plot()
+            .unwrap();
+        let reads = &plot.spec()["vconcat"][1];
+        assert_eq!(reads["height"]["step"], 2.0);
+        let layers = reads["layer"].as_array().unwrap();
+        assert!(layers
+            .iter()
+            .any(|layer| layer["encoding"]["strokeWidth"]["value"] == 4.0));
+        assert!(layers.iter().all(|layer| layer["encoding"]
+            .get("strokeWidth")
+            .is_none_or(|width| !width.is_null())));
+    }
+
+    #[test]
+    fn test_plot_builder_downsample_strategy() {
+        let plot = PlotBuilder::new(
+            vec![PathBuf::from("tests/sample_2/sample.bam")],
+            "tests/sample_2/ref.fa",
+        )
+        .region(Region::from_str("chr6:200-300").unwrap())
+        .max_read_depth(5)
+        .downsample_strategy(DownsampleStrategy::ByNameHash)
+        .seed(7)
+        .plot()
+        .unwrap();
+        let title = plot.spec()["vconcat"][1]["encoding"]["y"]["axis"]["title"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(title.contains("downsampling: by-name-hash"));
+    }
+
+    #[test]
+    fn test_plot_builder_coverage_by_strand() {
+        let plot = sample_1()
+            .region(Region::from_str("chr1:1-20").unwrap())
+            .highlights([Interval::new("variant".to_string(), 10.0, 10.0)])
+            .coverage_by_strand(true)
+            .plot()
+            .unwrap();
+        let spec = plot.spec();
+        assert_eq!(spec["vconcat"].as_array().unwrap().len(), 2);
+        let coverage = &spec["vconcat"][0];
+        assert_eq!(coverage["encoding"]["detail"]["field"], "strand");
+        assert_eq!(
+            coverage["transform"][0]["filter"],
+            "datum.sample == 'reads'"
+        );
+        let highlight = &spec["vconcat"][1]["layer"][0];
+        assert_eq!(
+            highlight["transform"][0]["calculate"],
+            "'reads:' + datum.name"
+        );
+
+        let strand_bias = plot.strand_bias();
+        assert_eq!(strand_bias.len(), 1);
+        assert_eq!(strand_bias[0].sample(), "reads");
+        assert_eq!(strand_bias[0].name(), "variant");
+        assert_eq!(strand_bias[0].position(), 9);
+        assert_eq!(strand_bias[0].reference_counts(), (1, 0));
+        assert_eq!(strand_bias[0].alternative_counts(), (0, 0));
+        assert_eq!(
+            plot.inline_spec()["datasets"]["strand_bias"][0]["site"],
+            "reads:variant"
+        );
+        assert_eq!(
+            plot.inline_spec()["datasets"]["strand_bias"][0]["position"],
+            9
+        );
+    }
+
+    #[test]
+    fn test_plot_builder_with_base_modifications() {
+        let (_dir, bam) = crate::utils::tests::modified_reads();
+        let plot = PlotBuilder::new(vec![bam], "tests/sample_1/reference.fa")
+            .region(Region::from_str("chr1:1-20").unwrap())
+            .plot()
+            .unwrap();
+        let frequencies = plot
+            .modifications()
+            .iter()
+            .map(|f| (f.position(), f.code(), f.modified(), f.total()))
+            .collect::<Vec<_>>();
+        assert_eq!(frequencies, [(3, "m", 2, 2), (4, "m", 0, 2)]);
+        let views = plot.spec()["vconcat"].as_array().unwrap();
+        assert_eq!(views.len(), 3);
+        assert_eq!(views[1]["data"]["name"], "modifications");
+        assert_eq!(
+            plot.inline_spec()["datasets"]["modifications"][0]["sample"],
+            "modified"
+        );
+
+        // Without base modifications, no track is added.
+        let plot = sample_1()
+            .region(Region::from_str("chr1:1-20").unwrap())
+            .plot()
+            .unwrap();
+        assert!(plot.modifications().is_empty());
+        assert_eq!(plot.spec()["vconcat"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_plot_builder_with_cram() {
+        let (_dir, cram) = crate::utils::tests::cram_reads();
+        let region = Region::from_str("chr1:1-20").unwrap();
+        let cram_plot = PlotBuilder::new(vec![cram], "tests/sample_1/reference.fa")
+            .region(region.clone())
+            .plot()
+            .unwrap();
+        let bam_plot = sample_1().region(region).plot().unwrap();
+        assert_eq!(cram_plot.reads(), bam_plot.reads());
+        assert_eq!(cram_plot.coverage(), bam_plot.coverage());
+    }
+
+    #[test]
+    fn test_plot_builder_without_reference() {
+        let region = Region::from_str("1:301-400").unwrap();
+        let bams = vec![PathBuf::from("tests/sample_3/NA12878.bam")];
+        let md_plot = PlotBuilder::without_reference(bams.clone())
+            .region(region.clone())
+            .plot()
+            .unwrap();
+        let plot = PlotBuilder::new(bams, "tests/sample_3/ref.fa")
+            .region(region)
+            .plot()
+            .unwrap();
+        assert_eq!(md_plot.reference(), plot.reference());
+        assert_eq!(md_plot.reads(), plot.reads());
+        assert!(
+            PlotBuilder::without_reference(vec![PathBuf::from("tests/sample_1/reads.bam")])
+                .region(Region::from_str("chr1:1-20").unwrap())
+                .plot()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_plot_builder_with_remote_files() {
+        let url = crate::utils::tests::serve_directory(Path::new("tests/sample_1"));
+        let region = Region::from_str("chr1:1-20").unwrap();
+        let remote_plot = PlotBuilder::new(
+            vec![PathBuf::from(format!("{url}/reads.bam"))],
+            format!("{url}/reference.fa"),
+        )
+        .region(region.clone())
+        .plot()
+        .unwrap();
+        let local_plot = sample_1().region(region).plot().unwrap();
+        assert_eq!(remote_plot.reference(), local_plot.reference());
+        assert_eq!(remote_plot.reads(), local_plot.reads());
+        assert_eq!(remote_plot.coverage(), local_plot.coverage());
+    }
+
+    #[test]
+    fn test_plot_builder_color_by() {
+        let plot = sample_1()
+            .region(Region::from_str("chr1:1-20").unwrap())
+            .color_by(Some(ReadAttribute::Strand))
+            .plot()
+            .unwrap();
+        let reads = &plot.spec()["vconcat"][1];
+        assert_eq!(reads["resolve"]["scale"]["color"], "independent");
+        assert!(reads["layer"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|layer| layer["encoding"]["color"]["field"] == "color"
+                && layer["encoding"]["color"]["title"] == "strand"));
+    }
+
+    #[test]
+    fn test_plot_builder_requires_region() {
+        assert!(sample_1().plot().is_err());
+    }
+
+    #[test]
+    fn test_plotter_reuses_readers() {
+        let mut plotter = sample_1().autofit(true).build().unwrap();
+        for region in ["chr1:1-20", "chr1:50-100"] {
+            let plot = plotter.plot(&Region::from_str(region).unwrap()).unwrap();
+            assert_eq!(plot.region().to_string(), region);
+            assert_eq!(plot.width(), None);
+        }
+    }
+
+    #[test]
+    fn test_plotter_reuses_reference_readers() {
+        let builder = PlotBuilder::new(
+            vec![
+                PathBuf::from("tests/sample_3/NA12878.bam"),
+                PathBuf::from("tests/sample_3/NA12878_with_clipping_read.bam"),
+            ],
+            "tests/sample_3/ref.fa",
+        );
+        let mut sequential = builder.clone().build().unwrap();
+        let mut concurrent = builder.threads(4).build().unwrap();
+        // The reference is opened once for each thread processing alignment files.
+        assert_eq!(sequential.fastas.len(), 1);
+        assert_eq!(concurrent.fastas.len(), 2);
+        for region in ["1:1-1200", "1:1200-2411"] {
+            let region = Region::from_str(region).unwrap();
+            assert_eq!(
+                sequential.plot(&region).unwrap().inline_spec(),
+                concurrent.plot(&region).unwrap().inline_spec()
+            );
+        }
+    }
+}
@@ -1,15 +1,23 @@
+use crate::builder::PlotBuilder;
+use crate::expression::Expression;
+use crate::filter::parse_flags;
+use crate::plot::{DisplayMode, DownsampleStrategy, ReadAttribute, SortBy};
 use crate::utils::{
-    ensure_bam_index, ensure_fasta_index, get_fasta_length, get_ref_and_bam_from_cwd,
+    ensure_bam_index, ensure_fasta_index, get_alignment_target_length, get_fasta_length,
+    get_ref_and_bam_from_cwd, open_alignment, read_vcf,
 };
 use anyhow::{anyhow, Context, Result};
 use log::warn;
-use rust_htslib::bam;
 use rust_htslib::bam::{FetchDefinition, Read};
-use rust_htslib::bcf::{Read as BCFRead, Reader};
+use rust_htslib::bcf;
+use rust_htslib::bcf::header::{HeaderView, TagType};
+use rust_htslib::bcf::Read as BCFRead;
 use serde::Deserialize;
 use serde::Serialize;
 use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use structopt::StructOpt;
@@ -20,11 +28,12 @@ use structopt::StructOpt;
     name = "alignoth"
 )]
 pub struct Alignoth {
-    /// BAM files to be visualized.
+    /// BAM or CRAM files to be visualized. Can also be http(s) URLs of indexed files.
     #[structopt(long, short = "b", parse(from_os_str))]
     pub(crate) bam_path: Vec<PathBuf>,
 
-    /// Path to the reference fasta file.
+    /// Path to the reference fasta file. It is also used to decode CRAM files. If omitted,
+    /// mismatches are reconstructed from the MD tags of the reads.
     #[structopt(long, short = "r", parse(from_os_str))]
     pub(crate) reference: Option<PathBuf>,
 
@@ -60,6 +69,22 @@ pub struct Alignoth {
     #[structopt(long, short = "d", default_value = "500")]
     pub(crate) max_read_depth: usize,
 
+    /// Set how reads are subsampled if they exceed the maximum read depth: rows keeps random rows of a random sample of the reads, reads keeps random reads (with their mates), by-name-hash keeps reads by a hash of their name so the same reads are kept across regions, and prefer-variant-support keeps reads carrying a mismatch or indel at a highlighted site first.
+    #[structopt(long, default_value = "rows")]
+    pub(crate) downsample_strategy: DownsampleStrategy,
+
+    /// Set the seed used to randomly subsample reads.
+    #[structopt(long, default_value = "42")]
+    pub(crate) seed: u64,
+
+    /// Set how densely reads are packed into rows: expanded, squished (half the row height and at least one base between reads) or collapsed (a quarter of the row height and no space between reads).
+    #[structopt(long, default_value = "expanded")]
+    pub(crate) display_mode: DisplayMode,
+
+    /// Set the number of threads used to decompress and process the BAM files. Multiple BAM files are then processed concurrently. The threads are split evenly between the BAM files processed at once, each using its share both to decompress its records and to convert the records of long regions into reads.
+    #[structopt(long, default_value = "1")]
+    pub(crate) threads: usize,
+
     /// Set the data format of the read, reference and highlight data.
     #[structopt(long, short = "f", default_value)]
     pub(crate) data_format: DataFormat,
@@ -98,27 +123,116 @@ pub struct Alignoth {
     pub(crate) output: Option<PathBuf>,
 
     /// If present, the generated plot will inserted into a plain html file containing the plot centered which is then written to stdout
-    #[structopt(long, conflicts_with("output"))]
+    #[structopt(long)]
     pub(crate) html: bool,
 
+    /// If present, the plot is rendered natively into a static SVG image which is then written to stdout. No browser is needed to view or convert the result.
+    #[structopt(long, conflicts_with_all = &["html", "png"])]
+    pub(crate) svg: bool,
+
+    /// If present, the plot is rendered natively into a static PNG image which is then written to stdout.
+    #[structopt(long, conflicts_with_all = &["html", "svg"])]
+    pub(crate) png: bool,
+
     /// Displays the given content of the aux tags in the tooltip of the plot. Multiple usage for more than one tag is possible.
     #[structopt(long, short = "x")]
     pub(crate) aux_tag: Option<Vec<String>>,
 
     /// If present, the generated html will not embed javscript dependencies and therefore be considerably smaller but require internet access to load the dependencies.
-    #[structopt(long, conflicts_with("output"))]
+    #[structopt(long)]
     pub(crate) no_embed_js: bool,
 
-    /// The minimum percentage of mismatches compared to total read depth at that point to display in the coverage plot.
+    /// The minimum percentage of mismatches and indels compared to total read depth at that point to display in the coverage plot.
     #[structopt(long, default_value = "1.0")]
     pub(crate) mismatch_display_min_percent: f64,
 
+    /// Mismatches with a lower base quality are not counted as mismatches in the coverage plot.
+    #[structopt(long, default_value = "0")]
+    pub(crate) min_base_quality: u8,
+
     /// If set, reads are clamped to the boundaries of the specified region before processing.
     #[structopt(long)]
     pub(crate) clamp_reads: bool,
+
+    /// Shows soft clipped bases at the read ends in a distinct color instead of hiding them. Soft clipped bases never count towards the coverage.
+    #[structopt(long)]
+    pub(crate) show_soft_clips: bool,
+
+    /// Shows a sashimi track above the reads with one arc per splice junction (N operations in the CIGAR strings), labelled with the number of supporting reads.
+    #[structopt(long)]
+    pub(crate) sashimi: bool,
+
+    /// Marks reads with supplementary alignments (SA tag) and shows the locus most of their other segments map to in a second panel below the plotted region. Clicking a read highlights its other segments there.
+    #[structopt(long)]
+    pub(crate) split_partners: bool,
+
+    /// Connects mates by a line and colors reads by the class of their pair: inward facing pairs by their insert size (outliers are more than three times larger or smaller than the median), all others by their orientation (RF, FF, RR) or as interchromosomal. Takes precedence over --color-by.
+    #[structopt(long)]
+    pub(crate) view_as_pairs: bool,
+
+    /// Shows the coverage of reads on the forward strand above and the one of reads on the reverse strand below the axis of the coverage track.
+    #[structopt(long)]
+    pub(crate) coverage_by_strand: bool,
+
+    /// Counts positions covered by both mates of a pair only once in the coverage track. If the mates disagree, the base with the higher quality is counted, like samtools mpileup does.
+    #[structopt(long)]
+    pub(crate) dedup_mate_overlap: bool,
+
+    /// Path to a BED file or a plain list of regions (one `target:start-end` per line, optionally followed by a name). One plot per region is written to the --output directory together with an index.html linking them all.
+    #[structopt(
+        long,
+        parse(from_os_str),
+        requires("output"),
+        conflicts_with_all = &["region", "around", "around_vcf_record", "plot_all"]
+    )]
+    pub(crate) regions_file: Option<PathBuf>,
+
+    /// Plots a region around every record of the VCF file given via --vcf. One plot per record is written to the --output directory, named after CHROM_POS_REF_ALT, together with an index.html linking them all.
+    #[structopt(
+        long,
+        requires_all = &["vcf", "output"],
+        conflicts_with_all = &["region", "around", "around_vcf_record", "plot_all", "regions_file"]
+    )]
+    pub(crate) each_vcf_record: bool,
+
+    /// Only plot VCF records whose FILTER column is PASS when using --each-vcf-record.
+    #[structopt(long, requires("each_vcf_record"))]
+    pub(crate) pass_only: bool,
+
+    /// Only plot VCF records whose INFO fields match the given expression when using --each-vcf-record. Comparisons can be combined with &&. Example: 'DP>10 && AF>=0.2'
+    #[structopt(long, requires("each_vcf_record"))]
+    pub(crate) info_filter: Option<Expression>,
+
+    /// Stacks reads into separate, labelled blocks per value of the given attribute. Possible values are strand, mapq, pair-orientation or any aux tag, e.g. HP for the haplotype.
+    #[structopt(long)]
+    pub(crate) group_by: Option<ReadAttribute>,
+
+    /// Colors reads by the given attribute instead of their mapping quality. Possible values are strand, mapq, pair-orientation or any aux tag, e.g. HP for the haplotype.
+    #[structopt(long)]
+    pub(crate) color_by: Option<ReadAttribute>,
+
+    /// Sorts reads before they are packed into rows. Possible values are base@target:position (reads with an alternative base at the given 1-based position first, then deletions, then reference bases), strand, mapq, insert-size or tag:XX for the aux tag XX, e.g. tag:HP.
+    #[structopt(long)]
+    pub(crate) sort_by: Option<SortBy>,
+
+    /// Skips reads with a mapping quality below the given value.
+    #[structopt(long, default_value = "0")]
+    pub(crate) min_mapq: u8,
+
+    /// Only plots reads that have all of the given SAM flags set (like samtools view -f). Flags can be given as a number or as a comma-separated list of names, e.g. PAIRED,PROPER_PAIR.
+    #[structopt(long, default_value = "0", parse(try_from_str = parse_flags))]
+    pub(crate) include_flags: u16,
+
+    /// Skips reads that have any of the given SAM flags set (like samtools view -F). Flags can be given as a number or as a comma-separated list of names, e.g. DUP,SECONDARY.
+    #[structopt(long, default_value = "0", parse(try_from_str = parse_flags))]
+    pub(crate) exclude_flags: u16,
+
+    /// Only plots reads whose aux tags match the given expression. Comparisons can be combined with &&. Example: 'NM<5 && HP==1'
+    #[structopt(long)]
+    pub(crate) aux_filter: Option<Expression>,
 }
 
-pub(crate) trait Preprocess {
+pub trait Preprocess {
     fn preprocess(&mut self) -> anyhow::Result<()>;
 }
 
@@ -133,9 +247,18 @@ impl Preprocess for Alignoth {
             && self.around.is_none()
             && !self.plot_all
             && self.around_vcf_record.is_none()
+            && !self.is_batch()
+        {
+            return Err(anyhow!(
+                "You have to specify either a region or a base to plot around or use the --plot-all, --around-vcf-record, --regions-file or --each-vcf-record option."
+            ));
+        }
+        if self.output.is_some()
+            && !self.is_batch()
+            && (self.html || self.no_embed_js || self.svg || self.png)
         {
             return Err(anyhow!(
-                "You have to specify either a region or a base to plot around or use the --plot-all or --around-vcf-record option."
+                "The --html, --no-embed-js, --svg and --png options can only be combined with --output when plotting multiple regions via --regions-file or --each-vcf-record."
             ));
         }
         if self.bam_path.is_empty() && self.reference.is_none() {
@@ -153,22 +276,22 @@ impl Preprocess for Alignoth {
                 "Missing bam file. Please use the -b flag to specify the bam file."
             ));
         }
-        if self.reference.is_none() {
-            return Err(anyhow!(
-                "Missing reference file. Please use the -r flag to specify the reference file."
-            ));
-        }
         for bam in &self.bam_path {
             ensure_bam_index(bam)?;
         }
-        ensure_fasta_index(self.reference.as_ref().unwrap())?;
+        if let Some(reference) = &self.reference {
+            ensure_fasta_index(reference)?;
+        }
+        if self.is_batch() {
+            return Ok(());
+        }
         if self.plot_all {
             warn!("You are using the --plot-all option. This is not recommended for large bam files or files with multiple targets.");
             let mut min_start = i64::MAX;
             let mut max_end = i64::MIN;
             let mut target = String::new();
             for bam in &self.bam_path {
-                let r = Region::from_bam(bam)?;
+                let r = Region::from_bam(bam, self.reference.as_deref())?;
                 if target.is_empty() {
                     target = r.target.clone();
                 } else if target != r.target {
@@ -192,14 +315,96 @@ impl Preprocess for Alignoth {
             )?);
         }
         let region = self.region.as_ref().unwrap();
-        let target_length =
-            get_fasta_length(self.reference.as_ref().unwrap(), &region.target)? as i64;
+        let target_length = self.target_length(&region.target)?;
         self.region = Some(region.clamp(0, target_length));
         Ok(())
     }
 }
 
 impl Alignoth {
+    /// Returns a plot builder configured by the given command line options.
+    pub(crate) fn plot_builder(&self) -> PlotBuilder {
+        let builder = match &self.reference {
+            Some(reference) => PlotBuilder::new(self.bam_path.clone(), reference),
+            None => PlotBuilder::without_reference(self.bam_path.clone()),
+        };
+        builder
+            .max_read_depth(self.max_read_depth)
+            .downsample_strategy(self.downsample_strategy)
+            .seed(self.seed)
+            .display_mode(self.display_mode)
+            .threads(self.threads)
+            .max_width(self.max_width)
+            .autofit(self.html)
+            .aux_tags(self.aux_tag.clone())
+            .mismatch_display_min_percent(self.mismatch_display_min_percent)
+            .min_base_quality(self.min_base_quality)
+            .clamp_reads(self.clamp_reads)
+            .show_soft_clips(self.show_soft_clips)
+            .sashimi(self.sashimi)
+            .split_partners(self.split_partners)
+            .view_as_pairs(self.view_as_pairs)
+            .coverage_by_strand(self.coverage_by_strand)
+            .dedup_mate_overlap(self.dedup_mate_overlap)
+            .group_by(self.group_by.clone())
+            .color_by(self.color_by.clone())
+            .sort_by(self.sort_by.clone())
+            .min_mapq(self.min_mapq)
+            .include_flags(self.include_flags)
+            .exclude_flags(self.exclude_flags)
+            .aux_filter(self.aux_filter.clone())
+            .highlights(self.highlight.iter().flatten().cloned())
+            .vcf(self.vcf.clone())
+            .bed(self.bed.clone())
+    }
+
+    /// Returns whether multiple regions are plotted via --regions-file or --each-vcf-record.
+    pub(crate) fn is_batch(&self) -> bool {
+        self.regions_file.is_some() || self.each_vcf_record
+    }
+
+    /// Reads the regions given via --regions-file or --each-vcf-record, clamped to the bounds of
+    /// their targets.
+    pub(crate) fn batch_regions(&self) -> Result<Vec<NamedRegion>> {
+        let regions = if self.each_vcf_record {
+            NamedRegion::from_vcf(
+                self.vcf.as_ref().unwrap(),
+                self.pass_only,
+                self.info_filter.as_ref(),
+            )?
+        } else {
+            NamedRegion::from_file(self.regions_file.as_ref().unwrap())?
+        };
+        // Targets are usually shared by many regions, so their lengths are only looked up once.
+        let mut target_lengths: HashMap<String, i64> = HashMap::new();
+        regions
+            .into_iter()
+            .map(|named| {
+                let target_length = match target_lengths.get(&named.region.target) {
+                    Some(target_length) => *target_length,
+                    None => {
+                        let target_length = self.target_length(&named.region.target)?;
+                        target_lengths.insert(named.region.target.clone(), target_length);
+                        target_length
+                    }
+                };
+                Ok(NamedRegion {
+                    region: named.region.clamp(0, target_length),
+                    ..named
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the length of the given target, taken from the reference if given and from the
+    /// header of the first alignment file otherwise.
+    fn target_length(&self, target: &str) -> Result<i64> {
+        Ok(match &self.reference {
+            Some(reference) => get_fasta_length(reference, target)? as i64,
+            None => get_alignment_target_length(&self.bam_path[0], target)? as i64,
+        })
+    }
+
     /// Renders the non-interactive `alignoth` command that reproduces this configuration.
     pub(crate) fn to_command(&self) -> String {
         let mut args = vec!["alignoth".to_string()];
@@ -235,27 +440,101 @@ impl Alignoth {
             args.push("-d".to_string());
             args.push(self.max_read_depth.to_string());
         }
+        if self.downsample_strategy != DownsampleStrategy::Rows {
+            args.push("--downsample-strategy".to_string());
+            args.push(self.downsample_strategy.to_string());
+        }
+        if self.seed != 42 {
+            args.push("--seed".to_string());
+            args.push(self.seed.to_string());
+        }
+        if self.display_mode != DisplayMode::Expanded {
+            args.push("--display-mode".to_string());
+            args.push(self.display_mode.to_string());
+        }
+        if self.threads != 1 {
+            args.push("--threads".to_string());
+            args.push(self.threads.to_string());
+        }
+        if let Some(group_by) = &self.group_by {
+            args.push("--group-by".to_string());
+            args.push(group_by.to_string());
+        }
+        if let Some(color_by) = &self.color_by {
+            args.push("--color-by".to_string());
+            args.push(color_by.to_string());
+        }
+        if let Some(sort_by) = &self.sort_by {
+            args.push("--sort-by".to_string());
+            args.push(sort_by.to_string());
+        }
+        if self.sashimi {
+            args.push("--sashimi".to_string());
+        }
+        if self.split_partners {
+            args.push("--split-partners".to_string());
+        }
+        if self.view_as_pairs {
+            args.push("--view-as-pairs".to_string());
+        }
+        if self.coverage_by_strand {
+            args.push("--coverage-by-strand".to_string());
+        }
+        if self.dedup_mate_overlap {
+            args.push("--dedup-mate-overlap".to_string());
+        }
+        if self.show_soft_clips {
+            args.push("--show-soft-clips".to_string());
+        }
+        if self.min_base_quality > 0 {
+            args.push("--min-base-quality".to_string());
+            args.push(self.min_base_quality.to_string());
+        }
+        if self.min_mapq > 0 {
+            args.push("--min-mapq".to_string());
+            args.push(self.min_mapq.to_string());
+        }
+        if self.include_flags > 0 {
+            args.push("--include-flags".to_string());
+            args.push(self.include_flags.to_string());
+        }
+        if self.exclude_flags > 0 {
+            args.push("--exclude-flags".to_string());
+            args.push(self.exclude_flags.to_string());
+        }
+        if let Some(aux_filter) = &self.aux_filter {
+            args.push("--aux-filter".to_string());
+            args.push(quote(&aux_filter.to_string()));
+        }
         if self.html {
             args.push("--html".to_string());
         }
+        if self.svg {
+            args.push("--svg".to_string());
+        }
+        if self.png {
+            args.push("--png".to_string());
+        }
         args.join(" ")
     }
 }
 
 /// Wraps `value` in single quotes if a shell would otherwise split or drop it.
 fn quote(value: &str) -> String {
-    if value.is_empty() || value.contains(char::is_whitespace) {
+    if value.is_empty() || value.contains(|c: char| c.is_whitespace() || "<>&|;$*?()".contains(c)) {
         format!("'{value}'")
     } else {
         value.to_string()
     }
 }
 
+/// A genomic region with a 0-based start and an exclusive end. It is parsed from and displayed
+/// as the 1-based, fully inclusive `target:start-end`.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Region {
-    pub(crate) target: String,
-    pub(crate) start: i64,
-    pub(crate) end: i64,
+    pub target: String,
+    pub start: i64,
+    pub end: i64,
 }
 
 pub(crate) trait Clamp {
@@ -314,14 +593,14 @@ impl FromAround for Region {
 }
 
 pub(crate) trait FromBam {
-    fn from_bam(bam_path: &Path) -> Result<Self>
+    fn from_bam(bam_path: &Path, reference: Option<&Path>) -> Result<Self>
     where
         Self: Sized;
 }
 
 impl FromBam for Region {
-    fn from_bam(bam_path: &Path) -> Result<Self> {
-        let mut bam = bam::IndexedReader::from_path(bam_path)?;
+    fn from_bam(bam_path: &Path, reference: Option<&Path>) -> Result<Self> {
+        let mut bam = open_alignment(bam_path, reference)?;
         let header = bam.header();
         let target = header.target_names()[0];
         let target = std::str::from_utf8(target)?.to_string();
@@ -345,6 +624,161 @@ impl FromBam for Region {
     }
 }
 
+/// Returns the first value of the given INFO field as a string, or `None` if it is missing. Flags
+/// are reported as `true`.
+fn info_value(record: &mut bcf::Record, header: &HeaderView, key: &str) -> Option<String> {
+    let tag = key.as_bytes();
+    let (tag_type, _) = header.info_type(tag).ok()?;
+    let mut info = record.info(tag);
+    match tag_type {
+        TagType::Flag => info.flag().ok()?.then(|| "true".to_string()),
+        TagType::Integer => Some(info.integer().ok()??.first()?.to_string()),
+        TagType::Float => Some(info.float().ok()??.first()?.to_string()),
+        TagType::String => {
+            Some(String::from_utf8_lossy(info.string().ok()??.first()?).into_owned())
+        }
+    }
+}
+
+/// A region plotted in batch mode, together with the name its output files are written under.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct NamedRegion {
+    pub(crate) name: String,
+    pub(crate) region: Region,
+}
+
+impl NamedRegion {
+    /// Parses a BED file or a plain list of `target:start-end` regions. BED records are named
+    /// after their name column and plain regions after an optional second column, falling back
+    /// to the region itself.
+    pub(crate) fn from_file(path: &Path) -> Result<Vec<Self>> {
+        let content = std::fs::read_to_string(path)
+            .context(format!("Could not read regions file {}", path.display()))?;
+        let mut regions = Vec::new();
+        for (line_number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty()
+                || line.starts_with('#')
+                || line.starts_with("track")
+                || line.starts_with("browser")
+            {
+                continue;
+            }
+            let named = Self::from_line(line).context(format!(
+                "Could not parse line {} of regions file {}",
+                line_number + 1,
+                path.display()
+            ))?;
+            regions.push(named);
+        }
+        if regions.is_empty() {
+            return Err(anyhow!("No regions found in {}", path.display()));
+        }
+        Ok(regions)
+    }
+
+    /// Reads a region around every record of the given VCF file in a single pass, optionally
+    /// keeping only records that PASS all filters and whose INFO fields match `info_filter`.
+    /// Records are named `CHROM_POS_REF_ALT`, with a numbered suffix for repeated names, e.g. of
+    /// duplicate records or of multiallelic records split across lines.
+    pub(crate) fn from_vcf(
+        path: &Path,
+        pass_only: bool,
+        info_filter: Option<&Expression>,
+    ) -> Result<Vec<Self>> {
+        let mut reader = read_vcf(path)?;
+        let header = reader.header().clone();
+        let mut regions = Vec::new();
+        for record in reader.records() {
+            let mut record = record?;
+            if pass_only && !record.has_filter("PASS".as_bytes()) {
+                continue;
+            }
+            if let Some(expression) = info_filter {
+                if !expression.matches(|key| info_value(&mut record, &header, key)) {
+                    continue;
+                }
+            }
+            let alleles = record
+                .alleles()
+                .iter()
+                .map(|allele| String::from_utf8_lossy(allele).into_owned())
+                .collect::<Vec<_>>();
+            let region = Region::around_vcf_record(&record, &header)?;
+            let name = format!(
+                "{}_{}_{}_{}",
+                region.target,
+                record.pos() + 1,
+                alleles[0],
+                alleles[1..].join(",")
+            );
+            regions.push(NamedRegion { name, region });
+        }
+        if regions.is_empty() {
+            return Err(anyhow!("No matching records found in {}", path.display()));
+        }
+        let names = deduplicate(regions.iter().map(|named| named.name.clone()));
+        Ok(regions
+            .into_iter()
+            .zip(names)
+            .map(|(named, name)| NamedRegion { name, ..named })
+            .collect())
+    }
+
+    fn from_line(line: &str) -> Result<Self> {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let bed_coordinates = fields
+            .get(1)
+            .zip(fields.get(2))
+            .and_then(|(start, end)| Some((start.parse::<i64>().ok()?, end.parse::<i64>().ok()?)));
+        let (region, name) = if let Some((start, end)) = bed_coordinates {
+            let region = Region {
+                target: fields[0].to_string(),
+                start,
+                end,
+            };
+            (region, fields.get(3))
+        } else {
+            (Region::from_str(fields[0])?, fields.get(1))
+        };
+        let name = name.map_or_else(|| region.to_string(), |name| name.to_string());
+        Ok(NamedRegion { name, region })
+    }
+
+    /// Returns the name with all characters that are unsafe in file names replaced by `_`.
+    pub(crate) fn file_stem(&self) -> String {
+        self.name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+}
+
+/// Makes the given names distinct by appending `_2`, `_3`, … to each repeated one. Names are
+/// compared case-insensitively, as they may end up as file names on case-insensitive file
+/// systems.
+pub(crate) fn deduplicate(names: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    names
+        .into_iter()
+        .map(|name| {
+            let mut unique = name.clone();
+            let mut count = 1;
+            while !seen.insert(unique.to_lowercase()) {
+                count += 1;
+                unique = format!("{name}_{count}");
+            }
+            unique
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Around {
     pub(crate) target: String,
@@ -396,7 +830,7 @@ impl FromStr for DataFormat {
 
 impl Region {
     /// Returns the length of the Region
-    pub(crate) fn length(&self) -> i64 {
+    pub fn length(&self) -> i64 {
         self.end - self.start
     }
 
@@ -409,7 +843,7 @@ impl Region {
     }
 
     pub(crate) fn from_vcf_record(vcf_record_index: u64, vcf: &PathBuf) -> Result<Self> {
-        let mut reader = Reader::from_path(vcf)?;
+        let mut reader = read_vcf(vcf)?;
         let header = reader.header().clone();
         let record = reader
             .records()
@@ -418,22 +852,26 @@ impl Region {
                 "Given vcf record index {vcf_record_index} not found in {}",
                 vcf.display()
             ))??;
-        let start = &record.pos();
-        let end = &record.end();
+        Region::around_vcf_record(&record, &header)
+    }
+
+    /// Returns the region starting 500bp before and ending 500bp after the given VCF record.
+    fn around_vcf_record(record: &bcf::Record, header: &HeaderView) -> Result<Self> {
         let target = String::from_utf8(header.rid2name(record.rid().unwrap())?.to_vec())?;
         Ok(Region {
             target,
-            start: *start - 500,
-            end: *end + 500,
+            start: record.pos() - 500,
+            end: record.end() + 500,
         })
     }
 }
 
+/// A named interval that is highlighted in the plot.
 #[derive(Debug, Clone, Serialize, PartialEq)]
 pub struct Interval {
-    pub(crate) name: String,
-    pub(crate) start: f64,
-    pub(crate) end: f64,
+    pub name: String,
+    pub start: f64,
+    pub end: f64,
 }
 
 impl FromStr for Interval {
@@ -486,6 +924,12 @@ impl Interval {
         Self { name, start, end }
     }
 
+    /// The 0-based, end exclusive positions of the bases covered by the interval, both before
+    /// and after preprocessing.
+    pub(crate) fn positions(&self) -> Range<i64> {
+        (self.start.ceil() as i64 - 1)..(self.end.floor() as i64)
+    }
+
     // Adjusts interval to match coordinate system of final vega-lite plot
     pub(crate) fn preprocess(&mut self) {
         self.start -= 0.5;
@@ -494,12 +938,18 @@ impl Interval {
 }
 
 #[cfg(test)]
-mod tests {
-    use crate::cli::{Alignoth, Around, DataFormat, FromAround, Interval, Preprocess, Region};
+pub(crate) mod tests {
+    use crate::cli::{
+        deduplicate, Alignoth, Around, DataFormat, FromAround, Interval, NamedRegion, Preprocess,
+        Region,
+    };
+    use crate::expression::Expression;
+    use crate::plot::{DisplayMode, DownsampleStrategy};
+    use std::io::Write;
     use std::path::PathBuf;
     use std::str::FromStr;
 
-    fn base_alignoth() -> Alignoth {
+    pub(crate) fn base_alignoth() -> Alignoth {
         Alignoth {
             bam_path: vec![PathBuf::from("sample.bam")],
             reference: Some(PathBuf::from("ref.fa")),
@@ -515,6 +965,10 @@ mod tests {
             vcf: None,
             bed: None,
             max_read_depth: 500,
+            downsample_strategy: DownsampleStrategy::Rows,
+            seed: 42,
+            display_mode: DisplayMode::Expanded,
+            threads: 1,
             data_format: DataFormat::Json,
             max_width: Some(1024),
             spec_output: None,
@@ -524,10 +978,30 @@ mod tests {
             highlight_data_output: None,
             output: None,
             html: false,
+            svg: false,
+            png: false,
             aux_tag: None,
             no_embed_js: false,
             mismatch_display_min_percent: 1.0,
+            min_base_quality: 0,
             clamp_reads: false,
+            show_soft_clips: false,
+            sashimi: false,
+            split_partners: false,
+            view_as_pairs: false,
+            coverage_by_strand: false,
+            dedup_mate_overlap: false,
+            regions_file: None,
+            each_vcf_record: false,
+            pass_only: false,
+            info_filter: None,
+            group_by: None,
+            color_by: None,
+            sort_by: None,
+            min_mapq: 0,
+            include_flags: 0,
+            exclude_flags: 0,
+            aux_filter: None,
         }
     }
 
@@ -555,6 +1029,127 @@ mod tests {
         assert_eq!((around.start, around.end), (0, 123));
     }
 
+    #[test]
+    fn test_preprocess_without_reference() {
+        let mut opt = Alignoth {
+            bam_path: vec![PathBuf::from("tests/sample_1/reads.bam")],
+            reference: None,
+            region: Some(Region::from_str("chr1:110-140").unwrap()),
+            ..base_alignoth()
+        };
+        opt.preprocess().unwrap();
+        // The target length is taken from the BAM header instead.
+        let region = opt.region.unwrap();
+        assert_eq!((region.start, region.end), (109, 140));
+    }
+
+    #[test]
+    fn test_named_regions_from_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "# candidate loci").unwrap();
+        writeln!(file, "chr1\t10\t20\tfirst").unwrap();
+        writeln!(file, "chr1\t30\t40").unwrap();
+        writeln!(file, "chr1:101-200 second").unwrap();
+        writeln!(file, "chr2:1-50").unwrap();
+        let regions = NamedRegion::from_file(file.path()).unwrap();
+        let expected = [
+            ("first", "chr1", 10, 20),
+            ("chr1:31-40", "chr1", 30, 40),
+            ("second", "chr1", 100, 200),
+            ("chr2:1-50", "chr2", 0, 50),
+        ];
+        assert_eq!(regions.len(), expected.len());
+        for (named, (name, target, start, end)) in regions.iter().zip(expected) {
+            assert_eq!(named.name, name);
+            assert_eq!(
+                named.region,
+                Region {
+                    target: target.to_string(),
+                    start,
+                    end
+                }
+            );
+        }
+        assert_eq!(regions[1].file_stem(), "chr1_31-40");
+    }
+
+    #[test]
+    fn test_deduplicate() {
+        let names = ["a_b", "a b", "a_b_2", "A_B", "c"].map(|name| {
+            NamedRegion {
+                name: name.to_string(),
+                region: Region::from_str("chr1:1-10").unwrap(),
+            }
+            .file_stem()
+        });
+        assert_eq!(
+            deduplicate(names),
+            vec!["a_b", "a_b_2", "a_b_2_2", "A_B_3", "c"]
+        );
+    }
+
+    #[test]
+    fn test_named_regions_from_vcf() {
+        let mut vcf = tempfile::Builder::new().suffix(".vcf").tempfile().unwrap();
+        write!(
+            vcf,
+            "##fileformat=VCFv4.2\n\
+             ##contig=<ID=1,length=2000>\n\
+             ##FILTER=<ID=PASS,Description=\"All filters passed\">\n\
+             ##FILTER=<ID=LowQual,Description=\"Low quality\">\n\
+             ##INFO=<ID=DP,Number=1,Type=Integer,Description=\"Depth\">\n\
+             #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n\
+             1\t257\t.\tA\tG\t.\tPASS\tDP=30\n\
+             1\t700\t.\tC\tT,G\t.\tLowQual\tDP=40\n\
+             1\t900\t.\tCT\tC\t.\tPASS\tDP=5\n\
+             1\t900\t.\tCT\tC\t.\tPASS\tDP=5\n"
+        )
+        .unwrap();
+        let names = |pass_only: bool, info_filter: Option<&str>| {
+            let expression = info_filter.map(|e| Expression::from_str(e).unwrap());
+            NamedRegion::from_vcf(vcf.path(), pass_only, expression.as_ref())
+                .unwrap()
+                .into_iter()
+                .map(|named| named.name)
+                .collect::<Vec<_>>()
+        };
+        // The duplicate record gets a name of its own.
+        assert_eq!(
+            names(false, None),
+            vec!["1_257_A_G", "1_700_C_T,G", "1_900_CT_C", "1_900_CT_C_2"]
+        );
+        assert_eq!(
+            names(true, None),
+            vec!["1_257_A_G", "1_900_CT_C", "1_900_CT_C_2"]
+        );
+        assert_eq!(
+            names(false, Some("DP>=30")),
+            vec!["1_257_A_G", "1_700_C_T,G"]
+        );
+        assert_eq!(names(true, Some("DP>=30")), vec!["1_257_A_G"]);
+
+        let regions = NamedRegion::from_vcf(vcf.path(), false, None).unwrap();
+        assert_eq!(
+            (regions[2].region.start, regions[2].region.end),
+            (399, 1401)
+        );
+    }
+
+    #[test]
+    fn test_batch_regions_are_clamped() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "chr1:100-200").unwrap();
+        let opt = Alignoth {
+            bam_path: vec![PathBuf::from("tests/sample_1/reads.bam")],
+            reference: Some(PathBuf::from("tests/sample_1/reference.fa")),
+            region: None,
+            regions_file: Some(file.path().to_path_buf()),
+            ..base_alignoth()
+        };
+        let regions = opt.batch_regions().unwrap();
+        assert_eq!((regions[0].region.start, regions[0].region.end), (99, 123));
+    }
+
     #[test]
     fn test_to_command_minimal() {
         assert_eq!(
@@ -571,12 +1166,15 @@ mod tests {
             bed: Some(PathBuf::from("regions.bed")),
             aux_tag: Some(vec!["HP".to_string(), "PS".to_string()]),
             max_read_depth: 200,
+            min_mapq: 20,
+            exclude_flags: 1024,
+            aux_filter: Some(Expression::from_str("NM<5").unwrap()),
             html: true,
             ..base_alignoth()
         };
         assert_eq!(
             opt.to_command(),
-            "alignoth -b sample.bam -r ref.fa -g chr1:1000-2000 -h var:1200 -v variants.vcf.gz --bed regions.bed -x HP -x PS -d 200 --html"
+            "alignoth -b sample.bam -r ref.fa -g chr1:1000-2000 -h var:1200 -v variants.vcf.gz --bed regions.bed -x HP -x PS -d 200 --min-mapq 20 --exclude-flags 1024 --aux-filter 'NM<5' --html"
         );
     }
 
@@ -0,0 +1,176 @@
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// A conjunction of simple comparisons on named values, e.g. `DP>10 && AF<=0.5 && DB`.
+///
+/// Values are compared numerically if both sides parse as numbers and as strings otherwise. A
+/// bare key without an operator only checks that the value is present.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression(Vec<Comparison>);
+
+#[derive(Debug, Clone, PartialEq)]
+struct Comparison {
+    key: String,
+    condition: Option<(Operator, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Operator {
+    /// All operators, ordered so that two-character operators are matched before their prefixes.
+    const ALL: [(&'static str, Operator); 6] = [
+        ("==", Operator::Eq),
+        ("!=", Operator::Ne),
+        ("<=", Operator::Le),
+        (">=", Operator::Ge),
+        ("<", Operator::Lt),
+        (">", Operator::Gt),
+    ];
+
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Operator::Eq => ordering == Ordering::Equal,
+            Operator::Ne => ordering != Ordering::Equal,
+            Operator::Lt => ordering == Ordering::Less,
+            Operator::Le => ordering != Ordering::Greater,
+            Operator::Gt => ordering == Ordering::Greater,
+            Operator::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+impl FromStr for Comparison {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let operator = Operator::ALL
+            .iter()
+            .filter_map(|(symbol, operator)| s.find(symbol).map(|i| (i, *symbol, *operator)))
+            .min_by_key(|(i, symbol, _)| (*i, std::cmp::Reverse(symbol.len())));
+        let comparison = match operator {
+            Some((i, symbol, operator)) => Comparison {
+                key: s[..i].trim().to_string(),
+                condition: Some((operator, s[i + symbol.len()..].trim().to_string())),
+            },
+            None => Comparison {
+                key: s.to_string(),
+                condition: None,
+            },
+        };
+        if comparison.key.is_empty()
+            || comparison
+                .condition
+                .as_ref()
+                .is_some_and(|(_, value)| value.is_empty())
+        {
+            return Err(anyhow!("Invalid comparison '{s}' in filter expression"));
+        }
+        Ok(comparison)
+    }
+}
+
+impl FromStr for Expression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Expression(
+            s.split("&&")
+                .map(Comparison::from_str)
+                .collect::<Result<_>>()?,
+        ))
+    }
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let comparisons = self
+            .0
+            .iter()
+            .map(|comparison| match &comparison.condition {
+                Some((operator, value)) => {
+                    let symbol = Operator::ALL.iter().find(|(_, o)| o == operator).unwrap().0;
+                    format!("{}{symbol}{value}", comparison.key)
+                }
+                None => comparison.key.clone(),
+            })
+            .collect::<Vec<_>>();
+        write!(f, "{}", comparisons.join(" && "))
+    }
+}
+
+impl Expression {
+    /// Returns whether all comparisons hold for the values returned by `lookup`. Comparisons on
+    /// missing values never hold.
+    pub(crate) fn matches<F>(&self, mut lookup: F) -> bool
+    where
+        F: FnMut(&str) -> Option<String>,
+    {
+        self.0.iter().all(|comparison| {
+            let Some(actual) = lookup(&comparison.key) else {
+                return false;
+            };
+            match &comparison.condition {
+                Some((operator, expected)) => {
+                    let ordering = match (actual.parse::<f64>(), expected.parse::<f64>()) {
+                        (Ok(actual), Ok(expected)) => actual.partial_cmp(&expected),
+                        _ => Some(actual.as_str().cmp(expected.as_str())),
+                    };
+                    ordering.is_some_and(|ordering| operator.holds(ordering))
+                }
+                None => true,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn lookup<'a>(values: &'a HashMap<&str, &str>) -> impl Fn(&str) -> Option<String> + 'a {
+        |key| values.get(key).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn test_expression_matches() {
+        let values = HashMap::from([("NM", "3"), ("HP", "1"), ("RG", "tumor")]);
+        let matches = |expression: &str| {
+            Expression::from_str(expression)
+                .unwrap()
+                .matches(lookup(&values))
+        };
+        assert!(matches("NM<5 && HP==1"));
+        assert!(matches("NM <= 3"));
+        assert!(!matches("NM<3"));
+        assert!(matches("RG==tumor && RG!=normal"));
+        assert!(matches("HP"));
+        assert!(!matches("PS"));
+        assert!(!matches("PS!=1"));
+        assert!(matches("NM>=3.0"));
+    }
+
+    #[test]
+    fn test_expression_rejects_incomplete_comparisons() {
+        assert!(Expression::from_str("NM<").is_err());
+        assert!(Expression::from_str("==1").is_err());
+        assert!(Expression::from_str("NM<5 && ").is_err());
+    }
+
+    #[test]
+    fn test_expression_round_trip() {
+        let expression = Expression::from_str("NM < 5&&HP==1 && DB").unwrap();
+        assert_eq!(expression.to_string(), "NM<5 && HP==1 && DB");
+    }
+}
@@ -0,0 +1,113 @@
+use crate::expression::Expression;
+use crate::utils::aux_to_string;
+use anyhow::{bail, Result};
+use rust_htslib::bam;
+
+/// SAM flag names as used by `samtools flags`, in the order of their bits.
+const FLAG_NAMES: [&str; 12] = [
+    "PAIRED",
+    "PROPER_PAIR",
+    "UNMAP",
+    "MUNMAP",
+    "REVERSE",
+    "MREVERSE",
+    "READ1",
+    "READ2",
+    "SECONDARY",
+    "QCFAIL",
+    "DUP",
+    "SUPPLEMENTARY",
+];
+
+/// Parses SAM flags given either as a decimal or hexadecimal (`0x`) number or as a comma-separated
+/// list of flag names like `DUP,SECONDARY`, following samtools.
+pub(crate) fn parse_flags(s: &str) -> Result<u16> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        return Ok(u16::from_str_radix(hex, 16)?);
+    }
+    if let Ok(flags) = s.parse() {
+        return Ok(flags);
+    }
+    s.split(',').try_fold(0, |flags, name| {
+        match FLAG_NAMES
+            .iter()
+            .position(|flag| flag.eq_ignore_ascii_case(name.trim()))
+        {
+            Some(bit) => Ok(flags | 1 << bit),
+            None => bail!(
+                "Unknown SAM flag '{name}'. Expected a number or one of {}.",
+                FLAG_NAMES.join(", ")
+            ),
+        }
+    })
+}
+
+/// Decides which records are plotted, based on their mapping quality, flags and aux tags.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReadFilter {
+    /// Records with a lower mapping quality are skipped.
+    pub min_mapq: u8,
+    /// Only records with all of these flags set are kept (samtools `-f`).
+    pub include_flags: u16,
+    /// Records with any of these flags set are skipped (samtools `-F`).
+    pub exclude_flags: u16,
+    /// Only records whose aux tags match the expression are kept, e.g. `NM<5 && HP==1`.
+    pub aux_filter: Option<Expression>,
+}
+
+impl ReadFilter {
+    /// Returns whether the given record passes the filter.
+    pub(crate) fn keep(&self, record: &bam::Record) -> bool {
+        let flags = record.flags();
+        record.mapq() >= self.min_mapq
+            && flags & self.include_flags == self.include_flags
+            && flags & self.exclude_flags == 0
+            && self.aux_filter.as_ref().is_none_or(|expression| {
+                expression.matches(|tag| record.aux(tag.as_bytes()).ok().map(aux_to_string))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::Aux;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_parse_flags() {
+        assert_eq!(parse_flags("1024").unwrap(), 1024);
+        assert_eq!(parse_flags("0x900").unwrap(), 0x900);
+        assert_eq!(parse_flags("DUP,secondary").unwrap(), 1024 | 256);
+        assert!(parse_flags("DUPLICATE").is_err());
+    }
+
+    #[test]
+    fn test_read_filter() {
+        let mut record = bam::Record::new();
+        record.set_mapq(30);
+        record.set_flags(0x1 | 0x400);
+        record.push_aux(b"NM", Aux::I32(2)).unwrap();
+        record.push_aux(b"HP", Aux::U8(1)).unwrap();
+
+        assert!(ReadFilter::default().keep(&record));
+        let filter = |min_mapq, include_flags, exclude_flags, aux_filter: Option<&str>| {
+            ReadFilter {
+                min_mapq,
+                include_flags,
+                exclude_flags,
+                aux_filter: aux_filter.map(|e| Expression::from_str(e).unwrap()),
+            }
+            .keep(&record)
+        };
+        assert!(filter(30, 0, 0, None));
+        assert!(!filter(31, 0, 0, None));
+        assert!(filter(0, 0x1, 0, None));
+        assert!(!filter(0, 0x1 | 0x2, 0, None));
+        assert!(!filter(0, 0, 0x400, None));
+        assert!(filter(0, 0, 0x100, None));
+        assert!(filter(0, 0, 0, Some("NM<5 && HP==1")));
+        assert!(!filter(0, 0, 0, Some("NM<2")));
+        assert!(!filter(0, 0, 0, Some("PS==1")));
+    }
+}
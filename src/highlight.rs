@@ -1,8 +1,9 @@
 use crate::cli::Interval;
 use crate::cli::Region;
+use crate::utils::open_vcf;
 use anyhow::Result;
 use bio::io::bed;
-use rust_htslib::bcf::{IndexedReader, Read};
+use rust_htslib::bcf::Read;
 use std::path::PathBuf;
 
 pub(crate) trait Highlight {
@@ -22,7 +23,7 @@ impl VcfHighlight {
 
 impl Highlight for VcfHighlight {
     fn intervals(&self, region: &Region) -> Result<Vec<Interval>> {
-        let mut reader = IndexedReader::from_path(&self.path)?;
+        let mut reader = open_vcf(&self.path)?;
         let header = reader.header().clone();
         let rid = header.name2rid(region.target.as_bytes())?;
         reader.fetch(rid, region.start as u64, Some(region.end as u64))?;
@@ -0,0 +1,30 @@
+//! Creates alignment plots from BAM files as [vega-lite](https://vega.github.io/vega-lite/)
+//! specs, standalone HTML pages or static images.
+//!
+//! Plots are configured via the [`PlotBuilder`], see its documentation for an example. The
+//! `alignoth` command line interface is a thin layer on top of it.
+
+mod app;
+mod builder;
+// The command line interface is only public for the `alignoth` binary and not part of the API.
+#[doc(hidden)]
+pub mod cli;
+mod expression;
+mod filter;
+mod highlight;
+mod plot;
+mod render;
+mod utils;
+#[doc(hidden)]
+pub mod wizard;
+
+#[doc(hidden)]
+pub use app::run;
+pub use builder::{Plot, PlotBuilder, Plotter};
+pub use cli::{Interval, Region};
+pub use expression::Expression;
+pub use filter::ReadFilter;
+pub use plot::{
+    Coverage, DisplayMode, DownsampleStrategy, EncodedRead, Junction, ModificationFrequency,
+    ReadAttribute, Reference, SortBy, StrandBias,
+};
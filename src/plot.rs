@@ -1,85 +1,280 @@
-use crate::cli;
-use crate::cli::Region;
-use crate::utils::{aux_to_string, get_fasta_length};
-use anyhow::{Context, Result};
+use crate::cli::{Interval, Region};
+use crate::filter::ReadFilter;
+use crate::utils::aux_to_string;
+use anyhow::{anyhow, bail, Context, Result};
 use itertools::Itertools;
 use log::warn;
-use rand::prelude::IteratorRandom;
+use rand::prelude::SliceRandom;
 use rand::rngs::StdRng;
-use rand::SeedableRng;
+use rand::{RngExt, SeedableRng};
 use rust_htslib::bam;
 use rust_htslib::bam::ext::BamRecordExtensions;
-use rust_htslib::bam::record::{Cigar, CigarString, CigarStringView};
+use rust_htslib::bam::record::{Aux, Cigar, CigarString, CigarStringView};
 use rust_htslib::bam::FetchDefinition::Region as FetchRegion;
 use rust_htslib::bam::Read as HtslibRead;
 use rust_htslib::faidx;
 use serde::{Serialize, Serializer};
-use std::cmp::{max, min};
-use std::collections::{HashMap, HashSet};
+use std::cmp::{max, min, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fmt;
 use std::fmt::Display;
-use std::path::Path;
+use std::ops::{Add, Range, Sub};
 use std::str::FromStr;
+use std::{mem, panic, thread};
 
-/// Generates the plot data for a given region of a bam file
-#[allow(clippy::too_many_arguments)]
-pub(crate) fn create_plot_data<P: AsRef<Path> + std::fmt::Debug>(
-    bam_path: P,
-    ref_path: P,
+/// The data shown in the plot of a region of a bam file.
+#[derive(Debug)]
+pub(crate) struct PlotData {
+    pub(crate) reads: Vec<EncodedRead>,
+    pub(crate) reference: Reference,
+    /// The number of reads in the region that passed the filter.
+    pub(crate) total_reads: usize,
+    pub(crate) coverage: Coverage,
+    pub(crate) junctions: Vec<Junction>,
+    /// The number of reads left after subsampling.
+    pub(crate) retained_reads: usize,
+    /// The number of reads removed by the filter.
+    pub(crate) filtered_reads: usize,
+    pub(crate) modifications: Vec<ModificationFrequency>,
+}
+
+/// Configures which reads [`create_plot_data`] shows and how.
+#[derive(Debug, Clone)]
+pub(crate) struct PlotDataOptions {
+    pub(crate) max_read_depth: usize,
+    pub(crate) downsampling: Downsampling,
+    pub(crate) display_mode: DisplayMode,
+    pub(crate) aux_tags: Option<Vec<String>>,
+    pub(crate) mismatch_display_min_percent: f64,
+    pub(crate) clamp_reads: bool,
+    pub(crate) show_soft_clips: bool,
+    pub(crate) group_by: Option<ReadAttribute>,
+    pub(crate) color_by: Option<ReadAttribute>,
+    pub(crate) sort_by: Option<SortBy>,
+    pub(crate) min_base_quality: u8,
+    pub(crate) filter: ReadFilter,
+    pub(crate) view_as_pairs: bool,
+    pub(crate) dedup_mate_overlap: bool,
+    pub(crate) threads: usize,
+}
+
+impl Default for PlotDataOptions {
+    fn default() -> Self {
+        PlotDataOptions {
+            max_read_depth: 500,
+            downsampling: Downsampling::default(),
+            display_mode: DisplayMode::default(),
+            aux_tags: None,
+            mismatch_display_min_percent: 1.0,
+            clamp_reads: false,
+            show_soft_clips: false,
+            group_by: None,
+            color_by: None,
+            sort_by: None,
+            min_base_quality: 0,
+            filter: ReadFilter::default(),
+            view_as_pairs: false,
+            dedup_mate_overlap: false,
+            threads: 1,
+        }
+    }
+}
+
+/// Generates the plot data for a given region of a bam file. The readers are only used for
+/// fetching, so they can be reused when plotting several regions. Without a fasta, the reference
+/// is reconstructed from the MD tags of the reads. If `view_as_pairs` is set, reads are colored by
+/// the class of their pair (see [`classify_pairs`]) instead of by `color_by`. If
+/// `dedup_mate_overlap` is set, positions covered by both mates of a pair are counted once in the
+/// coverage. Reads are sorted by `sort_by` before they are packed into rows as dense as given by
+/// `display_mode`, and reads exceeding `max_read_depth` rows are subsampled as given by
+/// `downsampling`. The records of long regions are fetched in chunks, which are then converted
+/// into reads by up to `threads` threads concurrently, while fetching and decoding the records and
+/// counting the coverage remain sequential. The coverage is counted from all reads as they are
+/// streamed, while only the reads that fit into `max_read_depth` rows are kept (see
+/// [`ReadReservoir`]), so that memory scales with the plot rather than the depth of the region.
+pub(crate) fn create_plot_data(
+    bam: &mut bam::IndexedReader,
+    fasta: Option<&faidx::Reader>,
     region: &Region,
-    max_read_depth: usize,
-    aux_tags: Option<Vec<String>>,
-    mismatch_display_min_percent: f64,
-    clamp_reads: bool,
+    options: &PlotDataOptions,
     sample: String,
-) -> Result<(Vec<EncodedRead>, Reference, usize, Coverage, usize)> {
-    let mut bam = bam::IndexedReader::from_path(&bam_path)?;
-    let tid = bam
-        .header()
-        .tid(region.target.as_bytes())
-        .context(format!(
-            "bam header does not contain given region target {}",
-            region.target
-        ))
-        .unwrap() as i32;
+) -> Result<PlotData> {
+    let tid = bam.header().tid(region.target.as_bytes()).context(format!(
+        "bam header does not contain given region target {}",
+        region.target
+    ))? as i32;
+    let target_length = match fasta {
+        Some(fasta) => fasta.fetch_seq_len(&region.target) as i64,
+        None => bam.header().target_len(tid as u32).unwrap_or_default() as i64,
+    };
+    let header = bam.header().clone();
+    let color_by = if options.view_as_pairs {
+        Some(&ReadAttribute::PairOrientation)
+    } else {
+        options.color_by.as_ref()
+    };
+    let mut reference_window = fasta
+        .map(|fasta| ReferenceWindow::new(fasta, region, target_length))
+        .transpose()?;
     bam.fetch(FetchRegion(tid, region.start, region.end))?;
-    let mut data = bam
-        .records()
-        .filter_map(|r| r.ok())
-        .filter_map(|r| {
-            Read::from_record(r, &ref_path, &aux_tags, region, clamp_reads)
-                .context(format!(
-                    "bam file does not contain given region target {}",
-                    region.target
-                ))
-                .unwrap()
-        })
-        .collect_vec();
-    let coverage =
-        Coverage::from_reads(&data, region, mismatch_display_min_percent, sample.clone());
-    let total_read_count = data.len();
-    data.order(max_read_depth)?;
+    let mut filtered_reads = 0;
+    let mut consensus = ConsensusReference::new(region);
+    let to_read = |r: bam::Record, ref_seq: Vec<char>| -> Result<Option<Read>> {
+        let mate_target = (r.is_paired() && r.mtid() >= 0 && r.mtid() != r.tid())
+            .then(|| String::from_utf8_lossy(header.tid2name(r.mtid() as u32)).into_owned());
+        let aligned_qualities = options
+            .dedup_mate_overlap
+            .then(|| mate_overlap_qualities(&r))
+            .flatten();
+        let sort_key = options
+            .sort_by
+            .as_ref()
+            .and_then(|sort_by| sort_by.key(&r, &ref_seq, &region.target));
+        let position = r.pos();
+        let read = Read::from_record(
+            r,
+            ref_seq,
+            target_length,
+            &options.aux_tags,
+            region,
+            options.clamp_reads,
+            options.show_soft_clips,
+            options.group_by.as_ref(),
+            color_by,
+        )
+        .with_context(|| {
+            format!(
+                "Failed to decode the record at {}:{}",
+                region.target,
+                position + 1
+            )
+        })?;
+        Ok(read.map(|read| Read {
+            mate_target,
+            aligned_qualities,
+            sort_key,
+            ..read
+        }))
+    };
+    // Records are collected in chunks of the region, up to one per thread, which are then
+    // converted into reads concurrently. The reads are streamed into the coverage, junction and
+    // modification counters and into a reservoir keeping no more reads than can be shown, so that
+    // deep regions do not have to be held in memory at once.
+    let mut coverage = CoverageCounter::new(region, options.min_base_quality);
+    let mut junctions = JunctionCounter::default();
+    let mut modifications = ModificationCounter::default();
+    let mut reservoir = ReadReservoir::new(
+        options.max_read_depth,
+        &options.downsampling,
+        options.sort_by.as_ref(),
+    );
+    let mut total_reads = 0;
+    let mut consume = |reads: Vec<Read>| {
+        for read in reads {
+            total_reads += 1;
+            coverage.add(&read);
+            junctions.add(&read, region);
+            modifications.add(&read, region);
+            reservoir.add(read);
+        }
+    };
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::new();
+    let mut chunk_end = region.start + REGION_CHUNK_LENGTH;
+    for r in bam.records().filter_map(|r| r.ok()) {
+        if !options.filter.keep(&r) {
+            filtered_reads += 1;
+            continue;
+        }
+        let ref_seq = match reference_window.as_mut() {
+            Some(window) => window.get(r.pos(), r.reference_end())?.to_vec(),
+            None => {
+                let ref_seq = reference_from_md(&r)?;
+                consensus.add(r.pos(), &ref_seq);
+                ref_seq
+            }
+        };
+        if r.pos() >= chunk_end || chunk.len() >= MAX_CHUNK_RECORDS {
+            chunk_end = r.pos() + REGION_CHUNK_LENGTH;
+            if !chunk.is_empty() {
+                chunks.push(mem::take(&mut chunk));
+            }
+            if chunks.len() >= options.threads {
+                consume(convert_chunks(mem::take(&mut chunks), &to_read)?);
+            }
+        }
+        chunk.push((r, ref_seq));
+    }
+    chunks.push(chunk);
+    consume(convert_chunks(chunks, &to_read)?);
+    let mut data = reservoir.finish();
+    if options.view_as_pairs {
+        classify_pairs(&mut data);
+    }
+    let coverage = coverage.finish(options.mismatch_display_min_percent, sample.clone());
+    let junctions = junctions.finish(sample.clone());
+    let modifications = modifications.finish(sample.clone());
+    data.order(
+        options.max_read_depth,
+        &options.downsampling,
+        options.display_mode,
+    )?;
     let retained_reads = data.len();
-    let reference_data = Reference {
+    let reference = Reference {
         start: region.start,
-        reference: read_fasta(ref_path, region)?.iter().collect(),
+        reference: match reference_window.as_mut() {
+            Some(window) => window.get(region.start, region.end)?.iter().collect(),
+            None => consensus.bases(),
+        },
     };
-    Ok((
-        vec![EncodedRead::from_reads(data, sample)],
-        reference_data,
-        total_read_count,
+    Ok(PlotData {
+        reads: vec![EncodedRead::from_reads(data, sample)],
+        reference,
+        total_reads,
         coverage,
+        junctions,
         retained_reads,
-    ))
+        filtered_reads,
+        modifications,
+    })
+}
+
+/// Returns the position and aligned base qualities used to deduplicate the overlap of the given
+/// record with its mate. Only primary alignments of paired reads are matched with their mate, as
+/// secondary and supplementary alignments share the name of the pair.
+fn mate_overlap_qualities(record: &bam::Record) -> Option<(i64, Vec<u8>)> {
+    (record.is_paired() && record.flags() & 0x900 == 0)
+        .then(|| (record.pos(), aligned_qualities(record)))
+}
+
+/// Returns the base qualities of the given record by reference position, from its first to its
+/// last aligned base. Deleted and skipped positions get a quality of 0.
+fn aligned_qualities(record: &bam::Record) -> Vec<u8> {
+    let qual = record.qual();
+    let mut qualities = Vec::new();
+    let mut read_index = 0;
+    for c in &record.cigar() {
+        match c {
+            Cigar::Match(length) | Cigar::Equal(length) | Cigar::Diff(length) => {
+                let range = read_index..read_index + *length as usize;
+                qualities.extend(range.map(|i| qual.get(i).copied().unwrap_or(0)));
+                read_index += *length as usize;
+            }
+            Cigar::Del(length) | Cigar::RefSkip(length) => {
+                qualities.resize(qualities.len() + *length as usize, 0)
+            }
+            Cigar::Ins(length) | Cigar::SoftClip(length) => read_index += *length as usize,
+            Cigar::HardClip(_) | Cigar::Pad(_) => {}
+        }
+    }
+    qualities
 }
 
 /// Reads the given region from the given fasta file and returns it as a vec of the bases as chars
-fn read_fasta<P: AsRef<Path> + std::fmt::Debug>(path: P, region: &Region) -> Result<Vec<char>> {
+fn read_fasta(reader: &faidx::Reader, region: &Region) -> Result<Vec<char>> {
     if region.end <= region.start {
         return Ok(Vec::new());
     }
-    let reader =
-        faidx::Reader::from_path(&path).context("error reading index file of input FASTA")?;
     let seq = reader.fetch_seq(
         &region.target,
         region.start as usize,
@@ -88,29 +283,293 @@ fn read_fasta<P: AsRef<Path> + std::fmt::Debug>(path: P, region: &Region) -> Res
     Ok(seq.iter().map(|u| char::from(*u)).collect_vec())
 }
 
+/// Reconstructs the reference bases covered by the aligned part of the given record, i.e.
+/// `record.pos()..record.reference_end()`, from its sequence and MD tag. Bases of skipped regions
+/// are not known and reported as `N`.
+fn reference_from_md(record: &bam::Record) -> Result<Vec<char>> {
+    if record.reference_end() <= record.pos() {
+        return Ok(Vec::new());
+    }
+    let name = String::from_utf8_lossy(record.qname());
+    let md = match record.aux(b"MD") {
+        Ok(Aux::String(md)) => md,
+        _ => bail!(
+            "Read '{name}' has no MD tag. Please provide a reference with -r to plot reads without MD tags."
+        ),
+    };
+    let read_seq = record.seq().as_bytes();
+    let read_base = |index: usize| read_seq.get(index).map_or('N', |base| char::from(*base));
+
+    // Start off with the read bases and fill in mismatches and deleted bases from the MD tag,
+    // which describes the positions of the reference covered by matches and deletions.
+    let mut ref_seq = Vec::new();
+    let mut md_positions = Vec::new();
+    let mut read_index = 0;
+    for c in &record.cigar() {
+        match c {
+            Cigar::Match(length) | Cigar::Equal(length) | Cigar::Diff(length) => {
+                for _ in 0..*length {
+                    md_positions.push(ref_seq.len());
+                    ref_seq.push(read_base(read_index));
+                    read_index += 1;
+                }
+            }
+            Cigar::Del(length) => {
+                for _ in 0..*length {
+                    md_positions.push(ref_seq.len());
+                    ref_seq.push('N');
+                }
+            }
+            Cigar::RefSkip(length) => ref_seq.resize(ref_seq.len() + *length as usize, 'N'),
+            Cigar::Ins(length) | Cigar::SoftClip(length) => read_index += *length as usize,
+            Cigar::HardClip(_) | Cigar::Pad(_) => {}
+        }
+    }
+
+    let invalid =
+        || anyhow!("Read '{name}' has an MD tag ('{md}') that does not match its CIGAR string.");
+    let mut md_positions = md_positions.into_iter();
+    let mut md_chars = md.chars().peekable();
+    while let Some(c) = md_chars.next() {
+        if let Some(digit) = c.to_digit(10) {
+            let mut matches = digit as usize;
+            while let Some(digit) = md_chars.peek().and_then(|c| c.to_digit(10)) {
+                matches = matches * 10 + digit as usize;
+                md_chars.next();
+            }
+            for _ in 0..matches {
+                md_positions.next().ok_or_else(invalid)?;
+            }
+        } else if c == '^' {
+            while let Some(base) = md_chars.next_if(char::is_ascii_alphabetic) {
+                ref_seq[md_positions.next().ok_or_else(invalid)?] = base.to_ascii_uppercase();
+            }
+        } else {
+            ref_seq[md_positions.next().ok_or_else(invalid)?] = c.to_ascii_uppercase();
+        }
+    }
+    Ok(ref_seq)
+}
+
+/// The length of the chunks of a region whose records are converted into reads concurrently.
+const REGION_CHUNK_LENGTH: i64 = 10_000;
+
+/// The maximum number of records of a chunk, so that deep regions are split into shorter chunks.
+const MAX_CHUNK_RECORDS: usize = 10_000;
+
+/// Converts the given chunks of records, together with their reference, into reads, each chunk in
+/// a thread of its own. The reads are returned in the order of the chunks.
+fn convert_chunks<F>(chunks: Vec<Vec<(bam::Record, Vec<char>)>>, to_read: &F) -> Result<Vec<Read>>
+where
+    F: Fn(bam::Record, Vec<char>) -> Result<Option<Read>> + Sync,
+{
+    let convert = |chunk: Vec<(bam::Record, Vec<char>)>| -> Result<Vec<Read>> {
+        chunk
+            .into_iter()
+            .filter_map(|(record, ref_seq)| to_read(record, ref_seq).transpose())
+            .collect()
+    };
+    let converted = if chunks.len() <= 1 {
+        chunks.into_iter().map(convert).collect_vec()
+    } else {
+        thread::scope(|scope| {
+            let handles = chunks
+                .into_iter()
+                .map(|chunk| scope.spawn(move || convert(chunk)))
+                .collect_vec();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| panic::resume_unwind(panic))
+                })
+                .collect_vec()
+        })
+    };
+    let mut reads = Vec::new();
+    for chunk in converted {
+        reads.extend(chunk?);
+    }
+    Ok(reads)
+}
+
+/// The number of bases the reference is fetched beyond the plotted region and beyond reads sticking
+/// out of the fetched window, so that the window rarely needs to be extended.
+const REFERENCE_PADDING: i64 = 1000;
+
+/// The reference around a plotted region, fetched once and shared by all reads instead of fetching
+/// the reference of every read separately. Reads sticking out of the window extend it.
+struct ReferenceWindow<'a> {
+    fasta: &'a faidx::Reader,
+    target: String,
+    target_length: i64,
+    start: i64,
+    seq: Vec<char>,
+    /// How often the reference has been fetched.
+    fetches: usize,
+}
+
+impl<'a> ReferenceWindow<'a> {
+    fn new(fasta: &'a faidx::Reader, region: &Region, target_length: i64) -> Result<Self> {
+        let mut window = ReferenceWindow {
+            fasta,
+            target: region.target.clone(),
+            target_length,
+            start: region.start,
+            seq: Vec::new(),
+            fetches: 0,
+        };
+        window.fetch(
+            region.start - REFERENCE_PADDING,
+            region.end + REFERENCE_PADDING,
+        )?;
+        Ok(window)
+    }
+
+    /// The 0-based position after the last fetched base.
+    fn end(&self) -> i64 {
+        self.start + self.seq.len() as i64
+    }
+
+    /// Fetches the reference between the given 0-based positions (end exclusive), clamped to the
+    /// bounds of the target.
+    fn fetch(&mut self, start: i64, end: i64) -> Result<()> {
+        let (start, end) = (max(start, 0), min(end, self.target_length));
+        self.seq = read_fasta(
+            self.fasta,
+            &Region {
+                target: self.target.clone(),
+                start,
+                end,
+            },
+        )?;
+        self.start = start;
+        self.fetches += 1;
+        Ok(())
+    }
+
+    /// Returns the reference between the given 0-based positions (end exclusive), clamped to the
+    /// bounds of the target. Extends the window if it does not contain them yet.
+    fn get(&mut self, start: i64, end: i64) -> Result<&[char]> {
+        let (start, end) = (max(start, 0), min(end, self.target_length));
+        if end <= start {
+            return Ok(&[]);
+        }
+        if start < self.start || end > self.end() {
+            self.fetch(
+                min(start, self.start) - REFERENCE_PADDING,
+                max(end, self.end()) + REFERENCE_PADDING,
+            )?;
+        }
+        let offset = |position: i64| (position - self.start) as usize;
+        Ok(self
+            .seq
+            .get(offset(start)..min(offset(end), self.seq.len()))
+            .unwrap_or_default())
+    }
+}
+
+/// Infers the reference of a region by majority vote over the reference bases of the reads
+/// overlapping it. Positions without any known base are reported as `N`.
+struct ConsensusReference {
+    start: i64,
+    counts: Vec<HashMap<char, usize>>,
+}
+
+impl ConsensusReference {
+    fn new(region: &Region) -> Self {
+        ConsensusReference {
+            start: region.start,
+            counts: vec![HashMap::new(); region.length().max(0) as usize],
+        }
+    }
+
+    /// Adds the given reference bases, starting at the given position.
+    fn add(&mut self, position: i64, bases: &[char]) {
+        for (offset, base) in bases.iter().enumerate() {
+            let index = position + offset as i64 - self.start;
+            if *base == 'N' || index < 0 || index >= self.counts.len() as i64 {
+                continue;
+            }
+            *self.counts[index as usize].entry(*base).or_default() += 1;
+        }
+    }
+
+    fn bases(&self) -> String {
+        self.counts
+            .iter()
+            .map(|counts| {
+                counts
+                    .iter()
+                    .sorted()
+                    .max_by_key(|(_, count)| **count)
+                    .map_or('N', |(base, _)| *base)
+            })
+            .collect()
+    }
+}
+
 /// A Read containing all relevant information for being plotted in a read plot
-#[derive(Serialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct Read {
-    name: String,
-    cigar: PlotCigar,
-    position: i64,
-    flags: u16,
-    mapq: u8,
-    row: Option<u32>,
+    pub(crate) name: String,
+    pub(crate) cigar: PlotCigar,
+    pub(crate) position: i64,
+    pub(crate) flags: u16,
+    pub(crate) mapq: u8,
+    pub(crate) row: Option<u32>,
     #[serde(skip)]
-    end_position: i64,
-    mpos: i64,
+    pub(crate) end_position: i64,
+    pub(crate) mpos: i64,
     aux: AuxRecord,
     raw_cigar: String,
+    pub(crate) group: Option<String>,
+    pub(crate) color: Option<String>,
+    pub(crate) supplementary: Vec<SupplementaryAlignment>,
+    /// The template length (`TLEN`) of paired reads, 0 otherwise.
+    pub(crate) tlen: i64,
+    /// The target id of the mate of paired reads, -1 otherwise.
+    pub(crate) mtid: i32,
+    /// The name of the target of the mate if it is aligned to another target than the read.
+    pub(crate) mate_target: Option<String>,
+    pub(crate) modifications: Vec<BaseModification>,
+    /// The base qualities of the aligned bases of paired reads, starting at the given reference
+    /// position, if overlapping mates are counted only once in the coverage. Deleted and skipped
+    /// positions have a quality of 0.
+    #[serde(skip)]
+    pub(crate) aligned_qualities: Option<(i64, Vec<u8>)>,
+    /// The key the read is sorted by before it is assigned a row, see [`SortBy::key`].
+    #[serde(skip)]
+    pub(crate) sort_key: Option<(i64, String)>,
 }
 
 impl Read {
     pub fn encode(&self) -> String {
         let aux_str = self.aux.to_string().replace(' ', "_");
         let row_str = self.row.map_or(".".to_string(), |r| r.to_string());
+        let optional = |value: &Option<String>| {
+            value
+                .as_ref()
+                .map_or(".".to_string(), |v| v.replace(' ', "_"))
+        };
+
+        let supplementary = if self.supplementary.is_empty() {
+            ".".to_string()
+        } else {
+            self.supplementary.iter().join(";")
+        };
+        let modifications = if self.modifications.is_empty() {
+            ".".to_string()
+        } else {
+            self.modifications
+                .iter()
+                .map(|m| format!("{}:{}:{}", m.position - self.position, m.code, m.likelihood))
+                .join(",")
+        };
 
         format!(
-            "{} {} {} {} {} {} {} {} {}",
+            "{} {} {} {} {} {} {} {} {} {} {} {} {} {} {} {}",
             aux_str,
             self.cigar,
             self.flags,
@@ -120,18 +579,95 @@ impl Read {
             self.position,
             row_str,
             self.raw_cigar,
+            optional(&self.group),
+            optional(&self.color),
+            supplementary,
+            self.tlen,
+            self.mtid,
+            optional(&self.mate_target),
+            modifications,
         )
     }
 }
 
+impl FromStr for Read {
+    type Err = anyhow::Error;
+
+    /// Parses a read from the whitespace-separated representation produced by [`Read::encode`].
+    fn from_str(s: &str) -> Result<Self> {
+        let fields = s.split(' ').collect_vec();
+        if fields.len() != 16 {
+            bail!(
+                "Expected 16 fields in encoded read but found {}: {s}",
+                fields.len()
+            );
+        }
+        let optional = |value: &str| (value != ".").then(|| value.replace('_', " "));
+        let cigar = PlotCigar::from_str(fields[1])?;
+        let position = fields[6].parse()?;
+        Ok(Read {
+            name: fields[5].to_string(),
+            end_position: position + cigar.reference_length(),
+            cigar,
+            position,
+            flags: fields[2].parse()?,
+            mapq: fields[3].parse()?,
+            row: match fields[7] {
+                "." => None,
+                row => Some(row.parse()?),
+            },
+            mpos: fields[4].parse()?,
+            aux: AuxRecord::decode(fields[0]),
+            raw_cigar: fields[8].to_string(),
+            group: optional(fields[9]),
+            color: optional(fields[10]),
+            supplementary: match fields[11] {
+                "." => Vec::new(),
+                segments => segments
+                    .split(';')
+                    .map(SupplementaryAlignment::from_str)
+                    .collect::<Result<_>>()?,
+            },
+            tlen: fields[12].parse()?,
+            mtid: fields[13].parse()?,
+            mate_target: optional(fields[14]),
+            modifications: match fields[15] {
+                "." => Vec::new(),
+                modifications => modifications
+                    .split(',')
+                    .map(|modification| {
+                        let (offset, code, likelihood) = modification
+                            .split(':')
+                            .collect_tuple()
+                            .context(format!("Invalid base modification: {modification}"))?;
+                        Ok(BaseModification {
+                            position: position + offset.parse::<i64>()?,
+                            code: code.to_string(),
+                            likelihood: likelihood.parse()?,
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+            },
+            aligned_qualities: None,
+            sort_key: None,
+        })
+    }
+}
+
 /// A compact string representation of multiple reads for embedding in Vega-Lite specifications.
 ///
 /// Each read is serialized using whitespace-separated fields:
-/// `aux cigar flags mapq mpos name position row raw_cigar`.
+/// `aux cigar flags mapq mpos name position row raw_cigar group color supplementary tlen mtid
+/// mate_target modifications`.
 ///
 /// - Fields within a read are separated by a single space (`' '`).
 /// - Multiple reads are concatenated using the section symbol delimiter (`§`).
-/// - Spaces within auxiliary tags are replaced with underscores (`_`) to preserve structure.
+/// - Spaces within auxiliary tags, groups and colors are replaced with underscores (`_`) to preserve structure.
+/// - Missing rows, groups, colors and mate targets are encoded as `.`, just like reads without
+///   supplementary alignments. Otherwise these are given in the format of the `SA` tag, separated
+///   by `;`.
+/// - Base modifications are given as `offset:code:likelihood`, separated by `,`, where `offset` is
+///   relative to the position of the read and `likelihood` is scaled to 0-255 like in the `ML` tag.
 ///
 /// This format avoids repetitive JSON keys and minimizes payload size,
 /// making it suitable for inline data embedding in visualization specs.
@@ -147,7 +683,7 @@ impl EncodedRead {
     ///
     /// # Example
     ///
-    /// ```
+    /// ```ignore
     /// let encoded = EncodedRead::from_reads(vec![read1, read2]);
     /// println!("{}", serde_json::to_string(&encoded).unwrap());
     /// ```
@@ -157,9 +693,21 @@ impl EncodedRead {
             values: reads.iter().map(|r| r.encode()).join("§"),
         }
     }
+
+    pub fn sample(&self) -> &str {
+        &self.sample
+    }
+
+    /// Decodes the reads again, e.g. for rendering them without vega-lite.
+    pub(crate) fn decode(&self) -> Result<Vec<Read>> {
+        if self.values.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.values.split('§').map(Read::from_str).collect()
+    }
 }
 
-#[derive(Debug, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct AuxRecord(HashMap<String, String>);
 
 impl AuxRecord {
@@ -179,6 +727,17 @@ impl AuxRecord {
         }
         AuxRecord(aux_values)
     }
+
+    /// Parses the underscore-escaped representation used within encoded reads.
+    fn decode(s: &str) -> Self {
+        AuxRecord(
+            s.replace('_', " ")
+                .split(", ")
+                .filter_map(|pair| pair.split_once(": "))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
 }
 
 impl Display for AuxRecord {
@@ -202,193 +761,1366 @@ impl Serialize for AuxRecord {
     }
 }
 
-/// A reference with all relevant information base for being plotted in a read plot
-#[derive(Serialize, Debug, Eq, PartialEq)]
-pub(crate) struct Reference {
-    start: i64,
-    reference: String,
-}
+/// Bases added to both sides of the locus the supplementary alignments of split reads map to.
+const PARTNER_PADDING: i64 = 100;
+/// The maximum span of supplementary alignments that are shown together as partner locus.
+const MAX_PARTNER_SPAN: i64 = 10_000;
 
-// A struct representing base coverage information, m represents a match to the reference
-#[derive(Serialize, Debug, Eq, PartialEq, Default, Clone, Copy)]
-pub(crate) struct BaseCoverage {
-    a: usize,
-    t: usize,
-    g: usize,
-    c: usize,
-    m: usize,
+/// Another segment of a chimeric read, as listed in its `SA` tag
+/// (`rname,pos,strand,CIGAR,mapQ,NM`).
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SupplementaryAlignment {
+    pub(crate) target: String,
+    /// The 0-based position of the first aligned base.
+    pub(crate) position: i64,
+    pub(crate) reverse: bool,
+    pub(crate) cigar: String,
+    pub(crate) mapq: u8,
+    pub(crate) nm: u32,
 }
 
-impl BaseCoverage {
-    /// Filters out low-frequency mismatches and merges them into the match count m.
-    pub fn filter_mismatches(&mut self, threshold: f64) {
-        let total = self.a + self.t + self.g + self.c + self.m;
-        if total == 0 {
-            return;
+impl SupplementaryAlignment {
+    /// Parses the `SA` tag of the given record, ignoring malformed entries.
+    fn from_record(record: &bam::Record) -> Vec<Self> {
+        match record.aux(b"SA") {
+            Ok(Aux::String(segments)) => segments
+                .split(';')
+                .filter(|segment| !segment.is_empty())
+                .filter_map(|segment| segment.parse().ok())
+                .collect(),
+            _ => Vec::new(),
         }
+    }
 
-        let mut removed = 0;
-        for b in [&mut self.a, &mut self.t, &mut self.g, &mut self.c] {
-            if (*b as f64 / total as f64 * 100.0) < threshold {
-                removed += *b;
-                *b = 0;
+    /// The 0-based position after the last aligned base.
+    pub(crate) fn end(&self) -> i64 {
+        let mut end = self.position;
+        let mut length = 0;
+        for c in self.cigar.chars() {
+            match c.to_digit(10) {
+                Some(digit) => length = length * 10 + i64::from(digit),
+                None => {
+                    if matches!(c, 'M' | 'D' | 'N' | '=' | 'X') {
+                        end += length;
+                    }
+                    length = 0;
+                }
             }
         }
-        self.m += removed;
+        end
     }
 }
 
-#[derive(Serialize, Debug, Eq, PartialEq, Default, Clone)]
-pub(crate) struct EncodedBaseCoverage(pub Vec<BaseCoverage>);
+impl FromStr for SupplementaryAlignment {
+    type Err = anyhow::Error;
 
-impl EncodedBaseCoverage {
-    /// Encode the match coverage as differences (Δ to previous position)
-    pub fn matches(&self) -> String {
-        let mut last = 0;
-        self.0
-            .iter()
-            .map(|bc| {
-                let diff = bc.m as isize - last;
-                last = bc.m as isize;
-                diff.to_string()
-            })
-            .collect::<Vec<_>>()
-            .join("|")
+    fn from_str(s: &str) -> Result<Self> {
+        let fields = s.split(',').collect_vec();
+        if fields.len() != 6 {
+            bail!(
+                "Expected 6 fields in SA tag entry but found {}: {s}",
+                fields.len()
+            );
+        }
+        Ok(SupplementaryAlignment {
+            target: fields[0].to_string(),
+            position: fields[1].parse::<i64>()? - 1,
+            reverse: match fields[2] {
+                "+" => false,
+                "-" => true,
+                strand => bail!("Invalid strand '{strand}' in SA tag entry: {s}"),
+            },
+            cigar: fields[3].to_string(),
+            mapq: fields[4].parse()?,
+            nm: fields[5].parse()?,
+        })
     }
+}
 
-    /// Helper for sparse encoding of a base track (A, T, G, C)
-    fn encode_sparse<F>(&self, f: F) -> String
-    where
-        F: Fn(&BaseCoverage) -> usize,
-    {
-        self.0
-            .iter()
-            .enumerate()
-            .filter_map(|(i, bc)| {
-                let cov = f(bc);
-                if cov > 0 {
-                    Some(format!("{}|{}", i + 1, cov))
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>()
-            .join("$")
+impl Display for SupplementaryAlignment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{},{}",
+            self.target,
+            self.position + 1,
+            if self.reverse { '-' } else { '+' },
+            self.cigar,
+            self.mapq,
+            self.nm
+        )
     }
+}
 
-    pub fn a(&self) -> String {
-        self.encode_sparse(|bc| bc.a)
-    }
+/// Returns the locus most supplementary alignments of the given reads map to outside of the
+/// given region, so that it can be shown next to it. Supplementary alignments further than
+/// [`MAX_PARTNER_SPAN`] apart are not merged into one locus.
+pub(crate) fn partner_region(reads: &[Read], region: &Region) -> Option<Region> {
+    let segments = reads
+        .iter()
+        .flat_map(|read| &read.supplementary)
+        .filter(|segment| {
+            segment.target != region.target
+                || segment.end() <= region.start
+                || segment.position >= region.end
+        })
+        .collect_vec();
+    let (target, _) = segments
+        .iter()
+        .map(|segment| &segment.target)
+        .counts()
+        .into_iter()
+        .max_by_key(|(target, count)| (*count, *target))?;
+    let positions = segments
+        .iter()
+        .filter(|segment| segment.target == *target)
+        .sorted_by_key(|segment| segment.position)
+        .collect_vec();
+    let anchor = positions[positions.len() / 2].position;
+    let locus = positions
+        .iter()
+        .filter(|segment| (segment.position - anchor).abs() <= MAX_PARTNER_SPAN / 2)
+        .collect_vec();
+    Some(Region {
+        target: target.clone(),
+        start: (locus.iter().map(|segment| segment.position).min()? - PARTNER_PADDING).max(0),
+        end: locus.iter().map(|segment| segment.end()).max()? + PARTNER_PADDING,
+    })
+}
 
-    pub fn t(&self) -> String {
-        self.encode_sparse(|bc| bc.t)
-    }
+/// Base modifications with at least this likelihood (as given in the `ML` tag, i.e. scaled to
+/// 0-255) count as modified in the modification frequency track.
+const MIN_MODIFIED_LIKELIHOOD: u8 = 128;
 
-    pub fn g(&self) -> String {
-        self.encode_sparse(|bc| bc.g)
-    }
+/// A base modification call of a read, as given by its `MM` and `ML` tags.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BaseModification {
+    /// The 0-based reference position of the modified base.
+    pub(crate) position: i64,
+    /// The modification code, e.g. `m` for 5mC, `h` for 5hmC or a ChEBI identifier.
+    pub(crate) code: String,
+    /// The likelihood of the modification, scaled to 0-255 like in the `ML` tag.
+    pub(crate) likelihood: u8,
+}
 
-    pub fn c(&self) -> String {
-        self.encode_sparse(|bc| bc.c)
+impl BaseModification {
+    /// Decodes the base modification calls from the `MM` and `ML` tags (or their `Mm` and `Ml`
+    /// predecessors) of the given record. Calls of bases that are not aligned to the reference,
+    /// i.e. inserted or soft clipped ones, are skipped. Without `ML` tag, all calls are taken as
+    /// certain.
+    fn from_record(record: &bam::Record) -> Result<Vec<Self>> {
+        let mm = match record.aux(b"MM").or_else(|_| record.aux(b"Mm")) {
+            Ok(Aux::String(mm)) => mm,
+            _ => return Ok(Vec::new()),
+        };
+        let mut likelihoods = match record.aux(b"ML").or_else(|_| record.aux(b"Ml")) {
+            Ok(Aux::ArrayU8(ml)) => ml.iter().collect_vec(),
+            _ => Vec::new(),
+        }
+        .into_iter();
+
+        // The reference position of each base of the stored sequence, if it is aligned.
+        let mut ref_positions = Vec::new();
+        let mut ref_pos = record.pos();
+        for c in &record.cigar() {
+            match c {
+                Cigar::Match(length) | Cigar::Equal(length) | Cigar::Diff(length) => {
+                    ref_positions.extend((ref_pos..ref_pos + i64::from(*length)).map(Some));
+                    ref_pos += i64::from(*length);
+                }
+                Cigar::Ins(length) | Cigar::SoftClip(length) => {
+                    ref_positions.resize(ref_positions.len() + *length as usize, None)
+                }
+                Cigar::Del(length) | Cigar::RefSkip(length) => ref_pos += i64::from(*length),
+                Cigar::HardClip(_) | Cigar::Pad(_) => {}
+            }
+        }
+
+        // `MM` counts bases in the orientation the read was sequenced in.
+        let mut seq = record.seq().as_bytes();
+        if record.is_reverse() {
+            seq = reverse_complement(&seq);
+        }
+        let stored_index = |index: usize| {
+            if record.is_reverse() {
+                seq.len() - 1 - index
+            } else {
+                index
+            }
+        };
+
+        let name = String::from_utf8_lossy(record.qname());
+        let invalid =
+            || anyhow!("Read '{name}' has an MM tag ('{mm}') that does not match its sequence.");
+        let mut modifications = Vec::new();
+        for entry in mm.split(';').filter(|entry| !entry.is_empty()) {
+            let mut fields = entry.split(',');
+            let header = fields.next().unwrap_or_default();
+            let mut chars = header.chars();
+            let (Some(base), Some(strand)) = (chars.next(), chars.next()) else {
+                return Err(invalid());
+            };
+            let codes = chars.as_str().trim_end_matches(['.', '?']);
+            let codes = if codes.chars().all(|c| c.is_ascii_digit()) {
+                vec![codes.to_string()]
+            } else {
+                codes.chars().map(String::from).collect_vec()
+            };
+            if codes.iter().any(String::is_empty) || !matches!(strand, '+' | '-') {
+                return Err(invalid());
+            }
+            // Modifications on the opposite strand are counted on the complementary base.
+            let base = match strand {
+                '-' => reverse_complement(&[base as u8])[0],
+                _ => base as u8,
+            };
+            let mut candidates = (0..seq.len()).filter(|i| base == b'N' || seq[*i] == base);
+            for skip in fields {
+                let index = candidates
+                    .nth(skip.parse().map_err(|_| invalid())?)
+                    .ok_or_else(invalid)?;
+                for code in &codes {
+                    let likelihood = likelihoods.next().unwrap_or(u8::MAX);
+                    if let Some(Some(position)) = ref_positions.get(stored_index(index)) {
+                        modifications.push(BaseModification {
+                            position: *position,
+                            code: code.clone(),
+                            likelihood,
+                        });
+                    }
+                }
+            }
+        }
+        modifications.sort_by_key(|modification| modification.position);
+        Ok(modifications)
     }
 }
 
-/// A coverage with all relevant information base for being plotted over a read plot
-/// Each value in coverage represents the number of reads covering that position.
-#[derive(Serialize, Debug, Eq, PartialEq)]
-pub(crate) struct Coverage {
-    sample: String,
-    start: i64,
-    #[serde(rename = "m")]
-    matches: String,
-    a: String,
-    t: String,
-    g: String,
-    c: String,
+/// Returns the reverse complement of the given bases.
+fn reverse_complement(bases: &[u8]) -> Vec<u8> {
+    bases
+        .iter()
+        .rev()
+        .map(|base| match base {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            other => *other,
+        })
+        .collect()
 }
 
-impl Coverage {
+/// A property of a read that reads can be grouped or colored by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadAttribute {
+    /// The strand the read is aligned to, `+` or `-`.
+    Strand,
+    /// The mapping quality of the read.
+    Mapq,
+    /// The relative orientation of the read and its mate, e.g. `FR` for an inward facing pair.
+    PairOrientation,
+    /// The value of the given aux tag, e.g. `HP` for the haplotype.
+    Tag(String),
+}
+
+impl FromStr for ReadAttribute {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "strand" => ReadAttribute::Strand,
+            "mapq" => ReadAttribute::Mapq,
+            "pair-orientation" => ReadAttribute::PairOrientation,
+            tag if tag.len() == 2 && tag.chars().all(|c| c.is_ascii_alphanumeric()) => {
+                ReadAttribute::Tag(tag.to_string())
+            }
+            _ => bail!(
+                "Invalid read attribute '{s}'. Expected strand, mapq, pair-orientation or a two-character aux tag."
+            ),
+        })
+    }
+}
+
+impl Display for ReadAttribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadAttribute::Strand => write!(f, "strand"),
+            ReadAttribute::Mapq => write!(f, "mapq"),
+            ReadAttribute::PairOrientation => write!(f, "pair-orientation"),
+            ReadAttribute::Tag(tag) => write!(f, "{tag}"),
+        }
+    }
+}
+
+impl ReadAttribute {
+    /// Returns the value of the attribute for the given record.
+    pub(crate) fn value(&self, record: &bam::Record) -> String {
+        match self {
+            ReadAttribute::Strand => if record.is_reverse() { "-" } else { "+" }.to_string(),
+            ReadAttribute::Mapq => record.mapq().to_string(),
+            ReadAttribute::PairOrientation => {
+                if !record.is_paired() || record.is_mate_unmapped() {
+                    return "unpaired".to_string();
+                }
+                if record.tid() != record.mtid() {
+                    return "interchromosomal".to_string();
+                }
+                let strand = |reverse: bool| if reverse { 'R' } else { 'F' };
+                let (read, mate) = (
+                    strand(record.is_reverse()),
+                    strand(record.is_mate_reverse()),
+                );
+                let is_left = record.pos() < record.mpos()
+                    || (record.pos() == record.mpos() && record.is_first_in_template());
+                if is_left {
+                    format!("{read}{mate}")
+                } else {
+                    format!("{mate}{read}")
+                }
+            }
+            ReadAttribute::Tag(tag) => record
+                .aux(tag.as_bytes())
+                .map_or(String::from("None"), aux_to_string),
+        }
+    }
+}
+
+/// What reads are sorted by before they are packed into rows, like the sort options of IGV.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SortBy {
+    /// The base at the given 0-based position: reads with a base differing from the reference
+    /// first (ordered by base), then deletions, then reference bases.
+    Base { target: String, position: i64 },
+    /// The strand, forward first.
+    Strand,
+    /// The mapping quality, highest first.
+    Mapq,
+    /// The absolute insert size, largest first.
+    InsertSize,
+    /// The value of the given aux tag.
+    Tag(String),
+}
+
+impl FromStr for SortBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(locus) = s.strip_prefix("base@") {
+            let (target, position) = locus.rsplit_once(':').context(format!(
+                "Invalid locus '{locus}'. Expected target:position."
+            ))?;
+            let position = i64::from_str(position)
+                .ok()
+                .filter(|position| *position > 0)
+                .context(format!("Invalid position '{position}' in '{s}'."))?;
+            return Ok(SortBy::Base {
+                target: target.to_string(),
+                position: position - 1,
+            });
+        }
+        Ok(match s {
+            "strand" => SortBy::Strand,
+            "mapq" => SortBy::Mapq,
+            "insert-size" => SortBy::InsertSize,
+            _ => match s.strip_prefix("tag:") {
+                Some(tag) if tag.len() == 2 && tag.chars().all(|c| c.is_ascii_alphanumeric()) => {
+                    SortBy::Tag(tag.to_string())
+                }
+                _ => bail!(
+                    "Invalid sort order '{s}'. Expected base@target:position, strand, mapq, insert-size or tag:XX with a two-character aux tag."
+                ),
+            },
+        })
+    }
+}
+
+impl Display for SortBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SortBy::Base { target, position } => write!(f, "base@{target}:{}", position + 1),
+            SortBy::Strand => write!(f, "strand"),
+            SortBy::Mapq => write!(f, "mapq"),
+            SortBy::InsertSize => write!(f, "insert-size"),
+            SortBy::Tag(tag) => write!(f, "tag:{tag}"),
+        }
+    }
+}
+
+impl SortBy {
+    /// Returns the key the given record is sorted by, smaller keys first. `ref_seq` is the
+    /// reference sequence starting at the position of the record and `target` the target it is
+    /// aligned to. Returns `None` if the record has no value, e.g. if it does not cover the sorted
+    /// position; such reads are sorted last.
+    pub(crate) fn key(
+        &self,
+        record: &bam::Record,
+        ref_seq: &[char],
+        target: &str,
+    ) -> Option<(i64, String)> {
+        match self {
+            SortBy::Base {
+                target: sort_target,
+                position,
+            } => {
+                if sort_target != target {
+                    return None;
+                }
+                let seq = record.seq();
+                let mut ref_pos = record.pos();
+                let mut read_index = 0;
+                for c in &record.cigar() {
+                    let length = i64::from(c.len());
+                    let covers = (ref_pos..ref_pos + length).contains(position);
+                    match c {
+                        Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_) => {
+                            if covers {
+                                let index = read_index + (position - ref_pos) as usize;
+                                if index >= seq.len() {
+                                    return None;
+                                }
+                                let base = (seq[index] as char).to_ascii_uppercase();
+                                let reference = ref_seq
+                                    .get((position - record.pos()) as usize)
+                                    .map(char::to_ascii_uppercase);
+                                return Some(if reference == Some(base) {
+                                    (2, String::new())
+                                } else {
+                                    (0, base.to_string())
+                                });
+                            }
+                            ref_pos += length;
+                            read_index += length as usize;
+                        }
+                        Cigar::Del(_) if covers => return Some((1, String::new())),
+                        Cigar::Del(_) | Cigar::RefSkip(_) => ref_pos += length,
+                        Cigar::Ins(_) | Cigar::SoftClip(_) => read_index += length as usize,
+                        Cigar::HardClip(_) | Cigar::Pad(_) => {}
+                    }
+                }
+                None
+            }
+            SortBy::Strand => Some((i64::from(record.is_reverse()), String::new())),
+            SortBy::Mapq => Some((-i64::from(record.mapq()), String::new())),
+            SortBy::InsertSize => Some((-record.insert_size().abs(), String::new())),
+            SortBy::Tag(tag) => record
+                .aux(tag.as_bytes())
+                .ok()
+                .map(|value| (0, aux_to_string(value))),
+        }
+    }
+}
+
+/// The classes reads are colored by with `--view-as-pairs`, together with their colors. Inward
+/// facing (`FR`) pairs are classified by their insert size, all other pairs by their orientation.
+pub(crate) const PAIR_COLORS: [(&str, &str); 8] = [
+    ("concordant", "#BBBBBB"),
+    ("large insert", "#CC6677"),
+    ("small insert", "#332288"),
+    ("RF", "#117733"),
+    ("FF", "#44AA99"),
+    ("RR", "#999933"),
+    ("interchromosomal", "#DDCC77"),
+    ("unpaired", "#888888"),
+];
+
+/// Inward facing pairs with an insert size this many times larger or smaller than the median
+/// insert size of all inward facing pairs are considered outliers.
+const INSERT_SIZE_OUTLIER_FACTOR: i64 = 3;
+
+/// Replaces the `FR` orientation in the colors of the given reads, as set by coloring them by
+/// [`ReadAttribute::PairOrientation`], by the insert size class of their pair.
+fn classify_pairs(reads: &mut [Read]) {
+    let is_inward = |read: &Read| read.color.as_deref() == Some("FR");
+    let insert_sizes = reads
+        .iter()
+        .filter(|read| is_inward(read) && read.tlen != 0)
+        .map(|read| read.tlen.abs())
+        .sorted()
+        .collect_vec();
+    let median = insert_sizes
+        .get(insert_sizes.len() / 2)
+        .copied()
+        .unwrap_or_default();
+    for read in reads.iter_mut().filter(|read| is_inward(read)) {
+        let insert_size = read.tlen.abs();
+        let class = if insert_size == 0 {
+            "concordant"
+        } else if insert_size > median * INSERT_SIZE_OUTLIER_FACTOR {
+            "large insert"
+        } else if insert_size * INSERT_SIZE_OUTLIER_FACTOR < median {
+            "small insert"
+        } else {
+            "concordant"
+        };
+        read.color = Some(class.to_string());
+    }
+}
+
+/// A reference with all relevant information base for being plotted in a read plot
+#[derive(Serialize, Debug, Eq, PartialEq)]
+pub struct Reference {
+    start: i64,
+    reference: String,
+}
+
+impl Reference {
+    /// The 0-based position of the first base.
+    pub fn start(&self) -> i64 {
+        self.start
+    }
+
+    pub fn bases(&self) -> &str {
+        &self.reference
+    }
+}
+
+// A struct representing base coverage information, m represents a match to the reference,
+// del a deletion of the base and ins an insertion right after it
+#[derive(Serialize, Debug, Eq, PartialEq, Default, Clone, Copy)]
+pub(crate) struct BaseCoverage {
+    pub(crate) a: usize,
+    pub(crate) t: usize,
+    pub(crate) g: usize,
+    pub(crate) c: usize,
+    pub(crate) m: usize,
+    pub(crate) del: usize,
+    pub(crate) ins: usize,
+}
+
+impl BaseCoverage {
+    /// Returns the total number of reads covering the position, including the ones with a
+    /// deletion of the base.
+    pub(crate) fn depth(&self) -> usize {
+        self.a + self.t + self.g + self.c + self.m + self.del
+    }
+
+    /// Filters out low-frequency mismatches and indels. Mismatches and deletions are merged into
+    /// the match count m, so that the depth stays the same, insertions are dropped.
+    pub fn filter_mismatches(&mut self, threshold: f64) {
+        let total = self.depth();
+        if total == 0 {
+            return;
+        }
+
+        let below_threshold = |count: usize| (count as f64 / total as f64 * 100.0) < threshold;
+        let mut removed = 0;
+        for b in [
+            &mut self.a,
+            &mut self.t,
+            &mut self.g,
+            &mut self.c,
+            &mut self.del,
+        ] {
+            if below_threshold(*b) {
+                removed += *b;
+                *b = 0;
+            }
+        }
+        self.m += removed;
+        if below_threshold(self.ins) {
+            self.ins = 0;
+        }
+    }
+
+    /// Hides the mismatches and indels of a single strand that have been filtered out of the
+    /// given coverage of both strands, the same way as `filter_mismatches`.
+    pub(crate) fn filter_like(&mut self, total: &BaseCoverage) {
+        let mut removed = 0;
+        for (b, total_count) in [
+            (&mut self.a, total.a),
+            (&mut self.t, total.t),
+            (&mut self.g, total.g),
+            (&mut self.c, total.c),
+            (&mut self.del, total.del),
+        ] {
+            if total_count == 0 {
+                removed += *b;
+                *b = 0;
+            }
+        }
+        self.m += removed;
+        if total.ins == 0 {
+            self.ins = 0;
+        }
+    }
+}
+
+impl Add for BaseCoverage {
+    type Output = BaseCoverage;
+
+    fn add(self, other: BaseCoverage) -> BaseCoverage {
+        BaseCoverage {
+            a: self.a + other.a,
+            t: self.t + other.t,
+            g: self.g + other.g,
+            c: self.c + other.c,
+            m: self.m + other.m,
+            del: self.del + other.del,
+            ins: self.ins + other.ins,
+        }
+    }
+}
+
+impl Sub for BaseCoverage {
+    type Output = BaseCoverage;
+
+    fn sub(self, other: BaseCoverage) -> BaseCoverage {
+        BaseCoverage {
+            a: self.a - other.a,
+            t: self.t - other.t,
+            g: self.g - other.g,
+            c: self.c - other.c,
+            m: self.m - other.m,
+            del: self.del - other.del,
+            ins: self.ins - other.ins,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Eq, PartialEq, Default, Clone)]
+pub(crate) struct EncodedBaseCoverage(pub Vec<BaseCoverage>);
+
+impl EncodedBaseCoverage {
+    /// Encode the match coverage as differences (Δ to previous position)
+    pub fn matches(&self) -> String {
+        let mut last = 0;
+        self.0
+            .iter()
+            .map(|bc| {
+                let diff = bc.m as isize - last;
+                last = bc.m as isize;
+                diff.to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("|")
+    }
+
+    /// Helper for sparse encoding of a base track (A, T, G, C) or an indel track
+    fn encode_sparse<F>(&self, f: F) -> String
+    where
+        F: Fn(&BaseCoverage) -> usize,
+    {
+        self.0
+            .iter()
+            .enumerate()
+            .filter_map(|(i, bc)| {
+                let cov = f(bc);
+                if cov > 0 {
+                    Some(format!("{}|{}", i + 1, cov))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("$")
+    }
+
+    pub fn a(&self) -> String {
+        self.encode_sparse(|bc| bc.a)
+    }
+
+    pub fn t(&self) -> String {
+        self.encode_sparse(|bc| bc.t)
+    }
+
+    pub fn g(&self) -> String {
+        self.encode_sparse(|bc| bc.g)
+    }
+
+    pub fn c(&self) -> String {
+        self.encode_sparse(|bc| bc.c)
+    }
+
+    pub fn del(&self) -> String {
+        self.encode_sparse(|bc| bc.del)
+    }
+
+    pub fn ins(&self) -> String {
+        self.encode_sparse(|bc| bc.ins)
+    }
+}
+
+/// A coverage with all relevant information base for being plotted over a read plot
+/// Each value in coverage represents the number of reads covering that position. The fields
+/// suffixed with `_rev` only count the reads on the reverse strand.
+#[derive(Serialize, Debug, Eq, PartialEq)]
+pub struct Coverage {
+    sample: String,
+    start: i64,
+    #[serde(rename = "m")]
+    matches: String,
+    a: String,
+    t: String,
+    g: String,
+    c: String,
+    del: String,
+    ins: String,
+    #[serde(rename = "m_rev")]
+    reverse_matches: String,
+    a_rev: String,
+    t_rev: String,
+    g_rev: String,
+    c_rev: String,
+    del_rev: String,
+    ins_rev: String,
+    /// The coverage of the forward and the reverse strand before low-frequency mismatches and
+    /// indels are hidden, which the strand bias is computed from.
+    #[serde(skip)]
+    strands: [Vec<BaseCoverage>; 2],
+}
+
+impl Coverage {
+    pub fn sample(&self) -> &str {
+        &self.sample
+    }
+
+    /// The 0-based position of the first covered base.
+    pub fn start(&self) -> i64 {
+        self.start
+    }
+
+    /// Decodes the delta and sparse encodings back into one `BaseCoverage` per position.
+    pub(crate) fn decode(&self) -> Result<Vec<BaseCoverage>> {
+        Self::decode_tracks(
+            &self.matches,
+            [&self.a, &self.t, &self.g, &self.c, &self.del, &self.ins],
+        )
+    }
+
+    /// Like [`Coverage::decode`], but only for the reads on the reverse strand.
+    pub(crate) fn decode_reverse(&self) -> Result<Vec<BaseCoverage>> {
+        Self::decode_tracks(
+            &self.reverse_matches,
+            [
+                &self.a_rev,
+                &self.t_rev,
+                &self.g_rev,
+                &self.c_rev,
+                &self.del_rev,
+                &self.ins_rev,
+            ],
+        )
+    }
+
+    fn decode_tracks(matches: &str, tracks: [&str; 6]) -> Result<Vec<BaseCoverage>> {
+        let mut coverage = Vec::new();
+        let mut depth = 0;
+        for delta in matches.split('|').filter(|d| !d.is_empty()) {
+            depth += delta.parse::<isize>()?;
+            coverage.push(BaseCoverage {
+                m: depth as usize,
+                ..Default::default()
+            });
+        }
+        for (base, track) in ['a', 't', 'g', 'c', 'd', 'i'].into_iter().zip(tracks) {
+            for entry in track.split('$').filter(|e| !e.is_empty()) {
+                let (index, count) = entry
+                    .split_once('|')
+                    .context(format!("Invalid sparse coverage entry {entry}"))?;
+                let index = index.parse::<usize>()? - 1;
+                let position = coverage
+                    .get_mut(index)
+                    .context(format!("Coverage index {index} out of bounds"))?;
+                let field = match base {
+                    'a' => &mut position.a,
+                    't' => &mut position.t,
+                    'g' => &mut position.g,
+                    'c' => &mut position.c,
+                    'd' => &mut position.del,
+                    _ => &mut position.ins,
+                };
+                *field = count.parse()?;
+            }
+        }
+        Ok(coverage)
+    }
+
+    /// Counts the coverage of the given reads within the given region. Mismatches below
+    /// `min_base_quality` are counted as matches.
     pub fn from_reads(
         reads: &[Read],
         region: &Region,
         mismatch_display_min_percent: f64,
+        min_base_quality: u8,
         sample: String,
     ) -> Self {
-        let mut coverage = vec![BaseCoverage::default(); region.length() as usize];
+        let mut counter = CoverageCounter::new(region, min_base_quality);
+        for read in reads {
+            counter.add(read);
+        }
+        counter.finish(mismatch_display_min_percent, sample)
+    }
+}
+
+/// Counts the coverage of a region from reads added one by one in the order of their position, so
+/// that the reads do not have to be kept. Reads with aligned qualities are held back until their
+/// mate has been added or can no longer overlap them, so that positions covered by both mates are
+/// counted once (see [`mate_overlaps`]).
+pub(crate) struct CoverageCounter {
+    start: i64,
+    end: i64,
+    min_base_quality: u8,
+    /// The coverage of the forward and the reverse strand.
+    strands: [Vec<BaseCoverage>; 2],
+    /// The held back reads by name.
+    pending: HashMap<String, Read>,
+    /// The ends and names of the held back reads, the smallest end on top.
+    pending_ends: BinaryHeap<Reverse<(i64, String)>>,
+}
+
+impl CoverageCounter {
+    pub(crate) fn new(region: &Region, min_base_quality: u8) -> Self {
+        CoverageCounter {
+            start: region.start,
+            end: region.end,
+            min_base_quality,
+            strands: [
+                vec![BaseCoverage::default(); region.length() as usize],
+                vec![BaseCoverage::default(); region.length() as usize],
+            ],
+            pending: HashMap::new(),
+            pending_ends: BinaryHeap::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, read: &Read) {
+        // Held back reads ending before the given read starts cannot overlap their mate anymore.
+        while let Some(Reverse((end, _))) = self.pending_ends.peek() {
+            if *end > read.position {
+                break;
+            }
+            let Reverse((_, name)) = self.pending_ends.pop().unwrap();
+            if let Some(pending) = self.pending.remove(&name) {
+                self.count(&pending, &HashSet::new());
+            }
+        }
+        if read.aligned_qualities.is_none() {
+            self.count(read, &HashSet::new());
+            return;
+        }
+        match self.pending.remove(&read.name) {
+            Some(mate) => {
+                let [mate_skipped, skipped] = mate_overlaps(&mate, read);
+                self.count(&mate, &mate_skipped);
+                self.count(read, &skipped);
+            }
+            None => {
+                self.pending_ends
+                    .push(Reverse((read.end_position, read.name.clone())));
+                self.pending.insert(read.name.clone(), read.clone());
+            }
+        }
+    }
+
+    /// Counts the coverage of the given read, except for the skipped positions.
+    fn count(&mut self, read: &Read, skipped: &HashSet<i64>) {
+        if read.end_position <= self.start || read.position >= self.end {
+            return;
+        }
+        let coverage = &mut self.strands[usize::from(read.flags & 0x10 != 0)];
+        let counted = |position: i64| !skipped.contains(&position);
+        let mut ref_pos = read.position;
+        for cigar in &read.cigar {
+            let start = ref_pos.max(self.start);
+            match cigar.cigar_type {
+                CigarType::Match => {
+                    if let Some(len) = cigar.length {
+                        let end = (ref_pos + len as i64).min(self.end);
+                        for i in (start..end).filter(|i| counted(*i)) {
+                            coverage[(i - self.start) as usize].m += 1;
+                        }
+                        ref_pos += len as i64;
+                    }
+                }
+                CigarType::Sub => {
+                    if let (Some(len), Some(bases)) = (cigar.length, &cigar.bases) {
+                        let end = (ref_pos + len as i64).min(self.end);
+                        // Mismatches below the minimum base quality are counted as
+                        // matches, so that they still contribute to the depth.
+                        let low_quality = cigar
+                            .qualities
+                            .as_ref()
+                            .is_some_and(|qualities| qualities[0] < self.min_base_quality);
+                        for pos in (start..end).filter(|pos| counted(*pos)) {
+                            let idx = (pos - self.start) as usize;
+                            if low_quality {
+                                coverage[idx].m += 1;
+                                continue;
+                            }
+                            match bases[0] {
+                                'A' => coverage[idx].a += 1,
+                                'T' => coverage[idx].t += 1,
+                                'G' => coverage[idx].g += 1,
+                                'C' => coverage[idx].c += 1,
+                                _ => coverage[idx].m += 1,
+                            }
+                        }
+                        ref_pos += len as i64;
+                    }
+                }
+
+                CigarType::Del => {
+                    if let Some(len) = cigar.length {
+                        let end = (ref_pos + len as i64).min(self.end);
+                        for i in (start..end).filter(|i| counted(*i)) {
+                            coverage[(i - self.start) as usize].del += 1;
+                        }
+                        ref_pos += len as i64;
+                    }
+                }
+                // Soft clipped bases are not aligned, so they do not add to the coverage.
+                CigarType::Skip | CigarType::SoftClip => {
+                    if let Some(len) = cigar.length {
+                        ref_pos += len as i64;
+                    }
+                }
+                // Insertions are counted at the base they follow.
+                CigarType::Ins => {
+                    if ref_pos > self.start && ref_pos <= self.end && counted(ref_pos - 1) {
+                        coverage[(ref_pos - 1 - self.start) as usize].ins += 1;
+                    }
+                }
+                // Neither hard clipped nor padded bases consume the reference.
+                CigarType::HardClip | CigarType::Pad => {}
+            }
+        }
+    }
+
+    pub(crate) fn finish(mut self, mismatch_display_min_percent: f64, sample: String) -> Coverage {
+        for pending in mem::take(&mut self.pending).into_values() {
+            self.count(&pending, &HashSet::new());
+        }
+        let strands = self.strands.clone();
+        let [forward, mut reverse] = self.strands;
+        let mut coverage = forward
+            .into_iter()
+            .zip(&reverse)
+            .map(|(forward, reverse)| forward + *reverse)
+            .collect_vec();
+        for (cov, reverse) in coverage.iter_mut().zip(&mut reverse) {
+            cov.filter_mismatches(mismatch_display_min_percent);
+            reverse.filter_like(cov);
+        }
+
+        let coverage = EncodedBaseCoverage(coverage);
+        let reverse = EncodedBaseCoverage(reverse);
+
+        Coverage {
+            sample,
+            start: self.start,
+            matches: coverage.matches(),
+            a: coverage.a(),
+            t: coverage.t(),
+            g: coverage.g(),
+            c: coverage.c(),
+            del: coverage.del(),
+            ins: coverage.ins(),
+            reverse_matches: reverse.matches(),
+            a_rev: reverse.a(),
+            t_rev: reverse.t(),
+            g_rev: reverse.g(),
+            c_rev: reverse.c(),
+            del_rev: reverse.del(),
+            ins_rev: reverse.ins(),
+            strands,
+        }
+    }
+}
+
+/// The call of a read at a reference position, used to compare overlapping mates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Call {
+    Match,
+    Base(char),
+    Deletion,
+}
+
+impl Read {
+    /// Returns the calls of the read by reference position.
+    fn calls(&self) -> HashMap<i64, Call> {
+        let mut calls = HashMap::new();
+        let mut ref_pos = self.position;
+        for cigar in &self.cigar {
+            let length = i64::from(cigar.length.unwrap_or(0));
+            let call = match cigar.cigar_type {
+                CigarType::Match => Some(Call::Match),
+                CigarType::Sub => cigar.bases.as_ref().map(|bases| Call::Base(bases[0])),
+                CigarType::Del => Some(Call::Deletion),
+                _ => None,
+            };
+            if let Some(call) = call {
+                calls.extend((ref_pos..ref_pos + length).map(|position| (position, call)));
+            }
+            if cigar.cigar_type.consumes_reference() {
+                ref_pos += length;
+            }
+        }
+        calls
+    }
+
+    /// Returns whether the read has a mismatch, deletion or insertion at one of the given sites
+    /// (0-based, end exclusive). Insertions count at the base they follow.
+    fn carries_variant(&self, sites: &[Range<i64>]) -> bool {
+        let at_site = |position: i64| sites.iter().any(|site| site.contains(&position));
+        let mut ref_pos = self.position;
+        for cigar in &self.cigar {
+            let length = i64::from(cigar.length.unwrap_or(0));
+            let variant = match cigar.cigar_type {
+                CigarType::Sub | CigarType::Del => (ref_pos..ref_pos + length).any(at_site),
+                CigarType::Ins => at_site(ref_pos - 1),
+                _ => false,
+            };
+            if variant {
+                return true;
+            }
+            if cigar.cigar_type.consumes_reference() {
+                ref_pos += length;
+            }
+        }
+        false
+    }
+
+    /// Returns the base quality of the read at the given reference position, 0 if unknown.
+    fn quality_at(&self, position: i64) -> u8 {
+        self.aligned_qualities
+            .as_ref()
+            .and_then(|(start, qualities)| qualities.get(usize::try_from(position - start).ok()?))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// Returns the positions of each of two mates that are not counted in the coverage as the other
+/// mate covers them, too. Like samtools mpileup does by default, the call with the higher base
+/// quality is kept if the mates disagree and the one of the first mate otherwise.
+fn mate_overlaps(first: &Read, second: &Read) -> [HashSet<i64>; 2] {
+    let mut skipped = [HashSet::new(), HashSet::new()];
+    if first.end_position <= second.position || second.end_position <= first.position {
+        return skipped;
+    }
+    let first_calls = first.calls();
+    for (position, call) in second.calls() {
+        let Some(first_call) = first_calls.get(&position) else {
+            continue;
+        };
+        let keep_second =
+            *first_call != call && second.quality_at(position) > first.quality_at(position);
+        skipped[usize::from(!keep_second)].insert(position);
+    }
+    skipped
+}
+
+/// A splice junction, i.e. a skipped region of the reference shared by one or more reads. Used
+/// for the arcs of the sashimi track.
+#[derive(Serialize, Debug, Eq, PartialEq, Clone)]
+pub struct Junction {
+    sample: String,
+    start: i64,
+    end: i64,
+    count: usize,
+}
+
+impl Junction {
+    /// The sample the supporting reads belong to.
+    pub fn sample(&self) -> &str {
+        &self.sample
+    }
+
+    /// The 0-based position of the first skipped base.
+    pub fn start(&self) -> i64 {
+        self.start
+    }
+
+    /// The 0-based, exclusive end of the skipped region.
+    pub fn end(&self) -> i64 {
+        self.end
+    }
+
+    /// The number of reads supporting the junction.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+/// Aggregates the skipped regions of reads that overlap a region into junctions.
+#[derive(Debug, Default)]
+pub(crate) struct JunctionCounter(HashMap<(i64, i64), usize>);
+
+impl JunctionCounter {
+    /// Counts the skipped regions of the given read that overlap the given region.
+    pub(crate) fn add(&mut self, read: &Read, region: &Region) {
+        let mut ref_pos = read.position;
+        for cigar in &read.cigar {
+            let length = i64::from(cigar.length.unwrap_or(0));
+            match cigar.cigar_type {
+                CigarType::Skip => {
+                    if ref_pos < region.end && ref_pos + length > region.start {
+                        *self.0.entry((ref_pos, ref_pos + length)).or_default() += 1;
+                    }
+                    ref_pos += length;
+                }
+                _ if cigar.cigar_type.consumes_reference() => ref_pos += length,
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns the junctions ordered by their position.
+    pub(crate) fn finish(self, sample: String) -> Vec<Junction> {
+        self.0
+            .into_iter()
+            .sorted()
+            .map(|((start, end), count)| Junction {
+                sample: sample.clone(),
+                start,
+                end,
+                count,
+            })
+            .collect()
+    }
+}
+
+/// The number of reads with a call for a base modification at a position of the plotted region,
+/// and how many of them are modified. Used for the modification frequency track.
+#[derive(Serialize, Debug, Eq, PartialEq, Clone)]
+pub struct ModificationFrequency {
+    sample: String,
+    position: i64,
+    code: String,
+    modified: usize,
+    total: usize,
+}
+
+impl ModificationFrequency {
+    /// The sample the reads belong to.
+    pub fn sample(&self) -> &str {
+        &self.sample
+    }
+
+    /// The 0-based position of the modified base.
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+
+    /// The modification code, e.g. `m` for 5mC or a ChEBI identifier.
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    /// The number of reads calling the base as modified.
+    pub fn modified(&self) -> usize {
+        self.modified
+    }
+
+    /// The number of reads with a call for the modification at the position.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+}
+
+/// Aggregates the base modification calls of reads within a region into frequencies.
+#[derive(Debug, Default)]
+pub(crate) struct ModificationCounter(HashMap<(i64, String), (usize, usize)>);
+
+impl ModificationCounter {
+    /// Counts the base modification calls of the given read within the given region.
+    pub(crate) fn add(&mut self, read: &Read, region: &Region) {
+        for modification in &read.modifications {
+            if modification.position < region.start || modification.position >= region.end {
+                continue;
+            }
+            let (modified, total) = self
+                .0
+                .entry((modification.position, modification.code.clone()))
+                .or_default();
+            if modification.likelihood >= MIN_MODIFIED_LIKELIHOOD {
+                *modified += 1;
+            }
+            *total += 1;
+        }
+    }
+
+    /// Returns the frequencies ordered by position and modification code.
+    pub(crate) fn finish(self, sample: String) -> Vec<ModificationFrequency> {
+        self.0
+            .into_iter()
+            .sorted()
+            .map(
+                |((position, code), (modified, total))| ModificationFrequency {
+                    sample: sample.clone(),
+                    position,
+                    code,
+                    modified,
+                    total,
+                },
+            )
+            .collect()
+    }
+}
+
+/// The strand bias of the reads of a sample at a position of a highlighted site, given by
+/// Fisher's exact test on the forward and reverse strand counts of reads matching the reference
+/// and of reads with a mismatch or indel. The position with the strongest bias is shown in the
+/// tooltip of the highlight.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct StrandBias {
+    sample: String,
+    name: String,
+    /// The sample and the name of the highlight, joined by `:` to look up the strand bias from
+    /// the highlight track of each sample.
+    site: String,
+    position: i64,
+    ref_forward: usize,
+    ref_reverse: usize,
+    alt_forward: usize,
+    alt_reverse: usize,
+    p_value: f64,
+    fs: f64,
+}
+
+impl StrandBias {
+    /// The sample the reads belong to.
+    pub fn sample(&self) -> &str {
+        &self.sample
+    }
 
-        for read in reads {
-            if !(read.end_position <= region.start || read.position >= region.end) {
-                let mut ref_pos = read.position;
-                for cigar in &read.cigar {
-                    let start = ref_pos.max(region.start);
-                    match cigar.cigar_type {
-                        CigarType::Match => {
-                            if let Some(len) = cigar.length {
-                                let end = (ref_pos + len as i64).min(region.end);
-                                for i in start..end {
-                                    coverage[(i - region.start) as usize].m += 1;
-                                }
-                                ref_pos += len as i64;
-                            }
-                        }
-                        CigarType::Sub => {
-                            if let (Some(len), Some(bases)) = (cigar.length, &cigar.bases) {
-                                let end = (ref_pos + len as i64).min(region.end);
-                                for pos in start..end {
-                                    let idx = (pos - region.start) as usize;
-                                    match bases[0] {
-                                        'A' => coverage[idx].a += 1,
-                                        'T' => coverage[idx].t += 1,
-                                        'G' => coverage[idx].g += 1,
-                                        'C' => coverage[idx].c += 1,
-                                        _ => coverage[idx].m += 1,
-                                    }
-                                }
-                                ref_pos += len as i64;
-                            }
-                        }
+    /// The name of the highlighted site.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-                        CigarType::Del => {
-                            if let Some(len) = cigar.length {
-                                ref_pos += len as i64;
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-            }
-        }
+    /// The 0-based position the reads are counted at.
+    pub fn position(&self) -> i64 {
+        self.position
+    }
 
-        for cov in &mut coverage {
-            cov.filter_mismatches(mismatch_display_min_percent);
-        }
+    /// The number of reads matching the reference on the forward and on the reverse strand.
+    pub fn reference_counts(&self) -> (usize, usize) {
+        (self.ref_forward, self.ref_reverse)
+    }
 
-        let coverage = EncodedBaseCoverage(coverage);
+    /// The number of reads with a mismatch or indel on the forward and on the reverse strand.
+    pub fn alternative_counts(&self) -> (usize, usize) {
+        (self.alt_forward, self.alt_reverse)
+    }
 
-        Self {
-            sample,
-            start: region.start,
-            matches: coverage.matches(),
-            a: coverage.a(),
-            t: coverage.t(),
-            g: coverage.g(),
-            c: coverage.c(),
+    /// The two-sided p-value of Fisher's exact test.
+    pub fn p_value(&self) -> f64 {
+        self.p_value
+    }
+
+    /// The phred-scaled p-value, like the `FS` annotation of GATK.
+    pub fn fs(&self) -> f64 {
+        self.fs
+    }
+
+    /// Computes the strand bias of the given coverage at each position covered by the given
+    /// (preprocessed) highlight, so that every read is counted once per table. All mismatches and
+    /// indels count, regardless of whether they are shown in the coverage track. Insertions are
+    /// counted at the base they follow, which almost always matches the reference, so reads with
+    /// an insertion after a matching base are counted as alternative instead of reference calls.
+    /// Positions outside of the coverage are skipped.
+    pub(crate) fn from_coverage(coverage: &Coverage, highlight: &Interval) -> Vec<Self> {
+        let [forward, reverse] = &coverage.strands;
+        let reference = |cov: &BaseCoverage| cov.m.saturating_sub(cov.ins);
+        let alternative =
+            |cov: &BaseCoverage| cov.a + cov.t + cov.g + cov.c + cov.del + cov.ins.min(cov.m);
+        highlight
+            .positions()
+            .filter_map(|position| {
+                let index = usize::try_from(position - coverage.start).ok()?;
+                let (forward, reverse) = (forward.get(index)?, reverse.get(index)?);
+                let (ref_forward, ref_reverse) = (reference(forward), reference(reverse));
+                let (alt_forward, alt_reverse) = (alternative(forward), alternative(reverse));
+                let p_value =
+                    fisher_exact([[ref_forward, ref_reverse], [alt_forward, alt_reverse]]);
+                Some(StrandBias {
+                    sample: coverage.sample.clone(),
+                    name: highlight.name.clone(),
+                    site: format!("{}:{}", coverage.sample, highlight.name),
+                    position,
+                    ref_forward,
+                    ref_reverse,
+                    alt_forward,
+                    alt_reverse,
+                    p_value,
+                    fs: (-10.0 * p_value.log10()).max(0.0),
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the position with the strongest strand bias of each site, given the strand biases
+    /// of all positions of each site one after another.
+    pub(crate) fn strongest(biases: &[StrandBias]) -> Vec<&StrandBias> {
+        biases
+            .iter()
+            .chunk_by(|bias| bias.site.clone())
+            .into_iter()
+            .filter_map(|(_, site)| site.min_by(|a, b| a.p_value.total_cmp(&b.p_value)))
+            .collect()
+    }
+}
+
+/// Returns the two-sided p-value of Fisher's exact test for the given 2x2 contingency table,
+/// i.e. the probability of all tables with the same margins that are at most as likely as the
+/// given one.
+fn fisher_exact(table: [[usize; 2]; 2]) -> f64 {
+    let [[a, b], [c, d]] = table;
+    let (row, col, n) = (a + b, a + c, a + b + c + d);
+    let first = (row + col).saturating_sub(n);
+    // The log probabilities of all tables with the given margins relative to the first one, from
+    // the ratio of the hypergeometric probabilities of consecutive tables.
+    let ln_probabilities = || {
+        (first..=row.min(col)).scan(0.0, move |ln_probability, x| {
+            if x > first {
+                let previous = (x - 1) as f64;
+                *ln_probability += ((row as f64 - previous) * (col as f64 - previous)
+                    / (x as f64 * (x + n - row - col) as f64))
+                    .ln();
+            }
+            Some((x, *ln_probability))
+        })
+    };
+    let (mut observed, mut max) = (0.0, f64::NEG_INFINITY);
+    for (x, ln_probability) in ln_probabilities() {
+        if x == a {
+            observed = ln_probability;
+        }
+        max = max.max(ln_probability);
+    }
+    let (mut extreme, mut total) = (0.0, 0.0);
+    for (_, ln_probability) in ln_probabilities() {
+        let probability = (ln_probability - max).exp();
+        total += probability;
+        // Allow for rounding errors when comparing to the observed table.
+        if ln_probability <= observed + 1e-7 {
+            extreme += probability;
         }
     }
+    (extreme / total).min(1.0)
 }
 
 /// A more detailed version of a CigarString with all relevant information base for being plotted in a read plot.
 ///
-/// | Cigar         | Syntax          |
-/// |---------------|-----------------|
-/// | Match         | `<#matches>=`   |
-/// | Deletion      | `<#deletions>d` |
-/// | Substitutions | `<#><base>`     |
-/// | Insertions    | `i<bases>`      |
+/// | Cigar         | Syntax                          |
+/// |---------------|---------------------------------|
+/// | Match         | `<#matches>=`                   |
+/// | Deletion      | `<#deletions>d`                 |
+/// | Skip (intron) | `<#skipped>n`                   |
+/// | Substitutions | `<#><base>[:<quality>]`         |
+/// | Insertions    | `i<bases>[:<quality>,...]`      |
+/// | Soft clips    | `s<bases>[:<quality>,...]`      |
+/// | Hard clips    | `<#clipped>h`                   |
+/// | Padding       | `<#padded>p`                    |
+///
+/// Base qualities are omitted if the record does not provide them. Consecutive substitutions are
+/// only merged if both base and quality are equal.
 ///
-/// Example: `50=|3d|10=|1C|1G|iGGT`
-#[derive(Debug, Eq, PartialEq)]
-struct PlotCigar(Vec<InnerPlotCigar>);
+/// Example: `50=|3d|10=|1C:37|1G:12|iGGT:37,37,20|1200n|40=`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct PlotCigar(Vec<InnerPlotCigar>);
 
 impl Serialize for PlotCigar {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -421,42 +2153,66 @@ impl FromStr for PlotCigar {
     fn from_str(s: &str) -> Result<Self> {
         let mut inner_cigars = Vec::new();
         for inner in s.split('|') {
-            let inner_cigar = match inner.chars().last() {
-                Some('=') => {
-                    let length = inner.chars().take(inner.len() - 1).collect::<String>();
-                    InnerPlotCigar {
-                        cigar_type: CigarType::Match,
-                        bases: None,
-                        length: Some(u32::from_str(&length).unwrap()),
-                    }
-                }
-                Some('d') => {
-                    let length = inner.chars().take(inner.len() - 1).collect::<String>();
-                    InnerPlotCigar {
-                        cigar_type: CigarType::Del,
-                        bases: None,
-                        length: Some(u32::from_str(&length).unwrap()),
-                    }
+            let (inner, qualities) = match inner.split_once(':') {
+                Some((inner, qualities)) => (
+                    inner,
+                    Some(
+                        qualities
+                            .split(',')
+                            .map(u8::from_str)
+                            .collect::<Result<Vec<_>, _>>()?,
+                    ),
+                ),
+                None => (inner, None),
+            };
+            if let Some(bases) = inner.strip_prefix('s') {
+                inner_cigars.push(InnerPlotCigar {
+                    cigar_type: CigarType::SoftClip,
+                    bases: Some(bases.chars().collect()),
+                    length: Some(bases.len() as u32),
+                    qualities,
+                });
+                continue;
+            }
+            if let Some(bases) = inner.strip_prefix('i') {
+                inner_cigars.push(InnerPlotCigar {
+                    cigar_type: CigarType::Ins,
+                    bases: Some(bases.chars().collect()),
+                    length: None,
+                    qualities,
+                });
+                continue;
+            }
+            let Some(operation) = inner.chars().last() else {
+                bail!("Empty operation in encoded CIGAR string: {s}");
+            };
+            let cigar_type = match operation {
+                '=' => CigarType::Match,
+                'd' => CigarType::Del,
+                'n' => CigarType::Skip,
+                'h' => CigarType::HardClip,
+                'p' => CigarType::Pad,
+                base if base.is_ascii_alphabetic() => CigarType::Sub,
+                _ => bail!("Invalid operation '{inner}' in encoded CIGAR string: {s}"),
+            };
+            let length = inner[..inner.len() - operation.len_utf8()]
+                .parse()
+                .context(format!(
+                    "Invalid length of operation '{inner}' in encoded CIGAR string: {s}"
+                ))?;
+            let inner_cigar = if cigar_type == CigarType::Sub {
+                InnerPlotCigar {
+                    cigar_type,
+                    bases: Some(vec![operation]),
+                    length: Some(length),
+                    qualities,
                 }
-                _ => {
-                    if inner.starts_with('i') {
-                        InnerPlotCigar {
-                            cigar_type: CigarType::Ins,
-                            bases: Some(inner.chars().skip(1).collect()),
-                            length: None,
-                        }
-                    } else {
-                        InnerPlotCigar {
-                            cigar_type: CigarType::Sub,
-                            bases: Some(vec![inner.chars().last().unwrap()]),
-                            length: Some(
-                                u32::from_str(
-                                    &inner.chars().take(inner.len() - 1).collect::<String>(),
-                                )
-                                .unwrap(),
-                            ),
-                        }
-                    }
+            } else {
+                InnerPlotCigar {
+                    cigar_type,
+                    bases: None,
+                    length: Some(length),
+                    qualities: None,
                 }
             };
             inner_cigars.push(inner_cigar);
@@ -465,11 +2221,13 @@ impl FromStr for PlotCigar {
     }
 }
 
-#[derive(Serialize, Debug, Eq, PartialEq)]
-struct InnerPlotCigar {
-    cigar_type: CigarType,
-    bases: Option<Vec<char>>,
-    length: Option<u32>,
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub(crate) struct InnerPlotCigar {
+    pub(crate) cigar_type: CigarType,
+    pub(crate) bases: Option<Vec<char>>,
+    pub(crate) length: Option<u32>,
+    /// Phred scaled base qualities of substituted (one for the whole run) or inserted bases.
+    pub(crate) qualities: Option<Vec<u8>>,
 }
 
 impl Display for InnerPlotCigar {
@@ -482,48 +2240,106 @@ impl Display for InnerPlotCigar {
                 self.bases.as_ref().unwrap().iter().collect::<String>()
             ),
             CigarType::Del => write!(f, "{}d", self.length.unwrap()),
+            CigarType::Skip => write!(f, "{}n", self.length.unwrap()),
+            CigarType::HardClip => write!(f, "{}h", self.length.unwrap()),
+            CigarType::Pad => write!(f, "{}p", self.length.unwrap()),
+            CigarType::SoftClip => write!(
+                f,
+                "s{}",
+                self.bases.as_ref().unwrap().iter().collect::<String>()
+            ),
             CigarType::Sub => write!(
                 f,
                 "{}{}",
                 self.length.unwrap(),
                 self.bases.as_ref().unwrap().iter().collect::<String>()
             ),
+        }?;
+        if let Some(qualities) = &self.qualities {
+            write!(f, ":{}", qualities.iter().join(","))?;
         }
+        Ok(())
     }
 }
 
-#[derive(Serialize, Debug, Eq, PartialEq)]
-enum CigarType {
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub(crate) enum CigarType {
     Match,
     Ins,
     Del,
     Sub,
+    /// A skipped region of the reference, e.g. an intron of a spliced RNA-seq read.
+    Skip,
+    /// Bases at the ends of the read that are not aligned to the reference.
+    SoftClip,
+    /// Bases clipped off the ends of the read that are not part of the stored sequence.
+    HardClip,
+    /// Silent padding of a multiple sequence alignment, without read or reference bases.
+    Pad,
+}
+
+impl CigarType {
+    /// Whether the operation advances the position along the reference. Soft clips count as
+    /// consuming, since they are laid out next to the aligned part of the read.
+    pub(crate) fn consumes_reference(&self) -> bool {
+        !matches!(self, CigarType::Ins | CigarType::HardClip | CigarType::Pad)
+    }
 }
 
 impl PlotCigar {
-    /// Creates a detailed PlotCigar from a given rust_htslib CigarStringView
+    /// Returns the number of reference bases covered by the cigar.
+    pub(crate) fn reference_length(&self) -> i64 {
+        self.0
+            .iter()
+            .filter(|inner| inner.cigar_type.consumes_reference())
+            .filter_map(|inner| inner.length)
+            .map(i64::from)
+            .sum()
+    }
+
+    /// Creates a detailed PlotCigar from a given rust_htslib CigarStringView. `read_qual` holds
+    /// the base qualities of the read and may be empty or `0xff` filled if they are unavailable.
+    /// `ref_seq` only covers the aligned part of the read, i.e. excludes soft clips.
     fn from_cigar(
         cigar: CigarStringView,
         read_seq: Vec<char>,
+        read_qual: &[u8],
         ref_seq: Vec<char>,
     ) -> Result<PlotCigar> {
+        let read_qual = (read_qual.len() == read_seq.len() && read_qual.first() != Some(&0xff))
+            .then_some(read_qual);
         let mut inner_plot_cigars = Vec::new();
         let (mut read_index, mut ref_index) = (0, 0);
         for c in &cigar {
             match c {
-                Cigar::Match(length) | Cigar::SoftClip(length) => {
+                // `=` and `X` are compared against the reference as well, so that the encoding
+                // does not depend on which flavour of match operators the aligner emits.
+                Cigar::Match(length) | Cigar::Equal(length) | Cigar::Diff(length) => {
                     inner_plot_cigars.extend(match_bases(
                         &read_seq[read_index..read_index + *length as usize],
+                        read_qual.map(|qual| &qual[read_index..read_index + *length as usize]),
                         &ref_seq[ref_index..ref_index + *length as usize],
                     ));
                     read_index += *length as usize;
                     ref_index += *length as usize;
                 }
+                Cigar::SoftClip(length) => {
+                    inner_plot_cigars.push(InnerPlotCigar {
+                        cigar_type: CigarType::SoftClip,
+                        bases: Some(read_seq[read_index..read_index + *length as usize].to_vec()),
+                        length: Some(*length),
+                        qualities: read_qual
+                            .map(|qual| qual[read_index..read_index + *length as usize].to_vec()),
+                    });
+                    read_index += *length as usize;
+                }
                 Cigar::Ins(length) => {
                     inner_plot_cigars.push(InnerPlotCigar {
                         cigar_type: CigarType::Ins,
                         bases: Some(read_seq[read_index..read_index + *length as usize].to_vec()),
                         length: None,
+                        qualities: read_qual
+                            .map(|qual| qual[read_index..read_index + *length as usize].to_vec()),
                     });
                     read_index += *length as usize;
                 }
@@ -532,37 +2348,73 @@ impl PlotCigar {
                         cigar_type: CigarType::Del,
                         bases: None,
                         length: Some(*length),
+                        qualities: None,
                     });
                     ref_index += *length as usize;
                 }
-                _ => {}
+                Cigar::RefSkip(length) => {
+                    inner_plot_cigars.push(InnerPlotCigar {
+                        cigar_type: CigarType::Skip,
+                        bases: None,
+                        length: Some(*length),
+                        qualities: None,
+                    });
+                    ref_index += *length as usize;
+                }
+                Cigar::HardClip(length) => {
+                    inner_plot_cigars.push(InnerPlotCigar {
+                        cigar_type: CigarType::HardClip,
+                        bases: None,
+                        length: Some(*length),
+                        qualities: None,
+                    });
+                }
+                Cigar::Pad(length) => {
+                    inner_plot_cigars.push(InnerPlotCigar {
+                        cigar_type: CigarType::Pad,
+                        bases: None,
+                        length: Some(*length),
+                        qualities: None,
+                    });
+                }
             }
         }
         Ok(PlotCigar(inner_plot_cigars))
     }
 }
 
-/// Matches a given read sequence against a given reference sequence and returning the result as Vec<InnerPlotCigar>
-fn match_bases(read_seq: &[char], ref_seq: &[char]) -> Vec<InnerPlotCigar> {
+/// Matches a given read sequence against a given reference sequence and returning the result as Vec<InnerPlotCigar>.
+/// Substitutions carry the base qualities of the read if given.
+fn match_bases(
+    read_seq: &[char],
+    read_qual: Option<&[u8]>,
+    ref_seq: &[char],
+) -> Vec<InnerPlotCigar> {
     let mut inner_plot_cigars = Vec::new();
     for (is_match, group) in &read_seq
         .iter()
         .zip_eq(ref_seq.iter())
-        .chunk_by(|(read, reference)| read == reference)
+        .enumerate()
+        .chunk_by(|(_, (read, reference))| read == reference)
     {
         if is_match {
             inner_plot_cigars.push(InnerPlotCigar {
                 cigar_type: CigarType::Match,
                 bases: None,
                 length: Some(group.count() as u32),
+                qualities: None,
             });
         } else {
-            let substitutions = group.into_iter().map(|(r, _)| *r).collect_vec();
-            for (length, base) in substitutions.iter().dedup_with_count() {
+            let substitutions = group
+                .into_iter()
+                .map(|(i, (r, _))| (*r, read_qual.map(|qual| qual[i])))
+                .collect_vec();
+            for (length, (base, quality)) in substitutions.iter().dedup_with_count() {
                 inner_plot_cigars.push(InnerPlotCigar {
                     cigar_type: CigarType::Sub,
                     bases: Some(vec![*base]),
                     length: Some(length as u32),
+                    qualities: quality.map(|quality| vec![quality]),
                 })
             }
         };
@@ -672,132 +2524,613 @@ fn clip_read(
             })
             .sum();
 
-        record.set_pos(start + new_leading_softclips);
-    } else {
-        record.set_pos(lower_bound);
+        record.set_pos(start + new_leading_softclips);
+    } else {
+        record.set_pos(lower_bound);
+    }
+
+    Ok(record)
+}
+
+impl Read {
+    /// Creates a Read from a given rust_htslib bam record. `ref_seq` holds the reference bases
+    /// covered by the aligned part of the record and `target_length` the length of its target.
+    /// Soft clipped bases are only kept if `show_soft_clips` is set, extending the read by its
+    /// clipped ends.
+    #[allow(clippy::too_many_arguments)]
+    fn from_record(
+        record: rust_htslib::bam::record::Record,
+        ref_seq: Vec<char>,
+        target_length: i64,
+        aux_tags: &Option<Vec<String>>,
+        region: &Region,
+        clamp: bool,
+        show_soft_clips: bool,
+        group_by: Option<&ReadAttribute>,
+        color_by: Option<&ReadAttribute>,
+    ) -> Result<Option<Read>> {
+        let read_seq = record
+            .seq()
+            .as_bytes()
+            .iter()
+            .map(|u| char::from(*u))
+            .collect_vec();
+        if read_seq.is_empty() && record.cigar().iter().next().is_some() {
+            warn!(
+                "Skipping read '{}': empty sequence but non-empty CIGAR string ('{}').",
+                String::from_utf8_lossy(record.qname()),
+                record.cigar(),
+            );
+            return Ok(None);
+        }
+        let modifications = BaseModification::from_record(&record).unwrap_or_else(|err| {
+            warn!("Ignoring base modifications: {err}");
+            Vec::new()
+        });
+        let aligned_start = record.pos();
+        let read_start = record.pos() - record.cigar().leading_softclips();
+        let read_end = record.reference_end() + record.cigar().trailing_softclips();
+
+        let lower_bound = if clamp { region.start } else { 0 };
+        let upper_bound = if clamp { region.end } else { target_length };
+
+        let record = if read_start < lower_bound || read_end > upper_bound {
+            clip_read(record, lower_bound, upper_bound)?
+        } else {
+            record
+        };
+        // Re-read the sequence from the (possibly clipped) record so it stays in sync with the
+        // clipped CIGAR and reference window below. Otherwise `from_cigar` would match the wrong
+        // read bases against the reference and report spurious mismatches.
+        let read_seq = record
+            .seq()
+            .as_bytes()
+            .iter()
+            .map(|u| char::from(*u))
+            .collect_vec();
+        let offset =
+            |position: i64| (position - aligned_start).clamp(0, ref_seq.len() as i64) as usize;
+        let ref_seq = ref_seq[offset(record.pos())..offset(record.reference_end())].to_vec();
+        let mut cigar = PlotCigar::from_cigar(record.cigar(), read_seq, record.qual(), ref_seq)?;
+        let (position, end_position) = if show_soft_clips {
+            (
+                record.pos() - record.cigar().leading_softclips(),
+                record.reference_end() + record.cigar().trailing_softclips(),
+            )
+        } else {
+            cigar
+                .0
+                .retain(|inner| inner.cigar_type != CigarType::SoftClip);
+            (record.pos(), record.reference_end())
+        };
+        let mpos = if record.is_paired() {
+            record.mpos()
+        } else {
+            -1
+        };
+        Ok(Some(Read {
+            name: String::from_utf8(record.qname().to_vec())?,
+            cigar,
+            position,
+            flags: record.flags(),
+            mapq: record.mapq(),
+            row: None,
+            end_position,
+            mpos,
+            aux: AuxRecord::new(&record, aux_tags),
+            raw_cigar: record.cigar().to_string(),
+            group: group_by.map(|attribute| format!("{attribute}: {}", attribute.value(&record))),
+            // Coloring by mapping quality is the default encoding of the plot.
+            color: color_by
+                .filter(|attribute| **attribute != ReadAttribute::Mapq)
+                .map(|attribute| attribute.value(&record)),
+            supplementary: SupplementaryAlignment::from_record(&record),
+            tlen: if record.is_paired() {
+                record.insert_size()
+            } else {
+                0
+            },
+            mtid: if record.is_paired() {
+                record.mtid()
+            } else {
+                -1
+            },
+            mate_target: None,
+            // Calls outside of the clipped read are dropped.
+            modifications: modifications
+                .into_iter()
+                .filter(|m| (record.pos()..record.reference_end()).contains(&m.position))
+                .collect(),
+            aligned_qualities: None,
+            sort_key: None,
+        }))
+    }
+
+    /// Sets the row of the Read
+    fn set_row(&mut self, row: u32) {
+        self.row = Some(row);
+    }
+}
+
+pub trait PlotOrder {
+    fn order(
+        &mut self,
+        max_read_depth: usize,
+        downsampling: &Downsampling,
+        display_mode: DisplayMode,
+    ) -> Result<()>;
+}
+
+/// How reads are subsampled if they take up more rows than the maximum read depth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DownsampleStrategy {
+    /// Keeps randomly chosen rows of reads. As the reads of a region are streamed, templates that
+    /// could not be shown in any row are dropped at random beforehand, so that the rows are
+    /// chosen from the rows packed from a random sample of the reads rather than from all reads.
+    #[default]
+    Rows,
+    /// Keeps randomly chosen reads (together with their mates) and packs them into rows again.
+    Reads,
+    /// Like `Reads`, but chooses reads by a hash of their name, so that the same reads are kept
+    /// across regions and plots.
+    ByNameHash,
+    /// Like `Reads`, but keeps reads with a mismatch or indel at a highlighted site first.
+    PreferVariantSupport,
+}
+
+impl FromStr for DownsampleStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "rows" => DownsampleStrategy::Rows,
+            "reads" => DownsampleStrategy::Reads,
+            "by-name-hash" => DownsampleStrategy::ByNameHash,
+            "prefer-variant-support" => DownsampleStrategy::PreferVariantSupport,
+            _ => bail!(
+                "Invalid downsample strategy '{s}'. Expected rows, reads, by-name-hash or prefer-variant-support."
+            ),
+        })
+    }
+}
+
+impl Display for DownsampleStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownsampleStrategy::Rows => write!(f, "rows"),
+            DownsampleStrategy::Reads => write!(f, "reads"),
+            DownsampleStrategy::ByNameHash => write!(f, "by-name-hash"),
+            DownsampleStrategy::PreferVariantSupport => write!(f, "prefer-variant-support"),
+        }
+    }
+}
+
+/// How densely reads are packed into rows and drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisplayMode {
+    /// Rows of full height, reads of a row are at least 5 bases apart.
+    #[default]
+    Expanded,
+    /// Rows of half the height, reads of a row are at least 1 base apart.
+    Squished,
+    /// Rows of a quarter of the height, reads of a row may directly follow each other.
+    Collapsed,
+}
+
+impl FromStr for DisplayMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "expanded" => DisplayMode::Expanded,
+            "squished" => DisplayMode::Squished,
+            "collapsed" => DisplayMode::Collapsed,
+            _ => bail!("Invalid display mode '{s}'. Expected expanded, squished or collapsed."),
+        })
+    }
+}
+
+impl Display for DisplayMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisplayMode::Expanded => write!(f, "expanded"),
+            DisplayMode::Squished => write!(f, "squished"),
+            DisplayMode::Collapsed => write!(f, "collapsed"),
+        }
+    }
+}
+
+impl DisplayMode {
+    /// The minimum number of bases between two reads of a row.
+    pub(crate) fn gap(self) -> i64 {
+        match self {
+            DisplayMode::Expanded => 5,
+            DisplayMode::Squished => 1,
+            DisplayMode::Collapsed => 0,
+        }
+    }
+
+    /// The factor the row height and the stroke widths of the reads are scaled by.
+    pub(crate) fn scale(self) -> f64 {
+        match self {
+            DisplayMode::Expanded => 1.0,
+            DisplayMode::Squished => 0.5,
+            DisplayMode::Collapsed => 0.25,
+        }
+    }
+}
+
+/// The strategy and seed used to subsample reads, together with the highlighted sites reads
+/// supporting a variant are looked up at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Downsampling {
+    pub(crate) strategy: DownsampleStrategy,
+    pub(crate) seed: u64,
+    /// The 0-based, end exclusive positions of the highlighted sites.
+    pub(crate) sites: Vec<Range<i64>>,
+}
+
+impl Default for Downsampling {
+    fn default() -> Self {
+        Downsampling {
+            strategy: DownsampleStrategy::default(),
+            seed: 42,
+            sites: Vec::new(),
+        }
+    }
+}
+
+impl Downsampling {
+    /// Returns the names of the templates of the given reads in the order they are kept.
+    fn rank_templates(&self, reads: &[Read], rng: &mut StdRng) -> Vec<String> {
+        let mut templates = reads
+            .iter()
+            .map(|read| read.name.clone())
+            .unique()
+            .collect_vec();
+        match self.strategy {
+            DownsampleStrategy::ByNameHash => {
+                templates.sort_by_key(|name| name_hash(name, self.seed))
+            }
+            _ => templates.shuffle(rng),
+        }
+        if self.strategy == DownsampleStrategy::PreferVariantSupport {
+            let supporting: HashSet<&str> = reads
+                .iter()
+                .filter(|read| read.carries_variant(&self.sites))
+                .map(|read| read.name.as_str())
+                .collect();
+            templates.sort_by_key(|name| !supporting.contains(name.as_str()));
+        }
+        templates
+    }
+}
+
+/// A hash of the given read name that is stable across runs and platforms (64 bit FNV-1a).
+fn name_hash(name: &str, seed: u64) -> u64 {
+    seed.to_le_bytes()
+        .iter()
+        .chain(name.as_bytes())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Stably sorts the given reads by their sort key, such that they are packed into rows in this
+/// order. The reads of a template are moved together, by the smallest key of any of them. Reads
+/// without a key are sorted last.
+fn sort_reads(reads: &mut [Read]) {
+    if reads.iter().all(|read| read.sort_key.is_none()) {
+        return;
+    }
+    let mut keys: HashMap<String, Option<(i64, String)>> = HashMap::new();
+    for read in reads.iter() {
+        let key = keys.entry(read.name.clone()).or_default();
+        if let Some(read_key) = &read.sort_key {
+            if key.as_ref().is_none_or(|key| read_key < key) {
+                *key = Some(read_key.clone());
+            }
+        }
+    }
+    reads.sort_by_cached_key(|read| {
+        let key = &keys[&read.name];
+        (key.is_none(), key.clone())
+    });
+}
+
+/// The ends of the rows reads are packed into, as a segment tree over the rows holding the
+/// smallest end in each subtree. Finds the first row a read fits into in logarithmic time instead
+/// of scanning all rows.
+struct RowEnds {
+    /// The number of leaves, always a power of two.
+    capacity: usize,
+    /// The nodes in heap order starting at index 1, the leaves start at `capacity`. Unused rows end
+    /// at `i64::MIN`.
+    ends: Vec<i64>,
+}
+
+impl RowEnds {
+    fn new() -> Self {
+        RowEnds {
+            capacity: 1,
+            ends: vec![i64::MIN; 2],
+        }
+    }
+
+    /// Returns the index of the first row that ends at or before the given position, which is an
+    /// unused one if no row in use does.
+    fn first_fit(&mut self, limit: i64) -> usize {
+        if self.ends[1] > limit {
+            self.grow();
+        }
+        let mut node = 1;
+        while node < self.capacity {
+            node = if self.ends[2 * node] <= limit {
+                2 * node
+            } else {
+                2 * node + 1
+            };
+        }
+        node - self.capacity
+    }
+
+    /// Extends the row with the given index to end at least at the given position.
+    fn extend(&mut self, row: usize, end: i64) {
+        let mut node = row + self.capacity;
+        if self.ends[node] >= end {
+            return;
+        }
+        self.ends[node] = end;
+        while node > 1 {
+            node /= 2;
+            self.ends[node] = min(self.ends[2 * node], self.ends[2 * node + 1]);
+        }
+    }
+
+    /// Doubles the number of rows.
+    fn grow(&mut self) {
+        let capacity = 2 * self.capacity;
+        let mut ends = vec![i64::MIN; 2 * capacity];
+        ends[capacity..capacity + self.capacity].copy_from_slice(&self.ends[self.capacity..]);
+        for node in (1..capacity).rev() {
+            ends[node] = min(ends[2 * node], ends[2 * node + 1]);
+        }
+        *self = RowEnds { capacity, ends };
+    }
+}
+
+/// Assigns the given reads rows starting at 1 such that mates share a row and reads of a row are
+/// at least `gap` bases apart. Each read is placed into the first row it fits into. Returns the
+/// number of used rows.
+fn assign_rows(reads: &mut [Read], gap: i64) -> usize {
+    let mut row_ends = RowEnds::new();
+    let mut template_rows = HashMap::new();
+    for read in reads.iter_mut() {
+        let row = match template_rows.get(&read.name) {
+            Some(row) => *row,
+            None => {
+                // Reads with a mate reserve the row up to their mate, so that both can be linked.
+                let limit = if read.mpos <= -1 {
+                    read.position - gap
+                } else {
+                    min(read.position, read.mpos) - gap - 1
+                };
+                let row = row_ends.first_fit(limit);
+                template_rows.insert(read.name.clone(), row);
+                row
+            }
+        };
+        row_ends.extend(row, max(read.end_position, read.mpos));
+        read.set_row(row as u32 + 1);
     }
+    template_rows.values().max().map_or(0, |row| row + 1)
+}
 
-    Ok(record)
+/// Assigns the given reads rows starting at 1 and subsamples them to at most `max_rows` rows,
+/// following the given strategy. Reads of a row are at least `gap` bases apart. Returns the number of used rows before subsampling.
+fn subsample(
+    reads: &mut Vec<Read>,
+    max_rows: usize,
+    downsampling: &Downsampling,
+    gap: i64,
+    rng: &mut StdRng,
+) -> usize {
+    let used_rows = assign_rows(reads, gap);
+    if used_rows <= max_rows {
+        return used_rows;
+    }
+    let mut rows = used_rows;
+    if downsampling.strategy != DownsampleStrategy::Rows {
+        // Keep a share of the templates that roughly fits into the rows and pack them again.
+        let templates = downsampling.rank_templates(reads, rng);
+        let kept = (templates.len() * max_rows).div_ceil(used_rows);
+        let kept: HashSet<String> = templates.into_iter().take(kept).collect();
+        reads.retain(|read| kept.contains(&read.name));
+        rows = assign_rows(reads, gap);
+    }
+    if rows > max_rows {
+        let supported: HashSet<u32> = match downsampling.strategy {
+            DownsampleStrategy::PreferVariantSupport => reads
+                .iter()
+                .filter(|read| read.carries_variant(&downsampling.sites))
+                .filter_map(|read| read.row)
+                .collect(),
+            _ => HashSet::new(),
+        };
+        let mut ranked = (1..=rows as u32).collect_vec();
+        ranked.shuffle(rng);
+        ranked.sort_by_key(|row| !supported.contains(row));
+        let kept: HashSet<u32> = ranked.into_iter().take(max_rows).collect();
+        reads.retain(|read| read.row.is_some_and(|row| kept.contains(&row)));
+    }
+    used_rows
 }
 
-impl Read {
-    /// Creates a Read from a given rust_htslib bam record
-    fn from_record<P: AsRef<Path> + std::fmt::Debug>(
-        record: rust_htslib::bam::record::Record,
-        ref_path: P,
-        aux_tags: &Option<Vec<String>>,
-        region: &Region,
-        clamp: bool,
-    ) -> Result<Option<Read>> {
-        let read_seq = record
-            .seq()
-            .as_bytes()
-            .iter()
-            .map(|u| char::from(*u))
-            .collect_vec();
-        if read_seq.is_empty() && record.cigar().iter().next().is_some() {
-            warn!(
-                "Skipping read '{}': empty sequence but non-empty CIGAR string ('{}').",
-                String::from_utf8_lossy(record.qname()),
-                record.cigar(),
-            );
-            return Ok(None);
-        }
-        let ref_length = get_fasta_length(&ref_path.as_ref().to_path_buf(), &region.target)?;
-        let read_start = record.pos() - record.cigar().leading_softclips();
-        let read_end = record.reference_end() + record.cigar().trailing_softclips();
+/// Keeps a sample of reads added in the order of their position, bounded by the rows they can be
+/// shown in. The reads are collected in bins of reads that all overlap each other, i.e. a bin ends
+/// with the first read ending in it. Of the templates starting in a bin only the `capacity` ones
+/// with the lowest priority are kept, as the others could not be shown anyway. The priority is
+/// drawn once per template, such that templates are kept or dropped as a whole. It is a hash of
+/// the read name with [`DownsampleStrategy::ByNameHash`] and drawn from the seeded random number
+/// generator otherwise, while templates supporting a variant at a highlighted site come first
+/// with [`DownsampleStrategy::PreferVariantSupport`]. If reads are sorted by the base at a position
+/// ([`SortBy::Base`]), templates with a base differing from the reference or a deletion there
+/// come before all others, as they are sorted into the top rows.
+pub(crate) struct ReadReservoir<'a> {
+    capacity: usize,
+    downsampling: &'a Downsampling,
+    /// Whether the reads are sorted by the base at a position, given by their sort key.
+    base_sort: bool,
+    /// The position before which all reads of the current bin end.
+    bin_end: i64,
+    /// The priorities and names of the templates starting in the current bin, the highest on top.
+    bin: BinaryHeap<((bool, bool, u64), String)>,
+    /// The reads of the kept templates by name, together with the order they were added in.
+    kept: HashMap<String, Vec<(usize, Read)>>,
+    /// The names of the dropped templates whose mates are still to come, with the mate position.
+    dropped: HashMap<String, i64>,
+    added: usize,
+    rng: StdRng,
+}
 
-        let lower_bound = if clamp { region.start } else { 0 };
-        let upper_bound = if clamp { region.end } else { ref_length as i64 };
+impl<'a> ReadReservoir<'a> {
+    pub(crate) fn new(
+        capacity: usize,
+        downsampling: &'a Downsampling,
+        sort_by: Option<&SortBy>,
+    ) -> Self {
+        ReadReservoir {
+            capacity,
+            downsampling,
+            base_sort: matches!(sort_by, Some(SortBy::Base { .. })),
+            bin_end: i64::MIN,
+            bin: BinaryHeap::new(),
+            kept: HashMap::new(),
+            dropped: HashMap::new(),
+            added: 0,
+            rng: StdRng::seed_from_u64(downsampling.seed),
+        }
+    }
 
-        let record = if read_start < lower_bound || read_end > upper_bound {
-            clip_read(record, lower_bound, upper_bound)?
-        } else {
-            record
-        };
-        // Re-read the sequence from the (possibly clipped) record so it stays in sync with the
-        // clipped CIGAR and reference window below. Otherwise `from_cigar` would match the wrong
-        // read bases against the reference and report spurious mismatches.
-        let read_seq = record
-            .seq()
-            .as_bytes()
-            .iter()
-            .map(|u| char::from(*u))
-            .collect_vec();
-        let region = cli::Region {
-            target: region.target.clone(),
-            start: record.pos() - record.cigar().leading_softclips(),
-            end: record.reference_end() + record.cigar().trailing_softclips(),
-        };
-        let ref_seq = read_fasta(ref_path, &region)?;
-        let mpos = if record.is_paired() {
-            record.mpos()
-        } else {
-            -1
-        };
-        Ok(Some(Read {
-            name: String::from_utf8(record.qname().to_vec())?,
-            cigar: PlotCigar::from_cigar(record.cigar(), read_seq, ref_seq)?,
-            position: record.pos() - record.cigar().leading_softclips(),
-            flags: record.flags(),
-            mapq: record.mapq(),
-            row: None,
-            end_position: record.reference_end(),
-            mpos,
-            aux: AuxRecord::new(&record, aux_tags),
-            raw_cigar: record.cigar().to_string(),
-        }))
+    pub(crate) fn add(&mut self, read: Read) {
+        let index = self.added;
+        self.added += 1;
+        if let Some(reads) = self.kept.get_mut(&read.name) {
+            reads.push((index, read));
+            return;
+        }
+        if self.dropped.contains_key(&read.name) {
+            return;
+        }
+        if read.position >= self.bin_end {
+            self.bin.clear();
+            self.bin_end = i64::MAX;
+            self.dropped
+                .retain(|_, mate_position| *mate_position >= read.position);
+        }
+        self.bin_end = self.bin_end.min(read.end_position);
+        // Sort keys of a base sort rank bases differing from the reference 0 and deletions 1.
+        let priority = (
+            self.base_sort && !read.sort_key.as_ref().is_some_and(|(rank, _)| *rank < 2),
+            self.downsampling.strategy == DownsampleStrategy::PreferVariantSupport
+                && !read.carries_variant(&self.downsampling.sites),
+            match self.downsampling.strategy {
+                DownsampleStrategy::ByNameHash => name_hash(&read.name, self.downsampling.seed),
+                _ => self.rng.random(),
+            },
+        );
+        if self.bin.len() >= self.capacity {
+            if self
+                .bin
+                .peek()
+                .is_none_or(|(highest, _)| *highest <= priority)
+            {
+                self.discard(&read);
+                return;
+            }
+            let (_, name) = self.bin.pop().unwrap();
+            for (_, read) in self.kept.remove(&name).unwrap_or_default() {
+                self.discard(&read);
+            }
+        }
+        self.bin.push((priority, read.name.clone()));
+        self.kept.insert(read.name.clone(), vec![(index, read)]);
     }
 
-    /// Sets the row of the Read
-    fn set_row(&mut self, row: u32) {
-        self.row = Some(row);
+    /// Remembers the template of the given read as dropped until its mate has been passed.
+    fn discard(&mut self, read: &Read) {
+        if read.mate_target.is_none() && read.mpos >= read.position {
+            let mate_position = self.dropped.entry(read.name.clone()).or_insert(read.mpos);
+            *mate_position = (*mate_position).max(read.mpos);
+        }
     }
-}
 
-pub trait PlotOrder {
-    fn order(&mut self, max_read_depth: usize) -> Result<()>;
+    /// Returns the kept reads in the order they were added in.
+    pub(crate) fn finish(self) -> Vec<Read> {
+        self.kept
+            .into_values()
+            .flatten()
+            .sorted_by_key(|(index, _)| *index)
+            .map(|(_, read)| read)
+            .collect()
+    }
 }
 
 impl PlotOrder for Vec<Read> {
-    /// Assigns given Reads their vertical position (row) in the read plot respecting the given max_read_depth by subsampling rows.
-    fn order(&mut self, max_read_depth: usize) -> Result<()> {
-        let mut row_ends = vec![0; 2];
-        let mut ordered_reads = HashMap::new();
-        for read in self.iter_mut() {
-            if let Some(row) = ordered_reads.get(&read.name) {
-                read.set_row(*row as u32);
-                if row_ends[*row] < read.end_position {
-                    row_ends[*row] = read.end_position;
-                }
-                continue;
-            }
-            for (row, row_end) in row_ends.iter().enumerate().skip(1) {
-                if min(read.position, read.mpos) > *row_end + 5
-                    || (read.mpos <= -1 && read.position >= *row_end + 5) // Read has no mate and can be placed purely dependent on its own position
-                    || *row_end == 0
-                // No previous rows fit the read. New row is unfilled and read can be placed at the beginning
-                {
-                    read.set_row(row as u32);
-                    row_ends[row] = max(read.end_position, read.mpos);
-                    ordered_reads.insert(&read.name, row);
-                    // We placed a read in the last row available so all rows seem to be filled so we append a new empty row for the next read
-                    if row == row_ends.len() - 1 {
-                        row_ends.push(0)
-                    }
-                    break;
-                }
+    /// Assigns given Reads their vertical position (row) in the read plot respecting the given max_read_depth by subsampling
+    /// them with the given strategy. Reads are packed as densely as the given display mode demands and in the order of their
+    /// sort keys, if any, so that the first ones end up in the top rows.
+    /// Grouped reads are stacked into separate blocks, ordered by group, each preceded by an empty row holding the group label.
+    fn order(
+        &mut self,
+        max_read_depth: usize,
+        downsampling: &Downsampling,
+        display_mode: DisplayMode,
+    ) -> Result<()> {
+        let gap = display_mode.gap();
+        let mut rng = StdRng::seed_from_u64(downsampling.seed);
+        sort_reads(self);
+        if self.iter().all(|read| read.group.is_none()) {
+            subsample(self, max_read_depth, downsampling, gap, &mut rng);
+            return Ok(());
+        }
+
+        let mut groups: Vec<(String, Vec<Read>)> = Vec::new();
+        for read in self.drain(..) {
+            let group = read.group.clone().unwrap_or_default();
+            match groups.iter_mut().find(|(name, _)| *name == group) {
+                Some((_, reads)) => reads.push(read),
+                None => groups.push((group, vec![read])),
             }
         }
-        if let Some(used_rows) = ordered_reads.values().max() {
-            if max_read_depth < *used_rows {
-                let mut rng = StdRng::seed_from_u64(42);
-                let random_rows: HashSet<_> = (0..*used_rows as u32)
-                    .sample(&mut rng, max_read_depth)
-                    .into_iter()
-                    .collect();
-                self.retain(|read| random_rows.contains(&read.row.unwrap()));
+        // Reads without a value for the grouping attribute are shown last.
+        groups.sort_by_key(|(name, _)| (name.ends_with(": None"), name.clone()));
+
+        let used_rows = groups
+            .iter_mut()
+            .map(|(_, reads)| assign_rows(reads, gap))
+            .collect_vec();
+        let total_rows: usize = used_rows.iter().sum();
+        let mut offset = 0;
+        for ((_, mut reads), used) in groups.into_iter().zip(used_rows) {
+            if max_read_depth < total_rows {
+                let kept = max(1, used * max_read_depth / total_rows);
+                subsample(&mut reads, kept, downsampling, gap, &mut rng);
             }
+            // Renumber the remaining rows compactly so that each block directly follows its label row.
+            let rows: HashMap<u32, u32> = reads
+                .iter()
+                .map(|read| read.row.unwrap())
+                .sorted()
+                .dedup()
+                .enumerate()
+                .map(|(i, row)| (row, offset + i as u32 + 2))
+                .collect();
+            for read in reads.iter_mut() {
+                read.set_row(rows[&read.row.unwrap()]);
+            }
+            offset += rows.len() as u32 + 1;
+            self.append(&mut reads);
         }
         Ok(())
     }
@@ -805,17 +3138,22 @@ impl PlotOrder for Vec<Read> {
 
 #[cfg(test)]
 mod tests {
-    use crate::cli::Region;
-    use crate::create_plot_data;
+    use crate::cli::{Interval, Region};
+    use crate::filter::ReadFilter;
+    use crate::plot::create_plot_data;
     use crate::plot::CigarType::{Del, Ins, Match, Sub};
     use crate::plot::{
-        match_bases, read_fasta, AuxRecord, CigarType, Coverage, EncodedRead, InnerPlotCigar,
-        PlotCigar, PlotOrder, Read, Reference,
+        assign_rows, classify_pairs, convert_chunks, fisher_exact, match_bases,
+        mate_overlap_qualities, partner_region, read_fasta, reference_from_md, AuxRecord,
+        BaseCoverage, BaseModification, CigarType, Coverage, DisplayMode, DownsampleStrategy,
+        Downsampling, EncodedRead, InnerPlotCigar, Junction, JunctionCounter, ModificationCounter,
+        PlotCigar, PlotData, PlotDataOptions, PlotOrder, Read, ReadAttribute, ReadReservoir,
+        Reference, ReferenceWindow, SortBy, StrandBias, SupplementaryAlignment,
     };
     use crate::utils::get_fasta_length;
     use itertools::Itertools;
-    use rust_htslib::bam;
     use rust_htslib::bam::record::{Aux, Cigar, CigarString, CigarStringView};
+    use rust_htslib::{bam, faidx};
     use std::collections::HashMap;
     use std::path::PathBuf;
     use std::str::FromStr;
@@ -827,31 +3165,37 @@ mod tests {
                 cigar_type: Match,
                 bases: None,
                 length: Some(50),
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: Del,
                 bases: None,
                 length: Some(3),
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: Match,
                 bases: None,
                 length: Some(10),
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: Sub,
                 bases: Some(vec!['C']),
                 length: Some(1),
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: Sub,
                 bases: Some(vec!['G']),
                 length: Some(1),
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: Ins,
                 bases: Some(vec!['G', 'G', 'T']),
                 length: None,
+                qualities: None,
             },
         ]);
         let expected_string = "50=|3d|10=|1C|1G|iGGT".to_string();
@@ -859,104 +3203,762 @@ mod tests {
     }
 
     #[test]
-    fn test_read_ordering() {
-        let read1 = Read {
-            name: "read1".to_string(),
-            cigar: PlotCigar(vec![]),
-            position: 20,
-            flags: 0,
-            mapq: 0,
-            row: None,
-            end_position: 120,
-            mpos: 100,
-            aux: AuxRecord(HashMap::new()),
-            raw_cigar: "100M".to_string(),
+    fn test_read_ordering() {
+        let read1 = Read {
+            name: "read1".to_string(),
+            cigar: PlotCigar(vec![]),
+            position: 20,
+            flags: 0,
+            mapq: 0,
+            row: None,
+            end_position: 120,
+            mpos: 100,
+            aux: AuxRecord(HashMap::new()),
+            raw_cigar: "100M".to_string(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+
+        let read2 = Read {
+            name: "read2".to_string(),
+            cigar: PlotCigar(vec![]),
+            position: 40,
+            flags: 0,
+            mapq: 0,
+            row: None,
+            end_position: 140,
+            mpos: 120,
+            aux: AuxRecord(HashMap::new()),
+            raw_cigar: "100M".to_string(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+
+        let mut reads = vec![read1, read2];
+        reads
+            .order(100, &Downsampling::default(), DisplayMode::default())
+            .unwrap();
+        assert_ne!(reads.first().unwrap().row, reads.last().unwrap().row);
+    }
+
+    #[test]
+    fn test_read_ordering_with_max_read_depth() {
+        let read1 = Read {
+            name: "read1".to_string(),
+            cigar: PlotCigar(vec![]),
+            position: 20,
+            flags: 0,
+            mapq: 0,
+            row: None,
+            end_position: 120,
+            mpos: 100,
+            aux: AuxRecord(HashMap::new()),
+            raw_cigar: "100M".to_string(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+
+        let read2 = Read {
+            name: "read2".to_string(),
+            cigar: PlotCigar(vec![]),
+            position: 40,
+            flags: 0,
+            mapq: 0,
+            row: None,
+            end_position: 140,
+            mpos: 120,
+            aux: AuxRecord(HashMap::new()),
+            raw_cigar: "100M".to_string(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+
+        let read3 = Read {
+            name: "read3".to_string(),
+            cigar: PlotCigar(vec![]),
+            position: 50,
+            flags: 0,
+            mapq: 0,
+            row: None,
+            end_position: 150,
+            mpos: 140,
+            aux: AuxRecord(HashMap::new()),
+            raw_cigar: "100M".to_string(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+
+        let mut reads = vec![read1, read2, read3];
+        reads
+            .order(2, &Downsampling::default(), DisplayMode::default())
+            .unwrap();
+        assert_eq!(reads.len(), 2);
+    }
+
+    fn grouped_read(name: &str, position: i64, group: &str) -> Read {
+        Read {
+            name: name.to_string(),
+            cigar: PlotCigar(vec![]),
+            position,
+            flags: 0,
+            mapq: 60,
+            row: None,
+            end_position: position + 100,
+            mpos: -1,
+            aux: AuxRecord(HashMap::new()),
+            raw_cigar: "100M".to_string(),
+            group: Some(group.to_string()),
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        }
+    }
+
+    #[test]
+    fn test_grouped_read_ordering() {
+        let mut reads = vec![
+            grouped_read("a", 0, "HP: None"),
+            grouped_read("b", 0, "HP: 2"),
+            grouped_read("c", 0, "HP: 1"),
+            grouped_read("d", 50, "HP: 1"),
+            grouped_read("e", 500, "HP: 1"),
+        ];
+        reads
+            .order(100, &Downsampling::default(), DisplayMode::default())
+            .unwrap();
+        let rows = reads
+            .iter()
+            .map(|read| (read.name.as_str(), read.row.unwrap()))
+            .collect::<HashMap<_, _>>();
+        // Every block starts with an empty label row, reads without a value come last.
+        assert_eq!(rows["c"], 2);
+        assert_eq!(rows["d"], 3);
+        assert_eq!(rows["e"], 2);
+        assert_eq!(rows["b"], 5);
+        assert_eq!(rows["a"], 7);
+    }
+
+    #[test]
+    fn test_grouped_read_ordering_with_max_read_depth() {
+        let mut reads = (0..10)
+            .map(|i| {
+                grouped_read(
+                    &format!("read{i}"),
+                    0,
+                    if i < 8 { "strand: +" } else { "strand: -" },
+                )
+            })
+            .collect_vec();
+        reads
+            .order(5, &Downsampling::default(), DisplayMode::default())
+            .unwrap();
+        let plus = reads
+            .iter()
+            .filter(|read| read.group.as_deref() == Some("strand: +"))
+            .map(|read| read.row.unwrap())
+            .sorted()
+            .collect_vec();
+        let minus = reads
+            .iter()
+            .filter(|read| read.group.as_deref() == Some("strand: -"))
+            .map(|read| read.row.unwrap())
+            .collect_vec();
+        assert_eq!(plus, vec![2, 3, 4, 5]);
+        assert_eq!(minus, vec![7]);
+    }
+
+    fn stacked_reads(n: usize) -> Vec<Read> {
+        (0..n)
+            .map(|i| Read {
+                group: None,
+                ..grouped_read(&format!("read{i}"), 0, "")
+            })
+            .collect_vec()
+    }
+
+    fn kept_names(reads: &[Read]) -> Vec<String> {
+        reads
+            .iter()
+            .map(|read| read.name.clone())
+            .sorted()
+            .collect_vec()
+    }
+
+    #[test]
+    fn test_downsample_strategy_from_str() {
+        for name in ["rows", "reads", "by-name-hash", "prefer-variant-support"] {
+            assert_eq!(
+                DownsampleStrategy::from_str(name).unwrap().to_string(),
+                name
+            );
+        }
+        assert!(DownsampleStrategy::from_str("random").is_err());
+    }
+
+    #[test]
+    fn test_prefer_variant_support_downsampling() {
+        let mut reads = stacked_reads(20);
+        for i in [7, 13] {
+            reads[i].cigar = PlotCigar(vec![
+                InnerPlotCigar {
+                    cigar_type: CigarType::Match,
+                    bases: None,
+                    length: Some(50),
+                    qualities: None,
+                },
+                InnerPlotCigar {
+                    cigar_type: CigarType::Sub,
+                    bases: Some(vec!['A']),
+                    length: Some(1),
+                    qualities: None,
+                },
+                InnerPlotCigar {
+                    cigar_type: CigarType::Match,
+                    bases: None,
+                    length: Some(49),
+                    qualities: None,
+                },
+            ]);
+        }
+        let downsampling = Downsampling {
+            strategy: DownsampleStrategy::PreferVariantSupport,
+            sites: vec![50..51],
+            ..Downsampling::default()
+        };
+        reads
+            .order(3, &downsampling, DisplayMode::default())
+            .unwrap();
+        let names = kept_names(&reads);
+        assert_eq!(names.len(), 3);
+        assert!(names.contains(&"read7".to_string()));
+        assert!(names.contains(&"read13".to_string()));
+    }
+
+    #[test]
+    fn test_by_name_hash_downsampling() {
+        let downsampling = Downsampling {
+            strategy: DownsampleStrategy::ByNameHash,
+            ..Downsampling::default()
+        };
+        let mut reads = stacked_reads(20);
+        let mut reversed = stacked_reads(20).into_iter().rev().collect_vec();
+        reads
+            .order(5, &downsampling, DisplayMode::default())
+            .unwrap();
+        reversed
+            .order(5, &downsampling, DisplayMode::default())
+            .unwrap();
+        // The kept reads only depend on their names, not on the order they were fetched in.
+        assert_eq!(kept_names(&reads).len(), 5);
+        assert_eq!(kept_names(&reads), kept_names(&reversed));
+    }
+
+    #[test]
+    fn test_downsampling_seed() {
+        let downsampled = |seed| {
+            let mut reads = stacked_reads(20);
+            let downsampling = Downsampling {
+                strategy: DownsampleStrategy::Reads,
+                seed,
+                ..Downsampling::default()
+            };
+            reads
+                .order(5, &downsampling, DisplayMode::default())
+                .unwrap();
+            kept_names(&reads)
+        };
+        assert_eq!(downsampled(1), downsampled(1));
+        assert_ne!(downsampled(1), downsampled(2));
+    }
+
+    #[test]
+    fn test_read_reservoir() {
+        let pairs = |reversed: bool| {
+            let mut reads = stacked_reads(1000);
+            if reversed {
+                reads.reverse();
+            }
+            let mates = reads
+                .iter()
+                .map(|read| Read {
+                    position: 500,
+                    end_position: 600,
+                    mpos: 0,
+                    ..read.clone()
+                })
+                .collect_vec();
+            for read in reads.iter_mut() {
+                read.flags = 1;
+                read.mpos = 500;
+            }
+            reads.extend(mates);
+            reads.push(grouped_read("after", 1000, ""));
+            reads
+        };
+        let downsampling = Downsampling {
+            strategy: DownsampleStrategy::ByNameHash,
+            ..Default::default()
+        };
+        let sampled = |reads: Vec<Read>| {
+            let mut reservoir = ReadReservoir::new(10, &downsampling, None);
+            for read in reads {
+                reservoir.add(read);
+            }
+            // Dropped templates are forgotten once their mates have been passed.
+            assert!(reservoir.dropped.is_empty());
+            reservoir.finish()
+        };
+        let kept = sampled(pairs(false));
+        // Ten templates are kept together with their mates, in the order they were added in.
+        assert_eq!(kept.len(), 21);
+        assert!(kept.is_sorted_by_key(|read| read.position));
+        assert_eq!(
+            kept.iter()
+                .counts_by(|read| read.name.as_str())
+                .into_values()
+                .max(),
+            Some(2)
+        );
+        // Templates are kept by the hash of their name, regardless of the order they come in.
+        assert_eq!(kept_names(&kept), kept_names(&sampled(pairs(true))));
+    }
+
+    #[test]
+    fn test_read_reservoir_strategies() {
+        let sampled = |strategy: DownsampleStrategy| {
+            let downsampling = Downsampling {
+                strategy,
+                ..Default::default()
+            };
+            let mut reservoir = ReadReservoir::new(10, &downsampling, None);
+            for read in stacked_reads(1000) {
+                reservoir.add(read);
+            }
+            kept_names(&reservoir.finish())
+        };
+        let by_name_hash = sampled(DownsampleStrategy::ByNameHash);
+        assert_eq!(by_name_hash.len(), 10);
+        for strategy in [DownsampleStrategy::Rows, DownsampleStrategy::Reads] {
+            let kept = sampled(strategy);
+            assert_eq!(kept.len(), 10);
+            assert_ne!(kept, by_name_hash);
+            // The random choice is reproducible with the same seed.
+            assert_eq!(kept, sampled(strategy));
+        }
+    }
+
+    #[test]
+    fn test_read_reservoir_base_sort() {
+        let downsampling = Downsampling {
+            strategy: DownsampleStrategy::Reads,
+            ..Default::default()
+        };
+        let sort_by = SortBy::from_str("base@1:1").unwrap();
+        let sampled = |sort_by: Option<&SortBy>| {
+            let mut reservoir = ReadReservoir::new(10, &downsampling, sort_by);
+            for (i, mut read) in stacked_reads(1000).into_iter().enumerate() {
+                read.sort_key = Some(match i % 200 {
+                    0 => (0, "A".to_string()),
+                    100 => (1, String::new()),
+                    _ => (2, String::new()),
+                });
+                reservoir.add(read);
+            }
+            kept_names(&reservoir.finish())
+        };
+        let alternative = (0..1000)
+            .step_by(100)
+            .map(|i| format!("read{i}"))
+            .sorted()
+            .collect_vec();
+        // All reads with a base differing from the reference or a deletion at the sorted position
+        // are kept, as they are shown in the top rows.
+        assert_eq!(sampled(Some(&sort_by)), alternative);
+        assert_ne!(sampled(None), alternative);
+        assert_ne!(sampled(Some(&SortBy::Mapq)), alternative);
+    }
+
+    #[test]
+    fn test_streamed_plot_data() {
+        let region = Region {
+            target: "1".to_string(),
+            start: 0,
+            end: 2411,
+        };
+        let fasta = faidx::Reader::from_path("tests/sample_3/ref.fa").unwrap();
+        let plot = |max_read_depth| {
+            create_plot_data(
+                &mut bam::IndexedReader::from_path("tests/sample_3/NA12878.bam").unwrap(),
+                Some(&fasta),
+                &region,
+                &PlotDataOptions {
+                    max_read_depth,
+                    mismatch_display_min_percent: 0.0,
+                    dedup_mate_overlap: true,
+                    ..Default::default()
+                },
+                "NA12878".to_string(),
+            )
+            .unwrap()
+        };
+        let PlotData {
+            total_reads: total,
+            coverage,
+            retained_reads: retained,
+            ..
+        } = plot(500);
+        let PlotData {
+            total_reads: sampled_total,
+            coverage: sampled_coverage,
+            retained_reads: sampled_retained,
+            ..
+        } = plot(2);
+        // The coverage is counted from all reads, even if only a few of them are kept.
+        assert_eq!(sampled_total, total);
+        assert_eq!(sampled_coverage, coverage);
+        assert!(sampled_retained < retained);
+    }
+
+    #[test]
+    fn test_display_mode_from_str() {
+        for name in ["expanded", "squished", "collapsed"] {
+            assert_eq!(DisplayMode::from_str(name).unwrap().to_string(), name);
+        }
+        assert!(DisplayMode::from_str("dense").is_err());
+    }
+
+    #[test]
+    fn test_assign_rows() {
+        let mut reads = stacked_reads(100);
+        reads.push(grouped_read("after", 300, ""));
+        assert_eq!(assign_rows(&mut reads, 5), 100);
+        // Reads are placed into the first row they fit into.
+        assert_eq!(reads.last().unwrap().row, Some(1));
+    }
+
+    #[test]
+    fn test_assign_rows_display_mode() {
+        let rows = |display_mode: DisplayMode| {
+            let mut reads = vec![grouped_read("a", 0, ""), grouped_read("b", 101, "")];
+            assign_rows(&mut reads, display_mode.gap())
         };
+        assert_eq!(rows(DisplayMode::Expanded), 2);
+        assert_eq!(rows(DisplayMode::Squished), 1);
+        assert_eq!(rows(DisplayMode::Collapsed), 1);
+    }
 
-        let read2 = Read {
-            name: "read2".to_string(),
-            cigar: PlotCigar(vec![]),
-            position: 40,
-            flags: 0,
-            mapq: 0,
-            row: None,
-            end_position: 140,
-            mpos: 120,
-            aux: AuxRecord(HashMap::new()),
-            raw_cigar: "100M".to_string(),
+    #[test]
+    fn test_assign_rows_soft_clips() {
+        let region = Region {
+            target: "chr1".to_string(),
+            start: 0,
+            end: 100,
+        };
+        let read = |name: &[u8], position: i64, cigar: Vec<Cigar>, show_soft_clips: bool| {
+            let mut record = bam::Record::new();
+            record.set(name, Some(&CigarString(cigar)), &[b'A'; 15], &[30; 15]);
+            record.set_pos(position);
+            Read::from_record(
+                record,
+                vec!['A'; 15],
+                100,
+                &None,
+                &region,
+                false,
+                show_soft_clips,
+                None,
+                None,
+            )
+            .unwrap()
+            .unwrap()
+        };
+        let rows = |start: i64, show_soft_clips: bool| {
+            let mut reads = vec![
+                read(
+                    b"clipped",
+                    10,
+                    vec![Cigar::Match(10), Cigar::SoftClip(5)],
+                    show_soft_clips,
+                ),
+                read(b"next", start, vec![Cigar::Match(15)], show_soft_clips),
+            ];
+            assign_rows(&mut reads, DisplayMode::Collapsed.gap())
         };
+        // The shown clipped tail ends at 25, so only a read starting there may share its row.
+        assert_eq!(rows(25, true), 1);
+        assert_eq!(rows(24, true), 2);
+        assert_eq!(rows(24, false), 1);
+    }
 
-        let mut reads = vec![read1, read2];
-        reads.order(100).unwrap();
-        assert_ne!(reads.first().unwrap().row, reads.last().unwrap().row);
+    #[test]
+    fn test_sort_by_from_str() {
+        assert_eq!(
+            SortBy::from_str("base@chr1:12345").unwrap(),
+            SortBy::Base {
+                target: "chr1".to_string(),
+                position: 12344
+            }
+        );
+        for name in ["base@chr1:12345", "strand", "mapq", "insert-size", "tag:HP"] {
+            assert_eq!(SortBy::from_str(name).unwrap().to_string(), name);
+        }
+        assert_eq!(
+            SortBy::from_str("tag:HP").unwrap(),
+            SortBy::Tag("HP".to_string())
+        );
+        for invalid in ["base@chr1:0", "base@chr1", "tag:H", "position"] {
+            assert!(SortBy::from_str(invalid).is_err());
+        }
     }
 
     #[test]
-    fn test_read_ordering_with_max_read_depth() {
-        let read1 = Read {
-            name: "read1".to_string(),
-            cigar: PlotCigar(vec![]),
-            position: 20,
-            flags: 0,
-            mapq: 0,
-            row: None,
-            end_position: 120,
-            mpos: 100,
-            aux: AuxRecord(HashMap::new()),
-            raw_cigar: "100M".to_string(),
+    fn test_sort_key() {
+        let mut record = bam::Record::new();
+        record.set(
+            b"read",
+            Some(&CigarString(vec![
+                Cigar::SoftClip(2),
+                Cigar::Match(3),
+                Cigar::Ins(1),
+                Cigar::Match(2),
+                Cigar::Del(2),
+                Cigar::Match(1),
+            ])),
+            b"TTACGGATC",
+            &[30; 9],
+        );
+        record.set_pos(10);
+        record.set_mapq(60);
+        record.push_aux(b"HP", Aux::I32(1)).unwrap();
+        let ref_seq = "ACGAGCCC".chars().collect_vec();
+        let key = |sort_by: &str| {
+            SortBy::from_str(sort_by)
+                .unwrap()
+                .key(&record, &ref_seq, "chr1")
         };
+        assert_eq!(key("base@chr1:15"), Some((0, "T".to_string())));
+        assert_eq!(key("base@chr1:14"), Some((2, String::new())));
+        assert_eq!(key("base@chr1:16"), Some((1, String::new())));
+        assert_eq!(key("base@chr1:30"), None);
+        assert_eq!(key("base@chr2:15"), None);
+        assert_eq!(key("strand"), Some((0, String::new())));
+        assert_eq!(key("mapq"), Some((-60, String::new())));
+        assert_eq!(key("tag:HP"), Some((0, "1".to_string())));
+        assert_eq!(key("tag:PS"), None);
+    }
 
-        let read2 = Read {
-            name: "read2".to_string(),
-            cigar: PlotCigar(vec![]),
-            position: 40,
-            flags: 0,
-            mapq: 0,
-            row: None,
-            end_position: 140,
-            mpos: 120,
-            aux: AuxRecord(HashMap::new()),
-            raw_cigar: "100M".to_string(),
-        };
+    #[test]
+    fn test_sorted_read_ordering() {
+        let mut reads = stacked_reads(4);
+        reads[2].sort_key = Some((0, "T".to_string()));
+        reads[3].sort_key = Some((2, String::new()));
+        // The mate of the first read carries the alternative base.
+        reads.push(Read {
+            name: "read0".to_string(),
+            sort_key: Some((0, "A".to_string())),
+            ..stacked_reads(1).remove(0)
+        });
+        reads
+            .order(100, &Downsampling::default(), DisplayMode::default())
+            .unwrap();
+        let rows = reads
+            .iter()
+            .map(|read| (read.name.as_str(), read.row.unwrap()))
+            .collect_vec();
+        assert_eq!(
+            rows,
+            vec![
+                ("read0", 1),
+                ("read0", 1),
+                ("read2", 2),
+                ("read3", 3),
+                ("read1", 4)
+            ]
+        );
+    }
 
-        let read3 = Read {
-            name: "read3".to_string(),
-            cigar: PlotCigar(vec![]),
-            position: 50,
-            flags: 0,
-            mapq: 0,
-            row: None,
-            end_position: 150,
-            mpos: 140,
-            aux: AuxRecord(HashMap::new()),
-            raw_cigar: "100M".to_string(),
+    #[test]
+    fn test_read_attribute_from_str() {
+        assert_eq!(
+            ReadAttribute::from_str("strand").unwrap(),
+            ReadAttribute::Strand
+        );
+        assert_eq!(
+            ReadAttribute::from_str("pair-orientation").unwrap(),
+            ReadAttribute::PairOrientation
+        );
+        assert_eq!(
+            ReadAttribute::from_str("HP").unwrap(),
+            ReadAttribute::Tag("HP".to_string())
+        );
+        assert_eq!(ReadAttribute::Mapq.to_string(), "mapq");
+        assert!(ReadAttribute::from_str("haplotype").is_err());
+    }
+
+    #[test]
+    fn test_create_plot_data_with_read_filter() {
+        let region = Region {
+            target: "chr6".to_string(),
+            start: 0,
+            end: 800,
+        };
+        let plot_data = |filter: &ReadFilter| {
+            create_plot_data(
+                &mut bam::IndexedReader::from_path("tests/sample_2/sample.bam").unwrap(),
+                Some(&faidx::Reader::from_path("tests/sample_2/ref.fa").unwrap()),
+                &region,
+                &PlotDataOptions {
+                    filter: filter.clone(),
+                    ..Default::default()
+                },
+                "sample_2".to_string(),
+            )
+            .unwrap()
+        };
+        let PlotData {
+            total_reads: all_reads,
+            coverage: all_coverage,
+            filtered_reads: none_filtered,
+            ..
+        } = plot_data(&ReadFilter::default());
+        assert_eq!(none_filtered, 0);
+        let PlotData {
+            reads,
+            total_reads: total,
+            coverage,
+            filtered_reads: filtered,
+            ..
+        } = plot_data(&ReadFilter {
+            exclude_flags: 0x10,
+            ..Default::default()
+        });
+        assert!(filtered > 0);
+        assert_eq!(total + filtered, all_reads);
+        assert!(reads[0]
+            .decode()
+            .unwrap()
+            .iter()
+            .all(|read| read.flags & 0x10 == 0));
+        let depth = |coverage: &Coverage| {
+            coverage
+                .decode()
+                .unwrap()
+                .iter()
+                .map(|c| c.depth())
+                .sum::<usize>()
         };
+        assert!(depth(&coverage) < depth(&all_coverage));
+    }
 
-        let mut reads = vec![read1, read2, read3];
-        reads.order(2).unwrap();
-        assert_eq!(reads.len(), 2);
+    #[test]
+    fn test_create_plot_data_grouped_by_strand() {
+        let region = Region {
+            target: "chr6".to_string(),
+            start: 0,
+            end: 800,
+        };
+        let PlotData {
+            reads,
+            total_reads: total,
+            retained_reads: retained,
+            ..
+        } = create_plot_data(
+            &mut bam::IndexedReader::from_path("tests/sample_2/sample.bam").unwrap(),
+            Some(&faidx::Reader::from_path("tests/sample_2/ref.fa").unwrap()),
+            &region,
+            &PlotDataOptions {
+                group_by: Some(ReadAttribute::Strand),
+                color_by: Some(ReadAttribute::PairOrientation),
+                ..Default::default()
+            },
+            "sample_2".to_string(),
+        )
+        .unwrap();
+        assert_eq!(total, retained);
+        let reads = reads[0].decode().unwrap();
+        assert!(reads
+            .iter()
+            .all(|read| matches!(read.group.as_deref(), Some("strand: +" | "strand: -"))));
+        assert!(reads.iter().all(|read| read.color.is_some()));
+        // Groups occupy disjoint blocks of rows.
+        let last_plus = reads
+            .iter()
+            .filter(|read| read.group.as_deref() == Some("strand: +"))
+            .filter_map(|read| read.row)
+            .max();
+        let first_minus = reads
+            .iter()
+            .filter(|read| read.group.as_deref() == Some("strand: -"))
+            .filter_map(|read| read.row)
+            .min();
+        if let (Some(last_plus), Some(first_minus)) = (last_plus, first_minus) {
+            assert!(first_minus > last_plus + 1);
+        }
     }
 
     #[test]
     fn test_matching_bases() {
         let reference = vec!['A', 'A', 'G', 'C', 'T', 'A'];
         let read = vec!['A', 'A', 'G', 'C', 'C', 'A'];
-        let inner_plot_cigars = match_bases(&read, &reference);
+        let inner_plot_cigars = match_bases(&read, None, &reference);
         let expected_inner_plot_cigars = vec![
             InnerPlotCigar {
                 cigar_type: CigarType::Match,
                 bases: None,
                 length: Some(4),
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: CigarType::Sub,
                 bases: Some(vec!['C']),
                 length: Some(1),
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: CigarType::Match,
                 bases: None,
                 length: Some(1),
+                qualities: None,
             },
         ];
         assert_eq!(inner_plot_cigars, expected_inner_plot_cigars)
@@ -969,33 +3971,52 @@ mod tests {
             start: 300,
             end: 500,
         };
-        let (reads, _reference, _, _, _) = create_plot_data(
-            "tests/sample_2/sample.bam",
-            "tests/sample_2/ref.fa",
-            &region,
-            500,
-            None,
-            0.0,
-            false,
-            "sample_2".to_string(),
-        )
-        .unwrap();
-
-        let expected_read = Read {
-            name: "HLA:HLA00318-1144".to_string(),
-            cigar: PlotCigar::from_str("1C|1=|1G|1=|1G|6=|1T|9=|1A|8=|1T|1G|1=|1T|2=|1T|4=|1G|10=|1C|1=|1C|36=|1T|16=|1T|1C|10=|1T|25=|1A|1=|1C|1=").unwrap(),
-            position: 368,
-            flags: 83,
-            mapq: 60,
-            row: Some(
-                9,
+        let aligned = "6=|1T:34|9=|1A:38|8=|1T:38|1G:38|1=|1T:38|2=|1T:38|4=|1G:23|10=|1C:34|1=|1C:38|36=|1T:41|16=|1T:38|1C:41|10=|1T:23|25=";
+        for (show_soft_clips, position, end_position, cigar) in [
+            (false, 373, 514, aligned.to_string()),
+            (
+                true,
+                368,
+                518,
+                format!("sCGGTG:23,34,34,28,34|{aligned}|sAGCG:16,34,34,34"),
             ),
-            end_position: 887,
-            mpos: 333,
-            aux: AuxRecord(HashMap::new()),
-            raw_cigar: "5S141M4S".to_string(),
-        };
-        assert!(reads[0].values.contains(&expected_read.encode()));
+        ] {
+            let PlotData { reads, .. } = create_plot_data(
+                &mut bam::IndexedReader::from_path("tests/sample_2/sample.bam").unwrap(),
+                Some(&faidx::Reader::from_path("tests/sample_2/ref.fa").unwrap()),
+                &region,
+                &PlotDataOptions {
+                    mismatch_display_min_percent: 0.0,
+                    show_soft_clips,
+                    ..Default::default()
+                },
+                "sample_2".to_string(),
+            )
+            .unwrap();
+
+            let expected_read = Read {
+                name: "HLA:HLA00318-1144".to_string(),
+                cigar: PlotCigar::from_str(&cigar).unwrap(),
+                position,
+                flags: 83,
+                mapq: 60,
+                row: Some(9),
+                end_position,
+                mpos: 333,
+                aux: AuxRecord(HashMap::new()),
+                raw_cigar: "5S141M4S".to_string(),
+                group: None,
+                color: None,
+                supplementary: Vec::new(),
+                tlen: -110,
+                mtid: 5,
+                mate_target: None,
+                modifications: Vec::new(),
+                aligned_qualities: None,
+                sort_key: None,
+            };
+            assert!(reads[0].values.contains(&expected_read.encode()));
+        }
     }
 
     #[test]
@@ -1003,22 +4024,25 @@ mod tests {
         let cigar_string = CigarStringView::new(CigarString::from(vec![Cigar::Match(10)]), 0);
         let reference = vec!['A', 'A', 'G', 'C', 'T', 'A', 'T', 'A', 'T', 'A'];
         let read = vec!['A', 'A', 'G', 'C', 'C', 'A', 'T', 'A', 'T', 'A'];
-        let cigar = PlotCigar::from_cigar(cigar_string, read, reference).unwrap();
+        let cigar = PlotCigar::from_cigar(cigar_string, read, &[], reference).unwrap();
         let expected_cigar = PlotCigar(vec![
             InnerPlotCigar {
                 cigar_type: CigarType::Match,
                 bases: None,
                 length: Some(4),
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: CigarType::Sub,
                 bases: Some(vec!['C']),
                 length: Some(1),
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: CigarType::Match,
                 bases: None,
                 length: Some(5),
+                qualities: None,
             },
         ]);
         assert_eq!(cigar, expected_cigar);
@@ -1032,22 +4056,25 @@ mod tests {
         );
         let reference = vec!['A', 'A', 'G', 'C'];
         let read = vec!['A', 'A', 'A', 'G', 'C'];
-        let cigar = PlotCigar::from_cigar(cigar_string, read, reference).unwrap();
+        let cigar = PlotCigar::from_cigar(cigar_string, read, &[], reference).unwrap();
         let expected_cigar = PlotCigar(vec![
             InnerPlotCigar {
                 cigar_type: CigarType::Match,
                 bases: None,
                 length: Some(2),
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: CigarType::Ins,
                 bases: Some(vec!['A']),
                 length: None,
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: CigarType::Match,
                 bases: None,
                 length: Some(2),
+                qualities: None,
             },
         ]);
         assert_eq!(cigar, expected_cigar);
@@ -1061,31 +4088,346 @@ mod tests {
         );
         let reference = vec!['A', 'A', 'A', 'A', 'G', 'C'];
         let read = vec!['A', 'A', 'G', 'C'];
-        let cigar = PlotCigar::from_cigar(cigar_string, read, reference).unwrap();
+        let cigar = PlotCigar::from_cigar(cigar_string, read, &[], reference).unwrap();
         let expected_cigar = PlotCigar(vec![
             InnerPlotCigar {
                 cigar_type: CigarType::Match,
                 bases: None,
                 length: Some(2),
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: CigarType::Del,
                 bases: None,
                 length: Some(2),
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: CigarType::Match,
                 bases: None,
                 length: Some(2),
+                qualities: None,
+            },
+        ]);
+        assert_eq!(cigar, expected_cigar);
+    }
+
+    #[test]
+    fn test_plot_cigar_ref_skip() {
+        let cigar_string = CigarStringView::new(
+            CigarString::from(vec![Cigar::Match(2), Cigar::RefSkip(3), Cigar::Match(2)]),
+            0,
+        );
+        let reference = vec!['A', 'A', 'T', 'T', 'T', 'G', 'C'];
+        let read = vec!['A', 'A', 'G', 'A'];
+        let cigar = PlotCigar::from_cigar(cigar_string, read, &[], reference).unwrap();
+        assert_eq!(cigar.to_string(), "2=|3n|1=|1A");
+        assert_eq!(PlotCigar::from_str("2=|3n|1=|1A").unwrap(), cigar);
+    }
+
+    #[test]
+    fn test_plot_cigar_soft_clip() {
+        let cigar_string = CigarStringView::new(
+            CigarString::from(vec![
+                Cigar::SoftClip(2),
+                Cigar::Match(3),
+                Cigar::SoftClip(1),
+            ]),
+            0,
+        );
+        let read = vec!['G', 'G', 'A', 'C', 'T', 'T'];
+        let reference = vec!['A', 'C', 'G'];
+        let qualities = [10, 20, 30, 30, 30, 40];
+        let cigar = PlotCigar::from_cigar(cigar_string, read, &qualities, reference).unwrap();
+        assert_eq!(cigar.to_string(), "sGG:10,20|2=|1T:30|sT:40");
+        assert_eq!(PlotCigar::from_str(&cigar.to_string()).unwrap(), cigar);
+        assert_eq!(cigar.reference_length(), 6);
+    }
+
+    #[test]
+    fn test_plot_cigar_all_operators() {
+        let cigar_string = CigarStringView::new(
+            CigarString::from(vec![
+                Cigar::HardClip(3),
+                Cigar::Equal(2),
+                Cigar::Diff(1),
+                Cigar::Pad(1),
+                Cigar::Ins(1),
+                Cigar::Equal(1),
+                Cigar::HardClip(2),
+            ]),
+            0,
+        );
+        let read = vec!['A', 'C', 'T', 'G', 'C'];
+        let reference = vec!['A', 'C', 'G', 'C'];
+        let qualities = [30, 30, 20, 25, 30];
+        let cigar = PlotCigar::from_cigar(cigar_string, read, &qualities, reference).unwrap();
+        assert_eq!(cigar.to_string(), "3h|2=|1T:20|1p|iG:25|1=|2h");
+        assert_eq!(PlotCigar::from_str(&cigar.to_string()).unwrap(), cigar);
+        assert_eq!(cigar.reference_length(), 4);
+    }
+
+    #[test]
+    fn test_plot_cigar_from_invalid_str() {
+        for invalid in ["", "2=|", "x=", "=", "3d|1?", "1A:x", "2=|-1n"] {
+            assert!(PlotCigar::from_str(invalid).is_err(), "{invalid}");
+        }
+        // Insertions may end with bases that are also used as operations.
+        assert_eq!(PlotCigar::from_str("iAn").unwrap().to_string(), "iAn");
+    }
+
+    #[test]
+    fn test_coverage_ignores_hard_clips_and_padding() {
+        let read = Read {
+            name: "clipped".to_string(),
+            cigar: "2h|3=|1p|1A|4h".parse().unwrap(),
+            position: 0,
+            end_position: 4,
+            flags: 0,
+            mapq: 60,
+            row: None,
+            mpos: -1,
+            aux: Default::default(),
+            raw_cigar: "2H3M1P1M4H".to_string(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+        let region = Region {
+            target: "chr1".to_owned(),
+            start: 0,
+            end: 6,
+        };
+        let coverage = Coverage::from_reads(&[read], &region, 0.0, 0, "test".to_string());
+        assert_eq!(coverage.matches, "1|0|0|-1|0|0");
+        assert_eq!(coverage.a, "4|1");
+    }
+
+    #[test]
+    fn test_coverage_counts_indels() {
+        let read = |name: &str, cigar: &str| Read {
+            name: name.to_string(),
+            cigar: cigar.parse().unwrap(),
+            position: 0,
+            end_position: 6,
+            flags: 0,
+            mapq: 60,
+            row: None,
+            mpos: -1,
+            aux: Default::default(),
+            raw_cigar: "6M".to_string(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+        let reads = vec![
+            read("deletion", "2=|2d|2="),
+            read("insertion", "3=|iGG|3="),
+            read("match", "6="),
+        ];
+        let region = Region {
+            target: "chr1".to_owned(),
+            start: 0,
+            end: 6,
+        };
+        let coverage = Coverage::from_reads(&reads, &region, 0.0, 0, "test".to_string());
+        assert_eq!(coverage.del, "3|1$4|1");
+        assert_eq!(coverage.ins, "3|1");
+        let decoded = coverage.decode().unwrap();
+        assert_eq!(decoded[2].del, 1);
+        assert_eq!(decoded[2].ins, 1);
+        assert_eq!(decoded[2].depth(), 3);
+
+        // Indels below the threshold are hidden like mismatches.
+        let coverage = Coverage::from_reads(&reads, &region, 50.0, 0, "test".to_string());
+        assert_eq!(coverage.del, "");
+        assert_eq!(coverage.ins, "");
+        assert_eq!(coverage.matches, "3|0|0|0|0|0");
+    }
+
+    #[test]
+    fn test_coverage_ignores_soft_clips() {
+        let read = Read {
+            name: "clipped".to_string(),
+            cigar: "sAA|3=|sC".parse().unwrap(),
+            position: 0,
+            end_position: 5,
+            flags: 0,
+            mapq: 60,
+            row: None,
+            mpos: -1,
+            aux: Default::default(),
+            raw_cigar: "2S3M1S".to_string(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+        let region = Region {
+            target: "chr1".to_owned(),
+            start: 0,
+            end: 6,
+        };
+        let coverage = Coverage::from_reads(&[read], &region, 0.0, 0, "test".to_string());
+        assert_eq!(coverage.matches, "0|0|1|0|0|-1");
+        assert_eq!(coverage.a, "");
+    }
+
+    #[test]
+    fn test_junctions_from_reads() {
+        let read = |name: &str, cigar: &str, position: i64| Read {
+            name: name.to_string(),
+            cigar: cigar.parse().unwrap(),
+            position,
+            end_position: position + 20,
+            flags: 0,
+            mapq: 60,
+            row: None,
+            mpos: -1,
+            aux: Default::default(),
+            raw_cigar: String::new(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+        let reads = vec![
+            read("spliced1", "6=|10n|4=", 0),
+            read("spliced2", "2=|1d|2=|10n|5=", 0),
+            read("spliced3", "2=|iA|3=|10n|2=|3n|3=", 0),
+            read("unspliced", "20=", 0),
+        ];
+        let region = Region {
+            target: "chr1".to_owned(),
+            start: 0,
+            end: 17,
+        };
+        let mut counter = JunctionCounter::default();
+        for read in &reads {
+            counter.add(read, &region);
+        }
+        let junctions = counter.finish("test".to_string());
+        let junction = |start: i64, end: i64, count: usize| Junction {
+            sample: "test".to_string(),
+            start,
+            end,
+            count,
+        };
+        assert_eq!(junctions, vec![junction(5, 15, 2), junction(6, 16, 1)]);
+    }
+
+    #[test]
+    fn test_reference_from_md() {
+        let mut record = bam::Record::new();
+        record.set(
+            b"read",
+            Some(&CigarString(vec![
+                Cigar::SoftClip(2),
+                Cigar::Match(3),
+                Cigar::Ins(1),
+                Cigar::Match(2),
+                Cigar::Del(2),
+                Cigar::Match(1),
+            ])),
+            b"TTACGGATC",
+            &[30; 9],
+        );
+        assert!(reference_from_md(&record)
+            .unwrap_err()
+            .to_string()
+            .contains("has no MD tag"));
+        record.push_aux(b"MD", Aux::String("4G0^CC1")).unwrap();
+        assert_eq!(
+            reference_from_md(&record).unwrap(),
+            "ACGAGCCC".chars().collect_vec()
+        );
+    }
+
+    #[test]
+    fn test_create_plot_data_without_reference() {
+        let region = Region {
+            target: "1".to_string(),
+            start: 300,
+            end: 400,
+        };
+        let plot = |fasta: Option<&faidx::Reader>| {
+            create_plot_data(
+                &mut bam::IndexedReader::from_path("tests/sample_3/NA12878.bam").unwrap(),
+                fasta,
+                &region,
+                &PlotDataOptions {
+                    mismatch_display_min_percent: 0.0,
+                    ..Default::default()
+                },
+                "NA12878".to_string(),
+            )
+        };
+        let PlotData {
+            reads: md_reads,
+            reference: md_reference,
+            coverage: md_coverage,
+            ..
+        } = plot(None).unwrap();
+        let fasta = faidx::Reader::from_path("tests/sample_3/ref.fa").unwrap();
+        let PlotData {
+            reads,
+            reference,
+            coverage,
+            ..
+        } = plot(Some(&fasta)).unwrap();
+        assert_eq!(md_reads, reads);
+        assert_eq!(md_coverage, coverage);
+        assert_eq!(md_reference, reference);
+    }
+
+    #[test]
+    fn test_create_plot_data_without_reference_or_md_tags() {
+        let region = Region {
+            target: "chr1".to_string(),
+            start: 0,
+            end: 20,
+        };
+        let error = create_plot_data(
+            &mut bam::IndexedReader::from_path("tests/sample_1/reads.bam").unwrap(),
+            None,
+            &region,
+            &PlotDataOptions {
+                max_read_depth: 100,
+                mismatch_display_min_percent: 0.0,
+                ..Default::default()
             },
-        ]);
-        assert_eq!(cigar, expected_cigar);
+            "sample_1".to_string(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("has no MD tag"));
     }
 
     #[test]
     fn test_fetch_reference() {
         let reference = read_fasta(
-            "tests/sample_1/reference.fa",
+            &faidx::Reader::from_path("tests/sample_1/reference.fa").unwrap(),
             &Region {
                 target: "chr1".to_string(),
                 start: 0,
@@ -1106,25 +4448,56 @@ mod tests {
         };
         let plot = |reference: &str| {
             create_plot_data(
-                "tests/sample_1/reads.bam",
-                reference,
+                &mut bam::IndexedReader::from_path("tests/sample_1/reads.bam").unwrap(),
+                Some(&faidx::Reader::from_path(reference).unwrap()),
                 &region,
-                100,
-                None,
-                0.0,
-                false,
+                &PlotDataOptions {
+                    max_read_depth: 100,
+                    mismatch_display_min_percent: 0.0,
+                    ..Default::default()
+                },
                 "sample_1".to_string(),
             )
             .unwrap()
         };
         let (_dir, gz) = crate::utils::tests::bgzipped_reference();
-        let (gz_reads, gz_reference, _, gz_coverage, _) = plot(gz.to_str().unwrap());
-        let (reads, reference, _, coverage, _) = plot("tests/sample_1/reference.fa");
+        let PlotData {
+            reads: gz_reads,
+            reference: gz_reference,
+            coverage: gz_coverage,
+            ..
+        } = plot(gz.to_str().unwrap());
+        let PlotData {
+            reads,
+            reference,
+            coverage,
+            ..
+        } = plot("tests/sample_1/reference.fa");
         assert_eq!(gz_reference, reference);
         assert_eq!(gz_reads, reads);
         assert_eq!(gz_coverage, coverage);
     }
 
+    #[test]
+    fn test_create_plot_data_unknown_target() {
+        let region = Region {
+            target: "chrX".to_string(),
+            start: 0,
+            end: 20,
+        };
+        let result = create_plot_data(
+            &mut bam::IndexedReader::from_path("tests/sample_1/reads.bam").unwrap(),
+            None,
+            &region,
+            &PlotDataOptions::default(),
+            "sample_1".to_string(),
+        );
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("does not contain given region target chrX"));
+    }
+
     #[test]
     fn test_create_plot_data() {
         let region = Region {
@@ -1132,14 +4505,22 @@ mod tests {
             start: 0,
             end: 20,
         };
-        let (reads, reference, total_reads, coverage, subsampled_reads) = create_plot_data(
-            "tests/sample_1/reads.bam",
-            "tests/sample_1/reference.fa",
+        let PlotData {
+            reads,
+            reference,
+            total_reads,
+            coverage,
+            retained_reads: subsampled_reads,
+            ..
+        } = create_plot_data(
+            &mut bam::IndexedReader::from_path("tests/sample_1/reads.bam").unwrap(),
+            Some(&faidx::Reader::from_path("tests/sample_1/reference.fa").unwrap()),
             &region,
-            100,
-            None,
-            0.0,
-            false,
+            &PlotDataOptions {
+                max_read_depth: 100,
+                mismatch_display_min_percent: 0.0,
+                ..Default::default()
+            },
             "sample_1".to_string(),
         )
         .unwrap();
@@ -1149,7 +4530,7 @@ mod tests {
         };
         let expected_read = Read {
             name: "sim_Som1-5-2_chr1_1_1acd6f".to_string(),
-            cigar: PlotCigar::from_str("16=|iAA|80=|1T|1=").unwrap(),
+            cigar: PlotCigar::from_str("16=|iAA:33,30|80=|1T:2|1=").unwrap(),
             position: 4,
             flags: 99,
             mapq: 30,
@@ -1158,12 +4539,26 @@ mod tests {
             mpos: 789264,
             aux: AuxRecord(HashMap::new()),
             raw_cigar: "16M2I82M".to_string(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 315,
+            mtid: 0,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
         };
 
         let expected_reads = vec![EncodedRead::from_reads(
             vec![expected_read],
             "sample_1".to_string(),
         )];
+        let mut forward = vec![BaseCoverage::default(); 20];
+        for cov in &mut forward[4..] {
+            cov.m = 1;
+        }
+        forward[19].ins = 1;
         let expected_coverage = Coverage {
             sample: "sample_1".to_string(),
             start: 0,
@@ -1172,6 +4567,16 @@ mod tests {
             t: "".to_string(),
             c: "".to_string(),
             g: "".to_string(),
+            del: "".to_string(),
+            ins: "20|1".to_string(),
+            reverse_matches: "0|0|0|0|0|0|0|0|0|0|0|0|0|0|0|0|0|0|0|0".to_string(),
+            a_rev: "".to_string(),
+            t_rev: "".to_string(),
+            g_rev: "".to_string(),
+            c_rev: "".to_string(),
+            del_rev: "".to_string(),
+            ins_rev: "".to_string(),
+            strands: [forward, vec![BaseCoverage::default(); 20]],
         };
         assert_eq!(reference, expected_reference);
         assert_eq!(coverage, expected_coverage);
@@ -1180,6 +4585,181 @@ mod tests {
         assert_eq!(subsampled_reads, 1);
     }
 
+    #[test]
+    fn test_reference_window_fetches_once() {
+        use rust_htslib::bam::ext::BamRecordExtensions;
+        use rust_htslib::bam::Read as _;
+
+        let fasta = faidx::Reader::from_path("tests/sample_3/ref.fa").unwrap();
+        let region = Region {
+            target: "1".to_string(),
+            start: 1000,
+            end: 1500,
+        };
+        let target_length = fasta.fetch_seq_len("1") as i64;
+        let mut bam = bam::IndexedReader::from_path("tests/sample_3/NA12878.bam").unwrap();
+        bam.fetch(("1", region.start, region.end)).unwrap();
+        let records = bam.records().map(|record| record.unwrap()).collect_vec();
+        assert!(records.len() > 100);
+
+        let per_read = records
+            .iter()
+            .map(|record| {
+                read_fasta(
+                    &fasta,
+                    &Region {
+                        target: "1".to_string(),
+                        start: record.pos(),
+                        end: record.reference_end(),
+                    },
+                )
+                .unwrap()
+            })
+            .collect_vec();
+        let mut window = ReferenceWindow::new(&fasta, &region, target_length).unwrap();
+        let windowed = records
+            .iter()
+            .map(|record| {
+                window
+                    .get(record.pos(), record.reference_end())
+                    .unwrap()
+                    .to_vec()
+            })
+            .collect_vec();
+
+        assert_eq!(per_read, windowed);
+        // The reference is fetched once for the whole region instead of once per read.
+        assert_eq!(window.fetches, 1);
+    }
+
+    /// Compares the time needed to fetch the reference of each read of tests/sample_3 once per
+    /// read and from a single window. Run with
+    /// `cargo test --release bench_reference_fetch -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_reference_fetch() {
+        use rust_htslib::bam::ext::BamRecordExtensions;
+        use rust_htslib::bam::Read as _;
+        use std::hint::black_box;
+        use std::time::Instant;
+
+        const ITERATIONS: u32 = 50;
+        let fasta = faidx::Reader::from_path("tests/sample_3/ref.fa").unwrap();
+        let target_length = fasta.fetch_seq_len("1") as i64;
+        let mut bam = bam::IndexedReader::from_path("tests/sample_3/NA12878.bam").unwrap();
+        bam.fetch("1").unwrap();
+        let records = bam.records().map(|record| record.unwrap()).collect_vec();
+        let region = Region {
+            target: "1".to_string(),
+            start: records.iter().map(|record| record.pos()).min().unwrap(),
+            end: records
+                .iter()
+                .map(|record| record.reference_end())
+                .max()
+                .unwrap(),
+        };
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            for record in &records {
+                black_box(
+                    read_fasta(
+                        &fasta,
+                        &Region {
+                            target: "1".to_string(),
+                            start: record.pos(),
+                            end: record.reference_end(),
+                        },
+                    )
+                    .unwrap(),
+                );
+            }
+        }
+        let per_read = start.elapsed() / ITERATIONS;
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            let mut window = ReferenceWindow::new(&fasta, &region, target_length).unwrap();
+            for record in &records {
+                black_box(
+                    window
+                        .get(record.pos(), record.reference_end())
+                        .unwrap()
+                        .to_vec(),
+                );
+            }
+        }
+        let windowed = start.elapsed() / ITERATIONS;
+
+        println!(
+            "{} reads: {per_read:?} per read, {windowed:?} windowed",
+            records.len()
+        );
+        assert!(windowed < per_read);
+    }
+
+    #[test]
+    fn test_convert_chunks() {
+        use rust_htslib::bam::ext::BamRecordExtensions;
+        use rust_htslib::bam::Read as _;
+
+        let fasta = faidx::Reader::from_path("tests/sample_3/ref.fa").unwrap();
+        let region = Region {
+            target: "1".to_string(),
+            start: 0,
+            end: 2411,
+        };
+        let mut window = ReferenceWindow::new(&fasta, &region, 2411).unwrap();
+        let mut bam = bam::IndexedReader::from_path("tests/sample_3/NA12878.bam").unwrap();
+        bam.fetch(("1", region.start, region.end)).unwrap();
+        let records = bam
+            .records()
+            .map(|record| {
+                let record = record.unwrap();
+                let ref_seq = window
+                    .get(record.pos(), record.reference_end())
+                    .unwrap()
+                    .to_vec();
+                (record, ref_seq)
+            })
+            .collect_vec();
+        let to_read = |record: bam::Record, ref_seq: Vec<char>| {
+            Read::from_record(
+                record, ref_seq, 2411, &None, &region, false, false, None, None,
+            )
+        };
+        let sequential = convert_chunks(vec![records.clone()], &to_read).unwrap();
+        let chunks = records.chunks(500).map(<[_]>::to_vec).collect_vec();
+        assert!(chunks.len() > 1);
+        assert_eq!(convert_chunks(chunks, &to_read).unwrap(), sequential);
+    }
+
+    #[test]
+    fn test_reference_window_extends() {
+        let fasta = faidx::Reader::from_path("tests/sample_3/ref.fa").unwrap();
+        let region = Region {
+            target: "1".to_string(),
+            start: 100,
+            end: 200,
+        };
+        let mut window = ReferenceWindow::new(&fasta, &region, 2411).unwrap();
+        assert_eq!(window.get(2300, 2500).unwrap().len(), 111);
+        assert_eq!(window.fetches, 2);
+        assert_eq!(
+            window.get(2300, 2411).unwrap(),
+            read_fasta(
+                &fasta,
+                &Region {
+                    target: "1".to_string(),
+                    start: 2300,
+                    end: 2411
+                }
+            )
+            .unwrap()
+        );
+        assert_eq!(window.fetches, 2);
+    }
+
     #[test]
     fn test_create_plot_data_2() {
         let len = get_fasta_length(&PathBuf::from("tests/sample_3/ref.fa"), "1").unwrap();
@@ -1189,13 +4769,13 @@ mod tests {
             end: len as i64,
         };
         let result = create_plot_data(
-            "tests/sample_3/NA12878.bam",
-            "tests/sample_3/ref.fa",
+            &mut bam::IndexedReader::from_path("tests/sample_3/NA12878.bam").unwrap(),
+            Some(&faidx::Reader::from_path("tests/sample_3/ref.fa").unwrap()),
             &region,
-            500,
-            None,
-            0.0,
-            false,
+            &PlotDataOptions {
+                mismatch_display_min_percent: 0.0,
+                ..Default::default()
+            },
             "NA12878".to_string(),
         );
         assert!(result.is_ok());
@@ -1209,13 +4789,14 @@ mod tests {
             end: 200,
         };
         let result = create_plot_data(
-            "tests/sample_3/NA12878_with_clipping_read.bam",
-            "tests/sample_3/ref.fa",
+            &mut bam::IndexedReader::from_path("tests/sample_3/NA12878_with_clipping_read.bam")
+                .unwrap(),
+            Some(&faidx::Reader::from_path("tests/sample_3/ref.fa").unwrap()),
             &region,
-            500,
-            None,
-            0.0,
-            false,
+            &PlotDataOptions {
+                mismatch_display_min_percent: 0.0,
+                ..Default::default()
+            },
             "NA12878_with_clipping_read".to_string(),
         );
         assert!(result.is_ok());
@@ -1234,14 +4815,16 @@ mod tests {
             start: 6,
             end: 12,
         };
-        let (reads, _reference, _, _, _) = create_plot_data(
-            "tests/sample_1/reads.bam",
-            "tests/sample_1/reference.fa",
+        let PlotData { reads, .. } = create_plot_data(
+            &mut bam::IndexedReader::from_path("tests/sample_1/reads.bam").unwrap(),
+            Some(&faidx::Reader::from_path("tests/sample_1/reference.fa").unwrap()),
             &region,
-            100,
-            None,
-            0.0,
-            true,
+            &PlotDataOptions {
+                max_read_depth: 100,
+                mismatch_display_min_percent: 0.0,
+                clamp_reads: true,
+                ..Default::default()
+            },
             "sample_1".to_string(),
         )
         .unwrap();
@@ -1256,6 +4839,15 @@ mod tests {
             mpos: 789264,
             aux: AuxRecord(HashMap::new()),
             raw_cigar: "6M".to_string(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 315,
+            mtid: 0,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
         };
         assert!(reads[0].values.contains(&expected_read.encode()));
     }
@@ -1268,26 +4860,47 @@ mod tests {
                 cigar_type: CigarType::Match,
                 bases: None,
                 length: Some(16),
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: CigarType::Ins,
                 bases: Some(vec!['A', 'A']),
                 length: None,
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: CigarType::Sub,
                 bases: Some(vec!['T']),
                 length: Some(1),
+                qualities: None,
             },
             InnerPlotCigar {
                 cigar_type: CigarType::Del,
                 bases: None,
                 length: Some(1),
+                qualities: None,
             },
         ]);
         assert_eq!(plot_cigar, expected_plot_cigar);
     }
 
+    #[test]
+    fn test_plot_cigar_with_qualities() {
+        let encoded = "2=|2A:30|1A:12|iGT:20,40";
+        let plot_cigar = PlotCigar::from_str(encoded).unwrap();
+        assert_eq!(plot_cigar.0[1].qualities, Some(vec![30]));
+        assert_eq!(plot_cigar.0[3].qualities, Some(vec![20, 40]));
+        assert_eq!(plot_cigar.to_string(), encoded);
+
+        let cigar_string =
+            CigarStringView::new(CigarString::from(vec![Cigar::Match(5), Cigar::Ins(2)]), 0);
+        let read = vec!['C', 'A', 'A', 'A', 'C', 'G', 'T'];
+        let reference = vec!['C', 'C', 'C', 'C', 'C'];
+        let qualities = [40, 30, 30, 12, 40, 20, 40];
+        let cigar = PlotCigar::from_cigar(cigar_string, read, &qualities, reference).unwrap();
+        assert_eq!(cigar.to_string(), "1=|2A:30|1A:12|1=|iGT:20,40");
+    }
+
     #[test]
     fn test_empty_aux_record() {
         let record = bam::Record::new();
@@ -1320,6 +4933,293 @@ mod tests {
         assert_eq!(aux_record_string, expected_aux_record_string);
     }
 
+    #[test]
+    fn test_supplementary_alignment_from_str() {
+        let segment: SupplementaryAlignment = "chr2,1001,-,50S51M,60,3".parse().unwrap();
+        assert_eq!(segment.target, "chr2");
+        assert_eq!(segment.position, 1000);
+        assert!(segment.reverse);
+        assert_eq!(segment.end(), 1051);
+        assert_eq!((segment.mapq, segment.nm), (60, 3));
+        assert_eq!(segment.to_string(), "chr2,1001,-,50S51M,60,3");
+        assert!("chr2,1001,*,50S51M,60,3"
+            .parse::<SupplementaryAlignment>()
+            .is_err());
+        assert!("chr2,1001,-,50S51M"
+            .parse::<SupplementaryAlignment>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_read_with_supplementary_alignments_roundtrip() {
+        let read = Read {
+            name: "split".to_string(),
+            cigar: "5=".parse().unwrap(),
+            position: 5,
+            end_position: 10,
+            flags: 0,
+            mapq: 60,
+            row: Some(1),
+            mpos: -1,
+            aux: Default::default(),
+            raw_cigar: "5M5S".to_string(),
+            group: None,
+            color: None,
+            supplementary: vec![
+                "chr2,1001,-,5S5M,60,0".parse().unwrap(),
+                "chr3,11,+,5S5M,20,1".parse().unwrap(),
+            ],
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+        let encoded = read.encode();
+        assert!(encoded.contains(" chr2,1001,-,5S5M,60,0;chr3,11,+,5S5M,20,1 "));
+        let decoded = Read::from_str(&encoded).unwrap();
+        assert_eq!(decoded.supplementary, read.supplementary);
+    }
+
+    #[test]
+    fn test_read_with_mate_on_other_target_roundtrip() {
+        let read = Read {
+            name: "discordant".to_string(),
+            cigar: "5=".parse().unwrap(),
+            position: 5,
+            end_position: 10,
+            flags: 65,
+            mapq: 60,
+            row: Some(1),
+            mpos: 1000,
+            aux: Default::default(),
+            raw_cigar: "5M".to_string(),
+            group: None,
+            color: Some("interchromosomal".to_string()),
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: 1,
+            mate_target: Some("chr2".to_string()),
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+        let encoded = read.encode();
+        assert!(encoded.ends_with(" . 0 1 chr2 ."));
+        assert_eq!(Read::from_str(&encoded).unwrap(), read);
+    }
+
+    #[test]
+    fn test_classify_pairs() {
+        let read = |color: &str, tlen: i64| Read {
+            name: "pair".to_string(),
+            cigar: "5=".parse().unwrap(),
+            position: 5,
+            end_position: 10,
+            flags: 1,
+            mapq: 60,
+            row: None,
+            mpos: 100,
+            aux: Default::default(),
+            raw_cigar: "5M".to_string(),
+            group: None,
+            color: Some(color.to_string()),
+            supplementary: Vec::new(),
+            tlen,
+            mtid: 0,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+        let mut reads = vec![
+            read("FR", 300),
+            read("FR", -310),
+            read("FR", 290),
+            read("FR", 305),
+            read("FR", 2000),
+            read("FR", -50),
+            read("RF", 300),
+            read("unpaired", 0),
+        ];
+        classify_pairs(&mut reads);
+        assert_eq!(
+            reads
+                .iter()
+                .map(|read| read.color.as_deref().unwrap())
+                .collect_vec(),
+            [
+                "concordant",
+                "concordant",
+                "concordant",
+                "concordant",
+                "large insert",
+                "small insert",
+                "RF",
+                "unpaired",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_base_modifications_from_record() {
+        let record = |flags: u16, mm: &str, ml: &[u8]| {
+            let mut record = bam::Record::new();
+            record.set(
+                b"modified",
+                Some(&CigarString(vec![
+                    Cigar::Match(3),
+                    Cigar::Ins(1),
+                    Cigar::Match(5),
+                ])),
+                b"CGACGTTCG",
+                &[30; 9],
+            );
+            record.set_pos(100);
+            record.set_flags(flags);
+            record.push_aux(b"MM", Aux::String(mm)).unwrap();
+            record.push_aux(b"ML", Aux::ArrayU8(ml.into())).unwrap();
+            record
+        };
+        let calls = |record: &bam::Record| {
+            BaseModification::from_record(record).map(|modifications| {
+                modifications
+                    .into_iter()
+                    .map(|m| (m.position, m.code, m.likelihood))
+                    .collect_vec()
+            })
+        };
+        // The 5hmC call is on the inserted C and therefore skipped.
+        assert_eq!(
+            calls(&record(0, "C+m,0,1;C+h?,1;", &[200, 10, 50])).unwrap(),
+            [(100, "m".to_string(), 200), (106, "m".to_string(), 10)]
+        );
+        assert_eq!(
+            calls(&record(0, "C+mh,0;", &[100, 20])).unwrap(),
+            [(100, "m".to_string(), 100), (100, "h".to_string(), 20)]
+        );
+        // Reverse reads are counted in the orientation they were sequenced in.
+        assert_eq!(
+            calls(&record(16, "C+m,1;", &[255])).unwrap(),
+            [(103, "m".to_string(), 255)]
+        );
+        assert!(calls(&record(0, "C+m,5;", &[255])).is_err());
+        assert!(calls(&bam::Record::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_modification_frequency_from_reads() {
+        let read = |name: &str, modifications: Vec<BaseModification>| Read {
+            name: name.to_string(),
+            cigar: "10=".parse().unwrap(),
+            position: 0,
+            end_position: 10,
+            flags: 0,
+            mapq: 60,
+            row: Some(1),
+            mpos: -1,
+            aux: Default::default(),
+            raw_cigar: "10M".to_string(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications,
+            aligned_qualities: None,
+            sort_key: None,
+        };
+        let call = |position: i64, code: &str, likelihood: u8| BaseModification {
+            position,
+            code: code.to_string(),
+            likelihood,
+        };
+        let reads = vec![
+            read(
+                "read1",
+                vec![call(2, "m", 250), call(2, "h", 3), call(5, "m", 200)],
+            ),
+            read("read2", vec![call(2, "m", 100), call(9, "m", 255)]),
+        ];
+        let decoded = Read::from_str(&reads[0].encode()).unwrap();
+        assert_eq!(decoded, reads[0]);
+
+        let region = Region {
+            target: "chr1".to_owned(),
+            start: 0,
+            end: 8,
+        };
+        let mut counter = ModificationCounter::default();
+        for read in &reads {
+            counter.add(read, &region);
+        }
+        let frequencies = counter
+            .finish("test".to_string())
+            .iter()
+            .map(|f| (f.position(), f.code().to_string(), f.modified(), f.total()))
+            .collect_vec();
+        assert_eq!(
+            frequencies,
+            [
+                (2, "h".to_string(), 0, 1),
+                (2, "m".to_string(), 1, 2),
+                (5, "m".to_string(), 1, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_partner_region() {
+        let read = |supplementary: &[&str]| Read {
+            name: "split".to_string(),
+            cigar: "5=".parse().unwrap(),
+            position: 5,
+            end_position: 10,
+            flags: 0,
+            mapq: 60,
+            row: None,
+            mpos: -1,
+            aux: Default::default(),
+            raw_cigar: "5M5S".to_string(),
+            group: None,
+            color: None,
+            supplementary: supplementary.iter().map(|s| s.parse().unwrap()).collect(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+        let region = Region {
+            target: "chr1".to_owned(),
+            start: 0,
+            end: 20,
+        };
+        assert_eq!(partner_region(&[read(&[])], &region), None);
+        // Segments within the plotted region are no partners.
+        assert_eq!(
+            partner_region(&[read(&["chr1,11,+,5S5M,60,0"])], &region),
+            None
+        );
+
+        let reads = vec![
+            read(&["chr2,1001,+,5S5M,60,0"]),
+            read(&["chr2,1201,+,5S5M,60,0", "chr3,1,+,5S5M,60,0"]),
+            read(&["chr2,50001,+,5S5M,60,0"]),
+        ];
+        assert_eq!(
+            partner_region(&reads, &region),
+            Some(Region {
+                target: "chr2".to_owned(),
+                start: 900,
+                end: 1305,
+            })
+        );
+    }
+
     #[test]
     fn test_coverage_from_reads_basic_overlap() {
         // Create two reads, with overlapping positions
@@ -1335,6 +5235,15 @@ mod tests {
                 mpos: -1,
                 aux: Default::default(),
                 raw_cigar: "5=".to_string(),
+                group: None,
+                color: None,
+                supplementary: Vec::new(),
+                tlen: 0,
+                mtid: -1,
+                mate_target: None,
+                modifications: Vec::new(),
+                aligned_qualities: None,
+                sort_key: None,
             },
             Read {
                 name: "read2".to_string(),
@@ -1347,6 +5256,15 @@ mod tests {
                 mpos: -1,
                 aux: Default::default(),
                 raw_cigar: "5=".to_string(),
+                group: None,
+                color: None,
+                supplementary: Vec::new(),
+                tlen: 0,
+                mtid: -1,
+                mate_target: None,
+                modifications: Vec::new(),
+                aligned_qualities: None,
+                sort_key: None,
             },
             Read {
                 name: "outside".to_string(),
@@ -1359,6 +5277,15 @@ mod tests {
                 mpos: -1,
                 aux: Default::default(),
                 raw_cigar: "5=".to_string(),
+                group: None,
+                color: None,
+                supplementary: Vec::new(),
+                tlen: 0,
+                mtid: -1,
+                mate_target: None,
+                modifications: Vec::new(),
+                aligned_qualities: None,
+                sort_key: None,
             },
         ];
 
@@ -1368,7 +5295,7 @@ mod tests {
             end: 15,
         };
 
-        let coverage = Coverage::from_reads(&reads, &region, 0.0, "test".to_string());
+        let coverage = Coverage::from_reads(&reads, &region, 0.0, 0, "test".to_string());
 
         let expected = Coverage {
             sample: "test".to_string(),
@@ -1377,11 +5304,283 @@ mod tests {
             c: "".to_string(),
             g: "".to_string(),
             matches: "1|0|1|0|0|-1|0|-1|0|0".to_string(),
+            del: "".to_string(),
+            ins: "".to_string(),
+            reverse_matches: "0|0|0|0|0|0|0|0|0|0".to_string(),
+            a_rev: "".to_string(),
+            t_rev: "".to_string(),
+            g_rev: "".to_string(),
+            c_rev: "".to_string(),
+            del_rev: "".to_string(),
+            ins_rev: "".to_string(),
             start: 5,
+            strands: [
+                [1, 1, 2, 2, 2, 1, 1, 0, 0, 0]
+                    .map(|m| BaseCoverage {
+                        m,
+                        ..Default::default()
+                    })
+                    .to_vec(),
+                vec![BaseCoverage::default(); 10],
+            ],
         };
         assert_eq!(coverage, expected);
     }
 
+    #[test]
+    fn test_coverage_from_reads_min_base_quality() {
+        let read = |name: &str, cigar: &str| Read {
+            name: name.to_string(),
+            cigar: cigar.parse().unwrap(),
+            position: 0,
+            end_position: 3,
+            flags: 0,
+            mapq: 60,
+            row: None,
+            mpos: -1,
+            aux: Default::default(),
+            raw_cigar: "3M".to_string(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+        let reads = vec![read("high", "1=|1A:30|1="), read("low", "1=|1A:5|1=")];
+        let region = Region {
+            target: "chr1".to_owned(),
+            start: 0,
+            end: 3,
+        };
+        let coverage = Coverage::from_reads(&reads, &region, 0.0, 10, "test".to_string());
+        let decoded = coverage.decode().unwrap();
+        assert_eq!(decoded[1].a, 1);
+        assert_eq!(decoded[1].m, 1);
+        let coverage = Coverage::from_reads(&reads, &region, 0.0, 0, "test".to_string());
+        assert_eq!(coverage.decode().unwrap()[1].a, 2);
+    }
+
+    #[test]
+    fn test_coverage_dedup_mate_overlap() {
+        let read = |position: i64, cigar: &str, qualities: Option<Vec<u8>>| Read {
+            name: "pair".to_string(),
+            cigar: cigar.parse().unwrap(),
+            position,
+            end_position: position + 6,
+            flags: 1,
+            mapq: 60,
+            row: None,
+            mpos: -1,
+            aux: Default::default(),
+            raw_cigar: "6M".to_string(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: qualities.map(|qualities| (position, qualities)),
+            sort_key: None,
+        };
+        let region = Region {
+            target: "chr1".to_owned(),
+            start: 0,
+            end: 9,
+        };
+        let coverage = |alt_quality: u8, dedup: bool| {
+            let qualities = |alt: u8| dedup.then(|| vec![30, alt, 30, 30, 30, 30]);
+            let reads = [
+                read(0, "6=", qualities(30)),
+                read(3, "1=|1A|4=", qualities(alt_quality)),
+            ];
+            Coverage::from_reads(&reads, &region, 0.0, 0, "test".to_string())
+        };
+
+        // Overlapping positions are counted twice without deduplication.
+        let doubled = coverage(40, false);
+        assert_eq!(doubled.matches, "1|0|0|1|-1|1|-1|0|0");
+        assert_eq!(doubled.a, "5|1");
+        // The mismatch of the second mate has a higher quality than the match of the first one.
+        let deduplicated = coverage(40, true);
+        assert_eq!(deduplicated.matches, "1|0|0|0|-1|1|0|0|0");
+        assert_eq!(deduplicated.a, "5|1");
+        // Otherwise, the match of the first mate is kept.
+        let deduplicated = coverage(20, true);
+        assert_eq!(deduplicated.matches, "1|0|0|0|0|0|0|0|0");
+        assert_eq!(deduplicated.a, "");
+    }
+
+    #[test]
+    fn test_coverage_dedup_mate_overlap_supplementary() {
+        let region = Region {
+            target: "chr1".to_owned(),
+            start: 0,
+            end: 9,
+        };
+        let read = |position: i64, flags: u16| {
+            let mut record = bam::Record::new();
+            record.set(
+                b"pair",
+                Some(&CigarString(vec![Cigar::Match(6)])),
+                &[b'A'; 6],
+                &[30; 6],
+            );
+            record.set_pos(position);
+            record.set_flags(flags);
+            let aligned_qualities = mate_overlap_qualities(&record);
+            let read = Read::from_record(
+                record,
+                vec!['A'; 6],
+                100,
+                &None,
+                &region,
+                false,
+                false,
+                None,
+                None,
+            )
+            .unwrap()
+            .unwrap();
+            Read {
+                aligned_qualities,
+                ..read
+            }
+        };
+        let reads = [read(0, 0x41), read(3, 0x841)];
+        assert!(reads[0].aligned_qualities.is_some());
+        assert!(reads[1].aligned_qualities.is_none());
+
+        // The supplementary alignment is not mistaken for the mate of its primary, so their
+        // overlap is counted twice.
+        let coverage = Coverage::from_reads(&reads, &region, 0.0, 0, "test".to_string());
+        let depths = coverage
+            .decode()
+            .unwrap()
+            .iter()
+            .map(BaseCoverage::depth)
+            .collect_vec();
+        assert_eq!(depths, vec![1, 1, 1, 2, 2, 2, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_coverage_by_strand() {
+        let read = |name: &str, flags: u16, cigar: &str| Read {
+            name: name.to_string(),
+            cigar: cigar.parse().unwrap(),
+            position: 0,
+            end_position: 4,
+            flags,
+            mapq: 60,
+            row: None,
+            mpos: -1,
+            aux: Default::default(),
+            raw_cigar: "4M".to_string(),
+            group: None,
+            color: None,
+            supplementary: Vec::new(),
+            tlen: 0,
+            mtid: -1,
+            mate_target: None,
+            modifications: Vec::new(),
+            aligned_qualities: None,
+            sort_key: None,
+        };
+        let reads = vec![
+            read("forward1", 0, "1=|1A|2="),
+            read("forward2", 0, "1=|1A|2="),
+            read("forward3", 0, "4="),
+            read("reverse1", 16, "4="),
+            read("reverse2", 16, "1=|1T|2="),
+        ];
+        let region = Region {
+            target: "chr1".to_owned(),
+            start: 0,
+            end: 4,
+        };
+        let coverage = Coverage::from_reads(&reads, &region, 30.0, 0, "test".to_string());
+        let unfiltered = Coverage::from_reads(&reads, &region, 0.0, 0, "test".to_string());
+        assert_eq!(coverage.a, "2|2");
+        assert_eq!(coverage.a_rev, "");
+        // The T is hidden in the coverage of both strands, so it is merged into the matches of
+        // the reverse strand, too.
+        assert_eq!(coverage.t, "");
+        assert_eq!(coverage.reverse_matches, "2|0|0|0");
+        let total = coverage.decode().unwrap();
+        assert_eq!(total[1].m, 3);
+        let [forward, reverse] = &coverage.strands;
+        assert_eq!((forward[1].a, forward[1].m), (2, 1));
+        assert_eq!((reverse[1].t, reverse[1].m), (1, 1));
+
+        let highlight = |name: &str, start: f64, end: f64| {
+            let mut interval = Interval::new(name.to_string(), start, end);
+            interval.preprocess();
+            interval
+        };
+        let biases = StrandBias::from_coverage(&coverage, &highlight("snv", 2.0, 2.0));
+        assert_eq!(biases.len(), 1);
+        let bias = biases[0].clone();
+        assert_eq!((bias.name(), bias.position()), ("snv", 1));
+        // The hidden T still counts as an alternative call on the reverse strand.
+        assert_eq!(bias.reference_counts(), (1, 1));
+        assert_eq!(bias.alternative_counts(), (2, 1));
+        assert!((bias.p_value() - 1.0).abs() < 1e-9);
+        // The strand bias does not depend on the threshold mismatches are shown from.
+        assert_eq!(
+            StrandBias::from_coverage(&unfiltered, &highlight("snv", 2.0, 2.0)),
+            vec![bias.clone()]
+        );
+        assert!(StrandBias::from_coverage(&coverage, &highlight("outside", 10.0, 12.0)).is_empty());
+
+        // Each read is counted once at each position of a longer highlight.
+        let biases = StrandBias::from_coverage(&coverage, &highlight("interval", 1.0, 4.0));
+        assert_eq!(
+            biases.iter().map(StrandBias::position).collect_vec(),
+            vec![0, 1, 2, 3]
+        );
+        for bias in &biases {
+            let (ref_forward, ref_reverse) = bias.reference_counts();
+            let (alt_forward, alt_reverse) = bias.alternative_counts();
+            assert_eq!(
+                (ref_forward + alt_forward, ref_reverse + alt_reverse),
+                (3, 2)
+            );
+        }
+        // The tooltip shows the position with the strongest bias of each site.
+        let strongest = StrandBias::strongest(&biases);
+        assert_eq!(strongest.len(), 1);
+        assert_eq!(strongest[0].position(), 1);
+        assert_eq!(strongest[0], &bias);
+
+        // A read with an insertion after a matching base is only counted as alternative call.
+        let reads = vec![
+            read("inserted", 0, "2=|iA|2="),
+            read("forward", 0, "4="),
+            read("reverse", 16, "4="),
+        ];
+        let coverage = Coverage::from_reads(&reads, &region, 0.0, 0, "test".to_string());
+        let bias = &StrandBias::from_coverage(&coverage, &highlight("ins", 2.0, 2.0))[0];
+        assert_eq!(bias.reference_counts(), (1, 1));
+        assert_eq!(bias.alternative_counts(), (1, 0));
+    }
+
+    #[test]
+    fn test_fisher_exact() {
+        assert!((fisher_exact([[3, 1], [1, 3]]) - 0.485_714_285_7).abs() < 1e-9);
+        assert!((fisher_exact([[10, 0], [0, 10]]) - 2.0 / 184_756.0).abs() < 1e-12);
+        assert!((fisher_exact([[5, 5], [5, 5]]) - 1.0).abs() < 1e-9);
+        assert!((fisher_exact([[0, 0], [0, 0]]) - 1.0).abs() < 1e-9);
+        assert!((fisher_exact([[1, 1], [2, 1]]) - 1.0).abs() < 1e-9);
+        // Deep tables neither overflow nor lose the tail probabilities.
+        let deep = fisher_exact([[500_000, 500_000], [1_000, 0]]);
+        assert!(deep > 0.0 && deep < 1e-100);
+        assert!((fisher_exact([[500_000, 500_000], [10, 10]]) - 1.0).abs() < 1e-6);
+    }
+
     #[test]
     fn test_filter_mismatches() {
         let mut cov = crate::plot::BaseCoverage {
@@ -1389,7 +5588,9 @@ mod tests {
             t: 0,
             g: 1,
             c: 1,
-            m: 95,
+            m: 93,
+            del: 2,
+            ins: 1,
         };
         cov.filter_mismatches(2.0);
         assert_eq!(
@@ -1399,7 +5600,9 @@ mod tests {
                 t: 0,
                 g: 0,
                 c: 0,
-                m: 97
+                m: 95,
+                del: 2,
+                ins: 0,
             }
         );
     }
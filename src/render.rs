@@ -0,0 +1,715 @@
+use crate::builder::Plot;
+use crate::cli::Region;
+use crate::plot::{
+    CigarType, Coverage, EncodedRead, Junction, ModificationFrequency, Read, PAIR_COLORS,
+};
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use resvg::{tiny_skia, usvg};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, OnceLock};
+
+const MARGIN: f64 = 10.0;
+const TITLE_HEIGHT: f64 = 20.0;
+const LABEL_HEIGHT: f64 = 14.0;
+const COVERAGE_HEIGHT: f64 = 60.0;
+const REFERENCE_HEIGHT: f64 = 8.0;
+const JUNCTION_HEIGHT: f64 = 40.0;
+const MODIFICATION_HEIGHT: f64 = 40.0;
+const READ_ROW_HEIGHT: f64 = 6.0;
+const READ_HEIGHT: f64 = 4.0;
+const PANEL_GAP: f64 = 10.0;
+
+const MATCH_COLOR: &str = "#BBBBBB";
+const DELETION_COLOR: &str = "#D55E00";
+const INSERTION_COLOR: &str = "#56B4E9";
+const MATE_LINK_COLOR: &str = "#BBBBBB";
+const MATE_TARGET_COLOR: &str = "#666666";
+const SKIP_COLOR: &str = "#888888";
+const SOFT_CLIP_COLOR: &str = "#009E73";
+const HARD_CLIP_COLOR: &str = "#882255";
+const SPLIT_COLOR: &str = "#AA3377";
+/// Base modification calls are drawn with their probability as opacity.
+const MODIFICATION_COLOR: &str = "#B2182B";
+/// Mismatches with at least this base quality are drawn fully opaque.
+const MAX_SHADED_QUALITY: f64 = 40.0;
+/// Read colors for increasing mapping qualities from 0 to 60, matching the vega-lite spec.
+const MAPQ_COLORS: [&str; 7] = [
+    "#910000", "#c70002", "#ff0000", "#ff7500", "#ffb200", "#ffe921", "#bbbbbb",
+];
+/// Colors of reads colored by a categorical attribute, matching vega's tableau10 scheme.
+const CATEGORY_COLORS: [&str; 10] = [
+    "#4c78a8", "#f58518", "#e45756", "#72b7b2", "#54a24b", "#eeca3b", "#b279a2", "#ff9da6",
+    "#9d755d", "#bab0ac",
+];
+const HIGHLIGHT_COLORS: [&str; 8] = [
+    "#332288", "#117733", "#44AA99", "#88CCEE", "#DDCC77", "#CC6677", "#AA4499", "#882255",
+];
+
+/// Returns the color of the given base, matching the vega-lite spec.
+fn base_color(base: char) -> &'static str {
+    match base.to_ascii_uppercase() {
+        'A' => "#F0E442",
+        'C' => "#E69F00",
+        'G' => "#CC79A7",
+        'T' => "#0072B2",
+        _ => "#000000",
+    }
+}
+
+/// Linearly interpolates the read color for the given mapping quality.
+fn mapq_color(mapq: u8) -> String {
+    let scaled = f64::from(mapq.min(60)) / 60.0 * (MAPQ_COLORS.len() - 1) as f64;
+    let lower = scaled.floor() as usize;
+    let upper = (lower + 1).min(MAPQ_COLORS.len() - 1);
+    let fraction = scaled - lower as f64;
+    let channel = |hex: &str, i: usize| u8::from_str_radix(&hex[1 + 2 * i..3 + 2 * i], 16).unwrap();
+    let mixed = (0..3)
+        .map(|i| {
+            let (from, to) = (
+                f64::from(channel(MAPQ_COLORS[lower], i)),
+                f64::from(channel(MAPQ_COLORS[upper], i)),
+            );
+            format!("{:02x}", (from + (to - from) * fraction).round() as u8)
+        })
+        .collect::<String>();
+    format!("#{mixed}")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Returns the opacity of a mismatch with the given base quality, matching the vega-lite spec.
+fn quality_opacity(quality: u8) -> f64 {
+    (f64::from(quality) / MAX_SHADED_QUALITY).clamp(0.2, 1.0)
+}
+
+/// Appends a rect spanning from `start` to `end` on the x axis, skipping rects that lie
+/// completely outside of the plotted region.
+fn rect(body: &mut String, start: f64, end: f64, top: f64, height: f64, fill: &str) -> Result<()> {
+    shaded_rect(body, start, end, top, height, fill, 1.0)
+}
+
+/// Like [`rect`], but with the given fill opacity.
+fn shaded_rect(
+    body: &mut String,
+    start: f64,
+    end: f64,
+    top: f64,
+    height: f64,
+    fill: &str,
+    opacity: f64,
+) -> Result<()> {
+    if end > start {
+        write!(
+            body,
+            r#"<rect x="{start:.2}" y="{top:.2}" width="{:.2}" height="{height:.2}" fill="{fill}""#,
+            end - start
+        )?;
+        if opacity < 1.0 {
+            write!(body, r#" fill-opacity="{opacity:.2}""#)?;
+        }
+        writeln!(body, "/>")?;
+    }
+    Ok(())
+}
+
+/// Returns the scale mapping positions of the given region to x coordinates of a plot of the
+/// given width.
+fn x_scale(region: &Region, width: i64) -> impl Fn(f64) -> f64 + '_ {
+    let scale = width as f64 / region.length().max(1) as f64;
+    move |position: f64| {
+        MARGIN + ((position - region.start as f64) * scale).clamp(0.0, width as f64)
+    }
+}
+
+/// Renders the given plot as a static SVG image, drawing the same coverage, modification,
+/// junction, reference, read and highlight tracks that are otherwise passed to vega-lite,
+/// followed by the panels of the partner locus of split reads, if any.
+pub(crate) fn render_svg(plot: &Plot) -> Result<String> {
+    let width = plot.width().unwrap_or(1024);
+    let x = x_scale(plot.region(), width);
+    let mut body = String::new();
+    let mut y = MARGIN;
+    title(&mut body, &plot.region().to_string(), &mut y)?;
+
+    let decoded = plot
+        .reads()
+        .iter()
+        .map(EncodedRead::decode)
+        .collect::<Result<Vec<_>>>()?;
+    let partner_decoded = plot
+        .partner_reads()
+        .iter()
+        .map(EncodedRead::decode)
+        .collect::<Result<Vec<_>>>()?;
+    let categories = decoded
+        .iter()
+        .chain(&partner_decoded)
+        .flatten()
+        .filter_map(|read| read.color.clone())
+        .sorted()
+        .dedup()
+        .enumerate()
+        .map(|(i, category)| {
+            // Pair classes keep the colors they have in the vega-lite plot.
+            let color = PAIR_COLORS
+                .iter()
+                .find(|(class, _)| *class == category)
+                .map_or(CATEGORY_COLORS[i % CATEGORY_COLORS.len()], |(_, color)| {
+                    *color
+                });
+            (category, color)
+        })
+        .collect::<HashMap<_, _>>();
+
+    for (i, (sample_reads, decoded)) in plot.reads().iter().zip(&decoded).enumerate() {
+        let sample = sample_reads.sample();
+        let panel_top = y;
+        if let Some(coverage) = plot.coverage().iter().find(|c| c.sample() == sample) {
+            render_coverage(&mut body, coverage, plot.coverage_by_strand(), y, &x)?;
+            y += COVERAGE_HEIGHT;
+        }
+
+        let modifications = plot
+            .modifications()
+            .iter()
+            .filter(|modification| modification.sample() == sample)
+            .collect_vec();
+        if !modifications.is_empty() {
+            render_modifications(&mut body, &modifications, y, &x)?;
+            y += MODIFICATION_HEIGHT;
+        }
+
+        let junctions = plot
+            .junctions()
+            .iter()
+            .filter(|junction| junction.sample() == sample)
+            .collect_vec();
+        if !junctions.is_empty() {
+            render_junctions(&mut body, &junctions, y, &x)?;
+            y += JUNCTION_HEIGHT;
+        }
+
+        label(
+            &mut body,
+            plot.labels().get(i).map_or(sample, String::as_str),
+            &mut y,
+        )?;
+
+        let reference = plot.reference();
+        for (offset, base) in reference.bases().chars().enumerate() {
+            let position = (reference.start() + offset as i64) as f64;
+            rect(
+                &mut body,
+                x(position),
+                x(position + 1.0),
+                y,
+                REFERENCE_HEIGHT,
+                base_color(base),
+            )?;
+        }
+        y += REFERENCE_HEIGHT + 2.0;
+
+        y += render_reads(&mut body, decoded, y, &x, &categories)?;
+
+        for (index, interval) in plot.highlight().iter().enumerate() {
+            // Highlights are stored in 1-based coordinates widened by half a base on each side.
+            let (start, end) = (x(interval.start - 0.5), x(interval.end - 0.5));
+            writeln!(
+                body,
+                r#"<rect x="{start:.2}" y="{panel_top:.2}" width="{:.2}" height="{:.2}" fill="{}" fill-opacity="0.4"><title>{}</title></rect>"#,
+                (end - start).max(1.0),
+                y - panel_top,
+                HIGHLIGHT_COLORS[index % HIGHLIGHT_COLORS.len()],
+                escape(&interval.name)
+            )?;
+        }
+        y += PANEL_GAP;
+    }
+
+    // The partner locus of split reads gets its own x scale, with the coverage and reads of each
+    // sample as in the vega-lite plot.
+    if let Some(partner) = plot.partner() {
+        let x = x_scale(partner, width);
+        title(
+            &mut body,
+            &format!("{partner} (supplementary alignments)"),
+            &mut y,
+        )?;
+        for (sample_reads, decoded) in plot.partner_reads().iter().zip(&partner_decoded) {
+            let sample = sample_reads.sample();
+            if let Some(coverage) = plot
+                .partner_coverage()
+                .iter()
+                .find(|c| c.sample() == sample)
+            {
+                render_coverage(&mut body, coverage, plot.coverage_by_strand(), y, &x)?;
+                y += COVERAGE_HEIGHT;
+            }
+            label(&mut body, sample, &mut y)?;
+            y += render_reads(&mut body, decoded, y, &x, &categories)?;
+            y += PANEL_GAP;
+        }
+    }
+
+    Ok(format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="sans-serif">
+<rect width="100%" height="100%" fill="white"/>
+{body}</svg>
+"#,
+        width as f64 + 2.0 * MARGIN,
+        y + MARGIN
+    ))
+}
+
+/// Writes the title of a region at the given height and moves below it.
+fn title(body: &mut String, text: &str, y: &mut f64) -> Result<()> {
+    writeln!(
+        body,
+        r#"<text x="{MARGIN}" y="{}" font-size="14" font-weight="bold">{}</text>"#,
+        *y + 14.0,
+        escape(text)
+    )?;
+    *y += TITLE_HEIGHT;
+    Ok(())
+}
+
+/// Writes the label of a sample's reads at the given height and moves below it.
+fn label(body: &mut String, text: &str, y: &mut f64) -> Result<()> {
+    writeln!(
+        body,
+        r#"<text x="{MARGIN}" y="{}" font-size="11">{}</text>"#,
+        *y + 11.0,
+        escape(text)
+    )?;
+    *y += LABEL_HEIGHT;
+    Ok(())
+}
+
+/// Draws the coverage of a sample as bars of stacked matches, mismatching bases and indels. If
+/// `by_strand` is set, the forward strand is stacked upwards and the reverse strand downwards
+/// from the middle of the track, as in the vega-lite plot.
+fn render_coverage(
+    body: &mut String,
+    coverage: &Coverage,
+    by_strand: bool,
+    top: f64,
+    x: &impl Fn(f64) -> f64,
+) -> Result<()> {
+    let total = coverage.decode()?;
+    let strands = if by_strand {
+        let reverse = coverage.decode_reverse()?;
+        let forward = total
+            .iter()
+            .zip(&reverse)
+            .map(|(total, reverse)| *total - *reverse)
+            .collect_vec();
+        vec![(forward, -1.0), (reverse, 1.0)]
+    } else {
+        vec![(total, -1.0)]
+    };
+    let max_depth = strands
+        .iter()
+        .flat_map(|(depths, _)| depths)
+        .map(|depth| depth.depth() + depth.ins)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let height = COVERAGE_HEIGHT / strands.len() as f64;
+    let unit = height / max_depth as f64;
+    for (depths, direction) in &strands {
+        for (offset, depth) in depths.iter().enumerate() {
+            let position = (coverage.start() + offset as i64) as f64;
+            let mut edge = top + height;
+            for (count, color) in [
+                (depth.m, MATCH_COLOR),
+                (depth.a, base_color('A')),
+                (depth.t, base_color('T')),
+                (depth.g, base_color('G')),
+                (depth.c, base_color('C')),
+                (depth.del, DELETION_COLOR),
+                (depth.ins, INSERTION_COLOR),
+            ] {
+                if count == 0 {
+                    continue;
+                }
+                let bar = count as f64 * unit;
+                let bar_top = if *direction < 0.0 { edge - bar } else { edge };
+                rect(body, x(position), x(position + 1.0), bar_top, bar, color)?;
+                edge += direction * bar;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Draws the frequency of each base modification as a lollipop from the bottom of the track,
+/// which spans frequencies from 0 to 1.
+fn render_modifications(
+    body: &mut String,
+    modifications: &[&ModificationFrequency],
+    top: f64,
+    x: &impl Fn(f64) -> f64,
+) -> Result<()> {
+    let baseline = top + MODIFICATION_HEIGHT;
+    for modification in modifications {
+        let center = x(modification.position() as f64 + 0.5);
+        let frequency = modification.modified() as f64 / modification.total().max(1) as f64;
+        let apex = baseline - frequency * (MODIFICATION_HEIGHT - 4.0);
+        writeln!(
+            body,
+            r#"<line x1="{center:.2}" y1="{baseline:.2}" x2="{center:.2}" y2="{apex:.2}" stroke="{MODIFICATION_COLOR}" stroke-width="1"/>"#,
+        )?;
+        writeln!(
+            body,
+            r#"<circle cx="{center:.2}" cy="{apex:.2}" r="2" fill="{MODIFICATION_COLOR}"><title>{}: {}/{}</title></circle>"#,
+            escape(modification.code()),
+            modification.modified(),
+            modification.total()
+        )?;
+    }
+    Ok(())
+}
+
+/// Draws each splice junction as an arc whose height and width scale with its read count.
+fn render_junctions(
+    body: &mut String,
+    junctions: &[&Junction],
+    top: f64,
+    x: &impl Fn(f64) -> f64,
+) -> Result<()> {
+    let max_count = junctions
+        .iter()
+        .map(|junction| junction.count())
+        .max()
+        .unwrap_or(1);
+    let baseline = top + JUNCTION_HEIGHT;
+    for junction in junctions {
+        let (start, end) = (
+            x(junction.start() as f64 + 0.5),
+            x(junction.end() as f64 + 0.5),
+        );
+        // The control point of a quadratic bezier curve lies twice as high as its apex.
+        let apex = (JUNCTION_HEIGHT - 10.0) * junction.count() as f64 / max_count as f64;
+        writeln!(
+            body,
+            r#"<path d="M {start:.2} {baseline:.2} Q {:.2} {:.2} {end:.2} {baseline:.2}" fill="none" stroke="{SKIP_COLOR}" stroke-width="{:.2}"/>"#,
+            (start + end) / 2.0,
+            baseline - 2.0 * apex,
+            1.0 + 3.0 * junction.count() as f64 / max_count as f64,
+        )?;
+        writeln!(
+            body,
+            r#"<text x="{:.2}" y="{:.2}" font-size="9" text-anchor="middle">{}</text>"#,
+            (start + end) / 2.0,
+            baseline - apex - 2.0,
+            junction.count()
+        )?;
+    }
+    Ok(())
+}
+
+/// Draws the given reads into their rows below `top`, together with the labels of their groups.
+/// Returns the height of the drawn rows.
+fn render_reads(
+    body: &mut String,
+    reads: &[Read],
+    top: f64,
+    x: &impl Fn(f64) -> f64,
+    categories: &HashMap<String, &str>,
+) -> Result<f64> {
+    let rows = reads.iter().filter_map(|read| read.row).max().unwrap_or(0);
+    for read in reads {
+        render_read(body, read, top, x, categories)?;
+    }
+    // Each block of grouped reads is preceded by an empty row holding its label.
+    let groups = reads
+        .iter()
+        .filter_map(|read| Some((read.group.as_ref()?, read.row?)))
+        .into_grouping_map()
+        .min();
+    for (group, first_row) in groups {
+        writeln!(
+            body,
+            r#"<text x="{}" y="{:.2}" font-size="10" font-weight="bold">{}</text>"#,
+            MARGIN + 2.0,
+            top + f64::from(first_row.saturating_sub(1)) * READ_ROW_HEIGHT,
+            escape(group)
+        )?;
+    }
+    Ok(f64::from(rows) * READ_ROW_HEIGHT)
+}
+
+/// Draws a single read, its mismatches, indels and the link to its mate into the row assigned
+/// to it.
+fn render_read(
+    body: &mut String,
+    read: &Read,
+    top: f64,
+    x: &impl Fn(f64) -> f64,
+    categories: &HashMap<String, &str>,
+) -> Result<()> {
+    let Some(row) = read.row else {
+        return Ok(());
+    };
+    let top = top + f64::from(row.saturating_sub(1)) * READ_ROW_HEIGHT;
+    let center = top + READ_HEIGHT / 2.0;
+    if let Some(mate_target) = &read.mate_target {
+        writeln!(
+            body,
+            r#"<text x="{:.2}" y="{:.2}" font-size="5" text-anchor="end" fill="{MATE_TARGET_COLOR}">{}</text>"#,
+            x(read.position as f64) - 1.0,
+            center + 2.0,
+            escape(mate_target)
+        )?;
+    } else if read.mpos >= 0 {
+        writeln!(
+            body,
+            r#"<line x1="{:.2}" y1="{center:.2}" x2="{:.2}" y2="{center:.2}" stroke="{MATE_LINK_COLOR}" stroke-width="0.5"/>"#,
+            x(read.position.min(read.mpos) as f64),
+            x(read.position.max(read.mpos) as f64),
+        )?;
+    }
+    if !read.supplementary.is_empty() {
+        // Reads with supplementary alignments are outlined by a halo.
+        let start = read.position as f64;
+        rect(
+            body,
+            x(start),
+            x(start + read.cigar.reference_length() as f64),
+            top - 1.0,
+            READ_HEIGHT + 2.0,
+            SPLIT_COLOR,
+        )?;
+    }
+    let mut position = read.position as f64;
+    for inner in &read.cigar {
+        let length = f64::from(inner.length.unwrap_or(0));
+        match inner.cigar_type {
+            CigarType::Match | CigarType::Sub => {
+                let color = match (&inner.cigar_type, &inner.bases) {
+                    (CigarType::Sub, Some(bases)) => base_color(bases[0]).to_string(),
+                    _ => match &read.color {
+                        Some(category) => categories[category].to_string(),
+                        None => mapq_color(read.mapq),
+                    },
+                };
+                let opacity = inner
+                    .qualities
+                    .as_ref()
+                    .map_or(1.0, |qualities| quality_opacity(qualities[0]));
+                shaded_rect(
+                    body,
+                    x(position),
+                    x(position + length),
+                    top,
+                    READ_HEIGHT,
+                    &color,
+                    opacity,
+                )?;
+                position += length;
+            }
+            CigarType::SoftClip => {
+                rect(
+                    body,
+                    x(position),
+                    x(position + length),
+                    top,
+                    READ_HEIGHT,
+                    SOFT_CLIP_COLOR,
+                )?;
+                position += length;
+            }
+            CigarType::Skip => {
+                writeln!(
+                    body,
+                    r#"<line x1="{:.2}" y1="{center:.2}" x2="{:.2}" y2="{center:.2}" stroke="{SKIP_COLOR}" stroke-width="0.5"/>"#,
+                    x(position),
+                    x(position + length),
+                )?;
+                position += length;
+            }
+            CigarType::Del => {
+                writeln!(
+                    body,
+                    r#"<line x1="{:.2}" y1="{center:.2}" x2="{:.2}" y2="{center:.2}" stroke="{DELETION_COLOR}" stroke-width="1"/>"#,
+                    x(position),
+                    x(position + length),
+                )?;
+                position += length;
+            }
+            CigarType::Ins => {
+                rect(
+                    body,
+                    x(position) - 0.75,
+                    x(position) + 0.75,
+                    top - 1.0,
+                    READ_HEIGHT + 2.0,
+                    INSERTION_COLOR,
+                )?;
+            }
+            CigarType::HardClip => {
+                rect(
+                    body,
+                    x(position) - 0.75,
+                    x(position) + 0.75,
+                    top,
+                    READ_HEIGHT,
+                    HARD_CLIP_COLOR,
+                )?;
+            }
+            CigarType::Pad => {}
+        }
+    }
+    for modification in &read.modifications {
+        let position = modification.position as f64;
+        shaded_rect(
+            body,
+            x(position),
+            x(position + 1.0),
+            top,
+            READ_HEIGHT,
+            MODIFICATION_COLOR,
+            (f64::from(modification.likelihood) + 0.5) / 256.0,
+        )?;
+    }
+    Ok(())
+}
+
+/// The system fonts used to rasterize text, which are only loaded once per process.
+static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+
+/// Rasterizes the given SVG image into a PNG.
+pub(crate) fn render_png(svg: &str) -> Result<Vec<u8>> {
+    let fontdb = FONTS.get_or_init(|| {
+        let mut fontdb = usvg::fontdb::Database::new();
+        fontdb.load_system_fonts();
+        Arc::new(fontdb)
+    });
+    let options = usvg::Options {
+        fontdb: fontdb.clone(),
+        ..Default::default()
+    };
+    let tree = usvg::Tree::from_str(svg, &options)?;
+    let size = tree.size().to_int_size();
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+        .context("Plot is too large to be rendered as PNG")?;
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+    Ok(pixmap.encode_png()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::PlotBuilder;
+    use crate::cli::Interval;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    fn sample_1() -> PlotBuilder {
+        PlotBuilder::new(
+            vec![PathBuf::from("tests/sample_1/reads.bam")],
+            "tests/sample_1/reference.fa",
+        )
+        .region(Region::from_str("chr1:1-20").unwrap())
+        .highlights([Interval::new("variant".to_string(), 10.0, 10.0)])
+    }
+
+    fn sample_1_svg() -> String {
+        sample_1().plot().unwrap().to_svg().unwrap()
+    }
+
+    #[test]
+    fn test_render_svg() {
+        let svg = sample_1_svg();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains("reads (1 reads)"));
+        assert!(svg.contains("<title>variant</title>"));
+        // The reference track draws one rect per base of the 20bp region.
+        assert!(svg.matches(r##"height="8.00" fill="#"##).count() >= 20);
+        // The single read is drawn with a mapq 30 color and its insertion marker.
+        assert!(svg.contains(&mapq_color(30)));
+        assert!(svg.contains(INSERTION_COLOR));
+    }
+
+    #[test]
+    fn test_render_svg_coverage_by_strand() {
+        let bar_tops = |svg: &str| {
+            svg.lines()
+                .filter(|line| line.contains(MATCH_COLOR) && line.starts_with("<rect"))
+                .map(|line| line.split('"').nth(3).unwrap().parse::<f64>().unwrap())
+                .collect_vec()
+        };
+        let coverage_top = MARGIN + TITLE_HEIGHT;
+        // The coverage of the forward strand stands on the middle of the track, the reverse
+        // strand hangs from it.
+        let by_strand = sample_1()
+            .coverage_by_strand(true)
+            .plot()
+            .unwrap()
+            .to_svg()
+            .unwrap();
+        let middle = coverage_top + COVERAGE_HEIGHT / 2.0;
+        assert!(bar_tops(&by_strand)
+            .iter()
+            .all(|top| *top <= middle || (*top - middle).abs() < 0.01));
+        let plain = bar_tops(&sample_1_svg());
+        assert!(plain.iter().all(|top| *top >= coverage_top));
+        assert_ne!(bar_tops(&by_strand), plain);
+    }
+
+    #[test]
+    fn test_render_svg_modifications() {
+        let (_dir, bam) = crate::utils::tests::modified_reads();
+        let svg = PlotBuilder::new(vec![bam], "tests/sample_1/reference.fa")
+            .region(Region::from_str("chr1:1-20").unwrap())
+            .plot()
+            .unwrap()
+            .to_svg()
+            .unwrap();
+        assert!(svg.contains("<title>m: 2/2</title>"));
+        assert!(svg.contains("<title>m: 0/2</title>"));
+        assert!(!sample_1_svg().contains("<circle"));
+    }
+
+    #[test]
+    fn test_render_svg_split_partners() {
+        let (_dir, bam) = crate::utils::tests::split_reads();
+        let plot = PlotBuilder::new(vec![bam], "tests/sample_3/ref.fa")
+            .region(Region::from_str("1:281-420").unwrap())
+            .split_partners(true)
+            .plot()
+            .unwrap();
+        let svg = plot.to_svg().unwrap();
+        let partner = plot.partner().unwrap();
+        assert!(svg.contains(&format!("{partner} (supplementary alignments)")));
+        assert!(svg.contains(&format!("split ({partner})")));
+    }
+
+    #[test]
+    fn test_render_png() {
+        let png = render_png(&sample_1_svg()).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    }
+
+    #[test]
+    fn test_mapq_color() {
+        assert_eq!(mapq_color(0), "#910000");
+        assert_eq!(mapq_color(60), "#bbbbbb");
+        assert_eq!(mapq_color(255), "#bbbbbb");
+        assert_eq!(mapq_color(30), "#ff7500");
+    }
+
+    #[test]
+    fn test_quality_opacity() {
+        assert_eq!(quality_opacity(0), 0.2);
+        assert_eq!(quality_opacity(20), 0.5);
+        assert_eq!(quality_opacity(60), 1.0);
+    }
+}
@@ -1,9 +1,11 @@
 use anyhow::{anyhow, bail, Context, Result};
 use itertools::Itertools;
+use rust_htslib::bam::Read as BamRead;
 use rust_htslib::bcf::{Format, Header, Read as BcfRead, Reader, Writer};
-use rust_htslib::{bam, bcf, faidx};
+use rust_htslib::{bam, bcf, bgzf, faidx};
 use std::fs;
 use std::path::{Path, PathBuf};
+use url::Url;
 
 #[derive(Clone, Copy)]
 pub(crate) enum FileKind {
@@ -57,7 +59,7 @@ pub(crate) fn get_ref_and_bam_from_cwd() -> Result<Option<(PathBuf, Vec<PathBuf>
 
 // Get length of fasta file and given target
 pub(crate) fn get_fasta_length(fasta_path: &PathBuf, target: &str) -> Result<usize> {
-    let reader = faidx::Reader::from_path(fasta_path)?;
+    let reader = open_fasta(fasta_path)?;
     if !reader.seq_names()?.iter().any(|name| name == target) {
         bail!(
             "FASTA file {} does not contain target {target}",
@@ -67,9 +69,24 @@ pub(crate) fn get_fasta_length(fasta_path: &PathBuf, target: &str) -> Result<usi
     Ok(reader.fetch_seq_len(target) as usize)
 }
 
+// Get length of the given target from the header of the given BAM/CRAM file
+pub(crate) fn get_alignment_target_length(path: &Path, target: &str) -> Result<u64> {
+    let reader = open_alignment(path, None)?;
+    let header = reader.header();
+    header
+        .tid(target.as_bytes())
+        .and_then(|tid| header.target_len(tid))
+        .with_context(|| {
+            format!(
+                "Alignment file {} does not contain target {target}",
+                path.display()
+            )
+        })
+}
+
 // Get all contigs/chromosomes from fasta file
 pub(crate) fn get_fasta_contigs(fasta_path: &PathBuf) -> Result<Vec<String>> {
-    Ok(faidx::Reader::from_path(fasta_path)?.seq_names()?)
+    Ok(open_fasta(fasta_path)?.seq_names()?)
 }
 
 /// Returns `path` with `.extension` appended, e.g. `reads.bam` + `bai` -> `reads.bam.bai`.
@@ -80,20 +97,51 @@ fn appended_extension(path: &Path, extension: &str) -> PathBuf {
     PathBuf::from(name)
 }
 
+/// Returns the given path as URL if it refers to a remote `http(s)://` file, which htslib streams
+/// on its own instead of reading it from disk.
+pub(crate) fn remote_url(path: &Path) -> Option<Url> {
+    path.to_str()
+        .filter(|path| path.starts_with("http://") || path.starts_with("https://"))
+        .and_then(|path| Url::parse(path).ok())
+}
+
+/// Returns whether the given local or remote file exists.
+fn file_present(path: &Path) -> bool {
+    match remote_url(path) {
+        Some(url) => bgzf::Reader::from_url(&url).is_ok(),
+        None => path.exists(),
+    }
+}
+
 /// Returns whether an index exists next to `path`, either appended (`reads.bam.bai`) or replacing
 /// the file's extension (`reads.bai`), matching htslib's own lookup.
 fn index_present(path: &Path, extension: &str) -> bool {
-    appended_extension(path, extension).exists() || path.with_extension(extension).exists()
+    file_present(&appended_extension(path, extension))
+        || file_present(&path.with_extension(extension))
+}
+
+/// Fails with a hint on where to put the index if the given remote file has none, since indexes
+/// can only be built for local files.
+fn ensure_remote_index(path: &Path, present: bool, extensions: &str) -> Result<()> {
+    if !present {
+        bail!(
+            "Could not find an index for {}. Indexes can not be built for remote files, please provide a {extensions} index next to it.",
+            path.display()
+        );
+    }
+    Ok(())
 }
 
-/// Returns whether a coordinate index (`.bai` or `.csi`) exists next to the given BAM/CRAM file.
+/// Returns whether a coordinate index (`.bai`, `.csi` or `.crai`) exists next to the given BAM/CRAM
+/// file.
 pub(crate) fn bam_index_present(path: &Path) -> bool {
-    ["bai", "csi"]
+    ["bai", "csi", "crai"]
         .iter()
         .any(|extension| index_present(path, extension))
 }
 
-/// Builds a `.bai` index for the given (coordinate-sorted) BAM/CRAM file.
+/// Builds an index for the given (coordinate-sorted) BAM/CRAM file, i.e. a `.bai` for BAM and a
+/// `.crai` for CRAM files.
 pub(crate) fn build_bam_index(path: &Path) -> Result<()> {
     bam::index::build(path, None, bam::index::Type::Bai, 1).with_context(|| {
         format!(
@@ -103,11 +151,64 @@ pub(crate) fn build_bam_index(path: &Path) -> Result<()> {
     })
 }
 
+/// Returns whether the given path is a CRAM file, i.e. ends in `.cram`.
+fn is_cram(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "cram")
+}
+
+/// Opens the given BAM/CRAM file for random access. CRAM records are decoded against the given
+/// reference, so CRAMs that do not embed their reference can be read without setting `REF_PATH`.
+pub(crate) fn open_alignment(path: &Path, reference: Option<&Path>) -> Result<bam::IndexedReader> {
+    let mut reader = match remote_url(path) {
+        Some(url) => bam::IndexedReader::from_url(&url),
+        None => bam::IndexedReader::from_path(path),
+    }
+    .with_context(|| format!("Failed to open {}", path.display()))?;
+    if let Some(reference) = reference.filter(|_| is_cram(path)) {
+        reader.set_reference(reference).with_context(|| {
+            format!(
+                "Failed to use {} as reference for {}",
+                reference.display(),
+                path.display()
+            )
+        })?;
+    }
+    Ok(reader)
+}
+
+/// Opens the given FASTA file for random access.
+pub(crate) fn open_fasta(path: &Path) -> Result<faidx::Reader> {
+    match remote_url(path) {
+        Some(url) => faidx::Reader::from_url(&url),
+        None => faidx::Reader::from_path(path),
+    }
+    .with_context(|| format!("Failed to open {}", path.display()))
+}
+
+/// Opens the given VCF/BCF file for reading all of its records.
+pub(crate) fn read_vcf(path: &Path) -> Result<bcf::Reader> {
+    match remote_url(path) {
+        Some(url) => bcf::Reader::from_url(&url),
+        None => bcf::Reader::from_path(path),
+    }
+    .with_context(|| format!("Failed to open {}", path.display()))
+}
+
+/// Opens the given indexed VCF/BCF file for random access.
+pub(crate) fn open_vcf(path: &Path) -> Result<bcf::IndexedReader> {
+    match remote_url(path) {
+        Some(url) => bcf::IndexedReader::from_url(&url),
+        None => bcf::IndexedReader::from_path(path),
+    }
+    .with_context(|| format!("Failed to open {}", path.display()))
+}
+
 /// Returns whether a `.fai` index exists next to the given FASTA file. A bgzipped FASTA
 /// additionally requires a `.gzi` index for random access.
 pub(crate) fn fasta_index_present(path: &Path) -> bool {
-    appended_extension(path, "fai").exists()
-        && (!is_bgzipped(path) || appended_extension(path, "gzi").exists())
+    file_present(&appended_extension(path, "fai"))
+        && (!is_bgzipped(path) || file_present(&appended_extension(path, "gzi")))
 }
 
 /// Returns whether the given path looks bgzipped, i.e. ends in `.gz`.
@@ -173,25 +274,39 @@ fn bgzip_vcf(path: &Path) -> Result<PathBuf> {
 }
 
 /// Ensures a coordinate index exists for the given BAM/CRAM file, building one if it is missing.
+/// Remote files are required to come with an index.
 pub(crate) fn ensure_bam_index(path: &Path) -> Result<()> {
-    if !bam_index_present(path) {
+    if remote_url(path).is_some() {
+        ensure_remote_index(path, bam_index_present(path), ".bai, .csi or .crai")?;
+    } else if !bam_index_present(path) {
         build_bam_index(path)?;
     }
     Ok(())
 }
 
 /// Ensures a `.fai` index exists for the given FASTA file, building one if it is missing.
+/// Remote files are required to come with an index.
 pub(crate) fn ensure_fasta_index(path: &Path) -> Result<()> {
-    if !fasta_index_present(path) {
+    if remote_url(path).is_some() {
+        ensure_remote_index(
+            path,
+            fasta_index_present(path),
+            ".fai (and .gzi if bgzipped)",
+        )?;
+    } else if !fasta_index_present(path) {
         build_fasta_index(path)?;
     }
     Ok(())
 }
 
 /// Ensures an index exists for the given VCF/BCF file, building one (and bgzipping a plain `.vcf`
-/// if necessary) when it is missing. Returns the path that should be used downstream.
+/// if necessary) when it is missing. Returns the path that should be used downstream. Remote files
+/// are required to come with an index.
 pub(crate) fn ensure_vcf_index(path: &Path) -> Result<PathBuf> {
-    if vcf_index_present(path) {
+    if remote_url(path).is_some() {
+        ensure_remote_index(path, vcf_index_present(path), ".tbi or .csi")?;
+        Ok(path.to_path_buf())
+    } else if vcf_index_present(path) {
         Ok(path.to_path_buf())
     } else {
         build_vcf_index(path)
@@ -235,10 +350,14 @@ pub(crate) fn ellipsis(s: &str, max_len: usize) -> String {
 pub(crate) mod tests {
     use crate::utils::{
         bam_index_present, build_bam_index, build_fasta_index, build_vcf_index, ellipsis,
-        fasta_index_present, get_fasta_contigs, get_fasta_length, get_ref_and_bam_from_cwd,
-        vcf_index_present,
+        ensure_bam_index, ensure_fasta_index, fasta_index_present, get_fasta_contigs,
+        get_fasta_length, get_ref_and_bam_from_cwd, open_alignment, vcf_index_present,
     };
-    use std::io::Write;
+    use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+    use rust_htslib::bam::{self, Read as _};
+    use rust_htslib::faidx;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
     use std::path::{Path, PathBuf};
     use std::str::FromStr;
     use tempfile::TempDir;
@@ -254,6 +373,223 @@ pub(crate) mod tests {
         (dir, path)
     }
 
+    /// Converts `tests/sample_1/reads.bam` into an indexed CRAM that does not embed its reference.
+    pub(crate) fn cram_reads() -> (TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reads.cram");
+        let mut reader = bam::Reader::from_path("tests/sample_1/reads.bam").unwrap();
+        let header = bam::Header::from_template(reader.header());
+        let mut writer = bam::Writer::from_path(&path, &header, bam::Format::Cram).unwrap();
+        writer.set_reference("tests/sample_1/reference.fa").unwrap();
+        for record in reader.records() {
+            writer.write(&record.unwrap()).unwrap();
+        }
+        drop(writer);
+        build_bam_index(&path).unwrap();
+        (dir, path)
+    }
+
+    /// Writes an indexed BAM with a single read that is split between `1:300-349` and
+    /// `1:1500-1549` of `tests/sample_3/ref.fa`, with both segments referring to each other via
+    /// their `SA` tags.
+    pub(crate) fn split_reads() -> (TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("split.bam");
+        let reader = bam::Reader::from_path("tests/sample_3/NA12878.bam").unwrap();
+        let header = bam::Header::from_template(reader.header());
+        let fasta = faidx::Reader::from_path("tests/sample_3/ref.fa").unwrap();
+        let seq = |start: usize| fasta.fetch_seq("1", start, start + 49).unwrap();
+        let segments = [
+            (
+                299,
+                0,
+                vec![Cigar::Match(50), Cigar::SoftClip(50)],
+                [seq(299), seq(1499)].concat(),
+                "1,1500,+,50S50M,60,0;",
+            ),
+            (
+                1499,
+                2048,
+                vec![Cigar::HardClip(50), Cigar::Match(50)],
+                seq(1499),
+                "1,300,+,50M50S,60,0;",
+            ),
+        ];
+        let mut writer = bam::Writer::from_path(&path, &header, bam::Format::Bam).unwrap();
+        for (pos, flags, cigar, seq, sa) in segments {
+            let mut record = bam::Record::new();
+            record.set(
+                b"split",
+                Some(&CigarString(cigar)),
+                &seq,
+                &vec![30; seq.len()],
+            );
+            record.set_tid(0);
+            record.set_pos(pos);
+            record.set_mapq(60);
+            record.set_flags(flags);
+            record.push_aux(b"SA", Aux::String(sa)).unwrap();
+            writer.write(&record).unwrap();
+        }
+        drop(writer);
+        build_bam_index(&path).unwrap();
+        (dir, path)
+    }
+
+    /// Writes an indexed BAM with two reads aligned to `chr1:1-20` of `tests/sample_1/reference.fa`,
+    /// both calling 5mC at the cytosines at positions 4 (likely) and 5 (unlikely) via `MM` and `ML`.
+    pub(crate) fn modified_reads() -> (TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("modified.bam");
+        let reader = bam::Reader::from_path("tests/sample_1/reads.bam").unwrap();
+        let header = bam::Header::from_template(reader.header());
+        let fasta = faidx::Reader::from_path("tests/sample_1/reference.fa").unwrap();
+        let seq = fasta.fetch_seq("chr1", 0, 19).unwrap();
+        let mut writer = bam::Writer::from_path(&path, &header, bam::Format::Bam).unwrap();
+        for name in ["modified1", "modified2"] {
+            let mut record = bam::Record::new();
+            record.set(
+                name.as_bytes(),
+                Some(&CigarString(vec![Cigar::Match(20)])),
+                &seq,
+                &[30; 20],
+            );
+            record.set_tid(0);
+            record.set_pos(0);
+            record.set_mapq(60);
+            record.set_flags(0);
+            record.push_aux(b"MM", Aux::String("C+m?,0,0;")).unwrap();
+            record
+                .push_aux(b"ML", Aux::ArrayU8((&[250u8, 20]).into()))
+                .unwrap();
+            writer.write(&record).unwrap();
+        }
+        drop(writer);
+        build_bam_index(&path).unwrap();
+        (dir, path)
+    }
+
+    /// Serves the files of the given directory via HTTP on a random local port, supporting the
+    /// range requests htslib uses for random access. Returns the base URL.
+    pub(crate) fn serve_directory(dir: &Path) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let dir = dir.to_path_buf();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let dir = dir.clone();
+                std::thread::spawn(move || serve_request(stream, &dir));
+            }
+        });
+        format!("http://{address}")
+    }
+
+    fn serve_request(mut stream: TcpStream, dir: &Path) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request = String::new();
+        if reader.read_line(&mut request).is_err() {
+            return;
+        }
+        let mut range = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some(bytes) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                let (start, end) = bytes.trim().split_once('-').unwrap_or((bytes.trim(), ""));
+                range = Some((
+                    start.parse::<usize>().unwrap_or(0),
+                    end.parse::<usize>().ok(),
+                ));
+            }
+        }
+        let mut parts = request.split_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let file = dir.join(parts.next().unwrap_or_default().trim_start_matches('/'));
+        let Ok(content) = std::fs::read(file) else {
+            let _ = stream.write_all(
+                b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+            return;
+        };
+        let (status, body) = match range {
+            Some((start, end)) if start < content.len() => {
+                let end = end.map_or(content.len() - 1, |end| end.min(content.len() - 1));
+                (
+                    format!(
+                        "206 Partial Content\r\nContent-Range: bytes {start}-{end}/{}",
+                        content.len()
+                    ),
+                    &content[start..=end],
+                )
+            }
+            Some(_) => (
+                format!(
+                    "416 Range Not Satisfiable\r\nContent-Range: bytes */{}",
+                    content.len()
+                ),
+                &content[..0],
+            ),
+            None => ("200 OK".to_string(), &content[..]),
+        };
+        let _ = write!(
+            stream,
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        if method != "HEAD" {
+            let _ = stream.write_all(body);
+        }
+    }
+
+    #[test]
+    fn test_remote_files_and_indexes() {
+        let url = serve_directory(Path::new("tests/sample_1"));
+        let bam = PathBuf::from(format!("{url}/reads.bam"));
+        let reference = PathBuf::from(format!("{url}/reference.fa"));
+        assert!(bam_index_present(&bam));
+        assert!(fasta_index_present(&reference));
+        ensure_bam_index(&bam).unwrap();
+        ensure_fasta_index(&reference).unwrap();
+        assert_eq!(get_fasta_contigs(&reference).unwrap(), vec!["chr1"]);
+        assert_eq!(get_fasta_length(&reference, "chr1").unwrap(), 123);
+        let names = |path: &Path| {
+            let mut reader = open_alignment(path, Some(&reference)).unwrap();
+            reader.fetch("chr1").unwrap();
+            reader
+                .records()
+                .map(|record| record.unwrap().qname().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&bam), names(Path::new("tests/sample_1/reads.bam")));
+    }
+
+    #[test]
+    fn test_missing_remote_index_is_not_built() {
+        let (dir, _bam) = copy_to_temp("tests/sample_1/reads.bam");
+        let url = serve_directory(dir.path());
+        let error = ensure_bam_index(&PathBuf::from(format!("{url}/reads.bam"))).unwrap_err();
+        assert!(error.to_string().contains("Could not find an index"));
+        assert!(!dir.path().join("reads.bam.bai").exists());
+    }
+
+    #[test]
+    fn test_cram_index_and_records_match_bam() {
+        let (_dir, cram) = cram_reads();
+        assert!(bam_index_present(&cram));
+        let reference = Path::new("tests/sample_1/reference.fa");
+        let names = |path: &Path| {
+            let mut reader = open_alignment(path, Some(reference)).unwrap();
+            reader.fetch("chr1").unwrap();
+            reader
+                .records()
+                .map(|record| record.unwrap().qname().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&cram), names(Path::new("tests/sample_1/reads.bam")));
+    }
+
     #[test]
     fn test_bgzipped_fasta_contigs_and_length_match_plain() {
         let plain = PathBuf::from("tests/sample_1/reference.fa");
@@ -11,7 +11,7 @@ use std::fs;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-pub(crate) async fn wizard_mode() -> Result<Alignoth> {
+pub async fn wizard_mode() -> Result<Alignoth> {
     println!("Welcome to Alignoth wizard mode 🪄 Let's build your plot interactively.\n");
 
     let current_dir = std::env::current_dir()?;
@@ -106,12 +106,16 @@ pub(crate) async fn wizard_mode() -> Result<Alignoth> {
 
     let max_read_depth: usize = prompt_parse("Max read depth (default 500):", Some("500"))?;
 
-    let html_output = Select::new(
+    let output_type = Select::new(
         "Choose output type:",
-        vec!["Interactive HTML", "Vega-Lite Specs"],
+        vec![
+            "Interactive HTML",
+            "Vega-Lite Specs",
+            "SVG Image",
+            "PNG Image",
+        ],
     )
-    .prompt()?
-        == "Interactive HTML";
+    .prompt()?;
 
     Ok(Alignoth {
         bam_path: vec![bam_path],
@@ -119,10 +123,16 @@ pub(crate) async fn wizard_mode() -> Result<Alignoth> {
         region: Some(region),
         aux_tag: aux_tags,
         max_read_depth,
+        downsample_strategy: Default::default(),
+        seed: 42,
+        display_mode: Default::default(),
+        threads: 1,
         max_width: None,
         output: None,
         data_format: Default::default(),
-        html: html_output,
+        html: output_type == "Interactive HTML",
+        svg: output_type == "SVG Image",
+        png: output_type == "PNG Image",
         around: None,
         around_vcf_record: None,
         plot_all: false,
@@ -136,7 +146,25 @@ pub(crate) async fn wizard_mode() -> Result<Alignoth> {
         coverage_output: None,
         no_embed_js: false,
         mismatch_display_min_percent: 1.0,
+        min_base_quality: 0,
         clamp_reads,
+        show_soft_clips: false,
+        sashimi: false,
+        split_partners: false,
+        view_as_pairs: false,
+        coverage_by_strand: false,
+        dedup_mate_overlap: false,
+        regions_file: None,
+        each_vcf_record: false,
+        pass_only: false,
+        info_filter: None,
+        group_by: None,
+        color_by: None,
+        sort_by: None,
+        min_mapq: 0,
+        include_flags: 0,
+        exclude_flags: 0,
+        aux_filter: None,
     })
 }
 